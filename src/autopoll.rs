@@ -0,0 +1,37 @@
+/// Estimates the thermal time constant [s] from temperatures sampled every
+/// `sample_secs` while the temperature settles after a step in the fan speed,
+/// or returns `None` if they don't settle like a first-order system.
+///
+/// The samples are split into three equal runs, whose means `m0`, `m1`, and
+/// `m2` are one run length `h` apart. An exponential approach shrinks each
+/// step by the same factor, so `(m2 - m1) / (m1 - m0) = e^(-h/τ)`.
+pub fn time_constant(temps: &[f32], sample_secs: f32) -> Option<f32> {
+    let run = temps.len() / 3;
+    if run == 0 {
+        return None;
+    }
+    let mean = |i: usize| temps[i * run..(i + 1) * run].iter().sum::<f32>() / run as f32;
+    let (m0, m1, m2) = (mean(0), mean(1), mean(2));
+    let ratio = (m2 - m1) / (m1 - m0);
+    if !(ratio > 0.0 && ratio < 1.0) {
+        return None;
+    }
+    Some(-(run as f32 * sample_secs) / ratio.ln())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_the_time_constant_of_a_decay() {
+        let temps: Vec<f32> = (0..30)
+            .map(|i| 45.0 + 15.0 * (-(i as f32 * 0.5) / 8.0).exp())
+            .collect();
+        let tau = time_constant(&temps, 0.5).unwrap();
+        assert!((tau - 8.0).abs() < 0.1, "got {tau}");
+
+        // A temperature that doesn't move can't be characterized
+        assert_eq!(time_constant(&[50.0; 30], 0.5), None);
+    }
+}
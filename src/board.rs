@@ -0,0 +1,130 @@
+use std::path::Path;
+
+/// Where the board's model name is read from
+const MODEL_PATH: &str = "/proc/device-tree/model";
+/// The CPU temperature sensor and the first GPIO chip, neither of which exist
+/// on most machines that aren't a Pi
+const PROBED_PATHS: [&str; 2] = ["/sys/class/thermal/thermal_zone0/temp", "/dev/gpiochip0"];
+
+/// Returns whether this looks like a Raspberry Pi at all, which it doesn't if
+/// there's neither a temperature sensor nor a GPIO chip
+pub fn hardware_present() -> bool {
+    PROBED_PATHS.iter().any(|path| Path::new(path).exists())
+}
+
+/// Returns the temperature [°C] that the firmware throttles the CPU at, as
+/// `vcgencmd get_config temp_limit` reports it, or `None` if it can't tell
+pub fn throttle_temp() -> Option<f32> {
+    let output = std::process::Command::new("vcgencmd")
+        .args(["get_config", "temp_limit"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .strip_prefix("temp_limit=")?
+        .parse()
+        .ok()
+}
+
+/// Returns the system hostname, or `localhost` if it can't be read
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the length passed is that of `buf`, which outlives the call
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    match String::from_utf8_lossy(&buf[..len]).trim() {
+        "" => "localhost".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// The Raspberry Pi boards that differ in how PWM is wired up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Board {
+    /// A Pi 5, Pi 500 or Compute Module 5, where PWM lives on the RP1 and has
+    /// four channels
+    Pi5,
+    /// Any earlier Pi, where PWM lives on the SoC and has two channels
+    Legacy,
+}
+
+impl Board {
+    /// Detects the board from the device tree, assuming an earlier Pi if the
+    /// model can't be read
+    pub fn detect() -> Self {
+        std::fs::read_to_string(MODEL_PATH)
+            .map(|model| Self::from_model(&model))
+            .unwrap_or(Self::Legacy)
+    }
+
+    /// Returns the board for a device tree model name, e.g. "Raspberry Pi 5
+    /// Model B Rev 1.0"
+    pub fn from_model(model: &str) -> Self {
+        let model = model.trim_end_matches('\0');
+        let is_pi5 = [
+            "Raspberry Pi 5",
+            "Raspberry Pi 500",
+            "Raspberry Pi Compute Module 5",
+        ]
+        .iter()
+        .any(|prefix| {
+            model
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        });
+        if is_pi5 {
+            Self::Pi5
+        } else {
+            Self::Legacy
+        }
+    }
+
+    /// The PWM channel that drives BCM GPIO 18, where the fan is usually
+    /// wired
+    pub fn default_pwm_channel(self) -> u8 {
+        match self {
+            Self::Pi5 => 2,
+            Self::Legacy => 0,
+        }
+    }
+
+    /// Returns the BCM GPIO pin that the given channel is output on with the
+    /// standard overlays, or `None` if the board doesn't have the channel
+    pub fn pwm_pin(self, channel: u8) -> Option<u8> {
+        match (self, channel) {
+            (Self::Pi5, 0) => Some(12),
+            (Self::Pi5, 1) => Some(13),
+            (Self::Pi5, 2) | (Self::Legacy, 0) => Some(18),
+            (Self::Pi5, 3) | (Self::Legacy, 1) => Some(19),
+            _ => None,
+        }
+    }
+
+    /// The overlay line for `config.txt` that enables the given channel on
+    /// its usual pin
+    pub fn pwm_overlay(self, channel: u8) -> String {
+        match (self, self.pwm_pin(channel)) {
+            (Self::Pi5, Some(pin)) => format!("dtoverlay=pwm,pin={pin},func={}", pwm_func(pin)),
+            (Self::Legacy, Some(18)) => "dtoverlay=pwm".to_string(),
+            _ => "dtoverlay=pwm-2chan".to_string(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pi5 => "Raspberry Pi 5",
+            Self::Legacy => "Raspberry Pi",
+        }
+    }
+}
+
+/// The `func` that routes PWM to a pin on the Pi 5, in the BCM283x numbering
+/// that the overlays take (ALT5 for GPIO 18 and 19, ALT0 for 12 and 13)
+fn pwm_func(pin: u8) -> u8 {
+    match pin {
+        18 | 19 => 2,
+        _ => 4,
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::{Duration, Instant};
+
+use crate::config::LogOnChangeConfig;
+
+/// Decides which cycles are worth printing a line for, when only significant
+/// changes are logged
+pub struct ChangeFilter {
+    config: LogOnChangeConfig,
+    /// When the last line was printed, and the temperature, duty and RPM in it
+    last: Option<(Instant, [f32; 3])>,
+}
+
+impl ChangeFilter {
+    pub fn new(config: LogOnChangeConfig) -> Self {
+        Self { config, last: None }
+    }
+
+    /// Returns whether to print a line for this cycle, which is the case for
+    /// the first cycle, whenever a reading has moved by more than its
+    /// threshold since the last line, and once per heartbeat otherwise
+    pub fn should_log(&mut self, cpu_temp: f32, duty: f32, rpm: f32) -> bool {
+        let now = Instant::now();
+        let readings = [cpu_temp, duty, rpm];
+        let heartbeat = Duration::from_secs_f32(self.config.heartbeat_mins * 60.0);
+        let log = match self.last {
+            None => true,
+            Some((at, last)) => {
+                let thresholds = [self.config.temp, self.config.duty, self.config.rpm];
+                now - at >= heartbeat
+                    || readings
+                        .iter()
+                        .zip(last)
+                        .zip(thresholds)
+                        .any(|((reading, last), threshold)| (reading - last).abs() > threshold)
+            }
+        };
+        if log {
+            self.last = Some((now, readings));
+        }
+        log
+    }
+}
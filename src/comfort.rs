@@ -0,0 +1,76 @@
+use std::time::Instant;
+
+use crate::{config::ComfortConfig, FAN_MAX, FAN_OFF};
+
+/// [°C] how far below the soft ceiling the comfort controller aims to hold
+/// the temperature, so that it starts speeding up before the ceiling is
+/// reached
+const COMFORT_MARGIN: f32 = 1.0;
+/// How much slower the comfort controller slows the fan down than it speeds
+/// it up, so that it creeps down to the quietest speed that still holds the
+/// temperature rather than overshooting it
+const RELAX_FACTOR: f32 = 4.0;
+
+/// Holds the slowest fan speed that keeps the temperature under a soft
+/// ceiling. Above its target, just under the ceiling, the speed rises in
+/// proportion to how far above it the temperature is; below the target it
+/// falls back, more slowly, until the fan is off.
+pub struct Comfort {
+    /// [°C] the temperature that the speed is adjusted towards
+    target: f32,
+    /// The speed change per °C·s above the target
+    aggressiveness: f32,
+    duty: f32,
+    last: Option<Instant>,
+}
+
+impl Comfort {
+    /// Starts the controller from `duty`, so it takes over without a jump
+    pub fn new(config: &ComfortConfig, duty: f32) -> Self {
+        Self {
+            target: config.soft_ceiling - COMFORT_MARGIN,
+            aggressiveness: config.aggressiveness,
+            duty,
+            last: None,
+        }
+    }
+
+    /// Folds in a temperature reading and returns the new speed, between 0.0
+    /// and 1.0
+    pub fn update(&mut self, temp: f32) -> f32 {
+        let now = Instant::now();
+        let dt = self.last.map_or(0.0, |last| (now - last).as_secs_f32());
+        self.last = Some(now);
+        self.duty = (self.duty + self.rate(temp) * dt).clamp(FAN_OFF, FAN_MAX);
+        self.duty
+    }
+
+    /// Returns how fast the speed changes at `temp`, per second
+    fn rate(&self, temp: f32) -> f32 {
+        let excess = temp - self.target;
+        if excess > 0.0 {
+            self.aggressiveness * excess
+        } else {
+            self.aggressiveness * excess / RELAX_FACTOR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_up_near_the_ceiling_and_relaxes_slowly() {
+        let config = ComfortConfig {
+            soft_ceiling: 60.0,
+            aggressiveness: 0.01,
+        };
+        let comfort = Comfort::new(&config, 0.3);
+        // 2°C over the target speeds up by 2% a second
+        assert!((comfort.rate(61.0) - 0.02).abs() < 1e-6);
+        // 2°C under it slows down by a quarter of that
+        assert!((comfort.rate(57.0) + 0.005).abs() < 1e-6);
+        assert_eq!(comfort.rate(59.0), 0.0);
+    }
+}
@@ -0,0 +1,2409 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use rppal::gpio::{InputPin, Pin, Trigger};
+use serde::Deserialize;
+
+use crate::{
+    board::Board,
+    curve::{fahrenheit_to_celsius, CurveLookup, CurveMode},
+    driver::PCA9685_FREQUENCIES,
+    error::AppError,
+    format::ConfigFormat,
+    profile::{self, Profile, ScheduleEntry},
+    record::LogFormat,
+    temperature::{self, Calibration, CPU_THERMAL_ZONE},
+};
+
+/// The path that the config is read from when neither the command line nor
+/// [`CONFIG_PATH_ENV`] gives one
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/rpi-fan-control.toml";
+/// The environment variable that the config path is read from when none is
+/// given on the command line
+pub const CONFIG_PATH_ENV: &str = "RPI_FAN_CONFIG";
+/// An annotated config showing every setting, printed by `--schema`
+pub const EXAMPLE: &str = include_str!("config.example.toml");
+
+/// Runtime configuration, read from a TOML file. Every field is optional, and
+/// an empty (or missing) config file results in the built-in defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The units that every temperature in the config is given in. They're
+    /// converted to Celsius while loading.
+    pub units: Units,
+    /// Whether the fan speed between `off_temp` and `max_temp` is baked into
+    /// a table every tenth of a degree at startup, rather than evaluated from
+    /// the curve each cycle
+    pub precompute_curve: bool,
+    /// The baked fan speeds, when `precompute_curve` is set
+    #[serde(skip)]
+    pub curve_lookup: Option<CurveLookup>,
+    /// The profile that the fan speed currently follows, as set with
+    /// [`Config::set_profile`]
+    #[serde(skip)]
+    pub active_profile: Option<String>,
+    /// [°C] temperatures that the fan speed is compared at before and after a
+    /// reload, to show what it changed
+    pub reload_preview_temps: Vec<f32>,
+    /// Where `off_temp`, `min_temp` and `max_temp` come from
+    pub thresholds: Thresholds,
+    /// [°C] temperature below which to stop the fan
+    pub off_temp: f32,
+    /// [°C] temperature above which to start the fan
+    pub min_temp: f32,
+    /// [°C] temperature above which to start the fan at full speed
+    pub max_temp: f32,
+    /// [°C] how far below `min_temp` (or `off_temp`) the temperature has to
+    /// fall while cooling before the fan steps down to the next band, which
+    /// smooths out the spin-down
+    pub spin_down_lag: f32,
+    /// [s] how long to wait between iterations of the control loop
+    pub poll_interval_secs: f32,
+    /// Picks `poll_interval_secs` at startup from how fast the temperature
+    /// responds to the fan, if set
+    pub auto_poll: Option<AutoPollConfig>,
+    /// [°C] the most that the CPU temperature can change from one cycle to
+    /// the next before the reading is treated as a glitch, if set
+    pub max_temp_slew_per_cycle: Option<f32>,
+    /// The number of glitches in a row that are rejected before the new
+    /// temperature is accepted as a real change
+    pub max_temp_slew_rejections: u32,
+    /// [s] the half-life of the exponential moving average that the CPU
+    /// temperature is smoothed with before it drives the fan, if set
+    pub smoothing_halflife_secs: Option<f32>,
+    /// The correction applied to the CPU temperature sensor's readings
+    pub cpu_calibration: Calibration,
+    /// What the CPU temperature sensor's reading is divided by to get °C,
+    /// e.g. 1000 for millidegrees
+    pub cpu_scale_divisor: f32,
+    /// Whether reaching `max_temp` bypasses the temperature smoothing, the
+    /// minimum dwell time and the deadband, as [`Config::emergency`] says
+    pub emergency_bypass_smoothing: bool,
+    /// [s] how long to wait after the hardware is set up at 0% before the fan
+    /// first reacts to the temperature
+    pub startup_delay_secs: f32,
+    /// [s] how long the fan is ramped up from 0% over at startup, or 0 to
+    /// start it straight at its first speed
+    pub soft_start_secs: f32,
+    /// What the main fan is called in the status lines and telemetry
+    pub fan_name: String,
+    /// What this machine is called in the telemetry, which defaults to the
+    /// system hostname
+    pub host_label: Option<String>,
+    /// Reports how far the CPU temperature is below the throttle point, if
+    /// set
+    pub headroom: Option<HeadroomConfig>,
+    /// How the health verdict is judged, and where it's written
+    pub health: HealthConfig,
+    /// Where to send InfluxDB line-protocol records, if anywhere
+    pub influx: Option<InfluxConfig>,
+    /// The number of decimal places that readings are printed with
+    pub precision: Precision,
+    /// Whether readings that aren't finite numbers (NaN or infinite) are
+    /// printed and sent as `nan_sentinel` instead
+    pub nan_safe_output: bool,
+    /// What non-finite readings are printed and sent as
+    pub nan_sentinel: f32,
+    /// Only print the per-cycle line when a reading has changed by more than
+    /// a threshold, if set
+    pub log_on_change: Option<LogOnChangeConfig>,
+    /// Summarises the readings over fixed windows, if set
+    pub rollup: Option<RollupConfig>,
+    /// Whether each cycle is printed on stdout, as its status lines or the
+    /// rollup
+    pub stdout: bool,
+    /// A file that a record of each cycle is appended to, if any
+    pub log_file: Option<LogFileConfig>,
+    /// A file that the latest readings are written to as `key=value` lines
+    /// each cycle, for scripts to read. It's removed on shutdown.
+    pub status_file: Option<PathBuf>,
+    /// Where to serve the status dashboard over HTTP, if anywhere
+    pub http: Option<HttpConfig>,
+    /// [RPM] averaged fan speed below which to warn at full speed, scaled by
+    /// the commanded duty at lower speeds
+    pub rpm_warn_low: Option<f32>,
+    /// [RPM] averaged fan speed above which to warn at full speed, scaled by
+    /// the commanded duty at lower speeds
+    pub rpm_warn_high: Option<f32>,
+    /// The number of consecutive cycles the fan speed has to be outside of
+    /// `rpm_warn_low`..`rpm_warn_high` before warning
+    pub rpm_warn_cycles: u32,
+    /// Checks that the fan's tachometer reads it as stopped after it's
+    /// commanded off, if set
+    pub off_confirmation: Option<OffConfirmationConfig>,
+    /// What to do when the fan has stalled at full speed while the CPU is
+    /// critically hot, if anything beyond the usual warnings
+    pub critical_stall: Option<CriticalStallConfig>,
+    /// Forces the fan to a fail-safe speed if the control loop stalls, if set
+    pub watchdog: Option<WatchdogConfig>,
+    /// Ramps up to full speed over a grace period above `max_temp` rather
+    /// than jumping straight to it, if set
+    pub max_temp_grace: Option<MaxTempGraceConfig>,
+    /// [°C] temperature below which the fan is always off, overriding both
+    /// the curve and the idle band. Temperatures at or above `max_temp` still
+    /// run the fan at full speed.
+    pub force_off_below: Option<f32>,
+    /// Whether the fan keeps running at `idle_speed` or faster at every
+    /// temperature, rather than stopping below `off_temp`
+    pub always_on: bool,
+    /// The speed (between 0.0 and 1.0) that the fan never drops below when
+    /// `always_on` is set
+    pub idle_speed: f32,
+    /// What the fan does between `off_temp` and `min_temp`, unless the active
+    /// profile says otherwise
+    pub idle_band: IdleBand,
+    /// How the RPM samples taken during each cycle are reduced to the
+    /// reported fan speed
+    pub rpm_averaging: RpmAveraging,
+    /// [RPM] the increment that the fan speed is rounded to where it's
+    /// printed and sent, if set. The control always sees the unrounded speed.
+    pub rpm_rounding: Option<f32>,
+    /// Whether to add duty when the temperature is accelerating upwards (and
+    /// take it away when decelerating). This reacts to rapidly compounding
+    /// load sooner, but can amplify sensor noise.
+    pub accel_feed_forward: bool,
+    /// The fan speed added per °C/s² of temperature acceleration
+    pub accel_gain: f32,
+    /// Adds duty while the CPUs run near their maximum frequency, which
+    /// hints at heat on the way, if set
+    pub freq_boost: Option<FreqBoostConfig>,
+    /// The shape of the fan curve
+    pub curve: CurveMode,
+    /// Whether to watch which fan speeds held each temperature steady, and
+    /// suggest a curve table based on that at shutdown
+    pub learning: bool,
+    /// [°C] temperature that the curve suggested by learning mode should
+    /// keep the CPU at or below
+    pub learning_target_max: f32,
+    /// Where the fan speed is read from
+    pub rpm_source: RpmSource,
+    /// The hwmon node that the fan speed is read from when `rpm_source` is
+    /// `hwmon`. Defaults to the first `fan1_input` found under
+    /// `/sys/class/hwmon`.
+    pub hwmon_fan_input: Option<PathBuf>,
+    /// The I2C register that the fan speed is read from when `rpm_source` is
+    /// `i2c`
+    pub rpm_i2c: Option<I2cRpmConfig>,
+    /// Which edges of the tachometer signal to count
+    pub tach_edge: TachEdge,
+    /// The number of tachometer pulses the fans put out per revolution
+    pub pulses_per_rev: f32,
+    /// Changes in fan speed smaller than this (between 0.0 and 1.0) aren't
+    /// written to the PWM channel at all
+    pub duty_deadband: f32,
+    /// [s] how long each newly commanded speed is held before it can change
+    /// again, except to go to full speed
+    pub min_dwell_secs: f32,
+    /// The most that the fan speed can rise per second (as a fraction of
+    /// full speed), if limited. Emergencies rise straight away.
+    pub ramp_up_rate: Option<f32>,
+    /// The most that the fan speed can fall per second (as a fraction of
+    /// full speed), if limited
+    pub ramp_down_rate: Option<f32>,
+    /// The internal pull resistor to enable on the tachometer input
+    pub tach_pull: TachPull,
+    /// [µs] tachometer edges closer than this to the last counted one are
+    /// ignored as noise, or 0 to count every edge
+    pub tach_min_edge_interval_us: u64,
+    /// An ambient temperature sensor that keeps the fan from stopping when
+    /// the room is hot
+    pub ambient: Option<AmbientConfig>,
+    /// A GPU temperature sensor that the fan is controlled from along with
+    /// the CPU's
+    pub gpu: Option<GpuConfig>,
+    /// Sensors that the CPU temperature is read from instead of its thermal
+    /// zone, in order of priority. Each cycle, the first one that reads
+    /// successfully is used.
+    pub sensor_priority: Vec<SensorConfig>,
+    /// What drives the fan's PWM signal
+    pub fan_driver: FanBackend,
+    /// The PWM channel that the fan is driven from. Defaults to the channel
+    /// on BCM GPIO 18 for the detected board, which is 0 on earlier Pis and
+    /// 2 on the Pi 5.
+    pub pwm_channel: Option<u8>,
+    /// A file to hold an advisory lock on while driving the PWM channel, so
+    /// that a second process (like another instance, or a test script that
+    /// takes the same lock) refuses to start rather than fighting over the fan
+    pub pwm_lock: Option<PathBuf>,
+    /// What happens when the PWM channel goes missing and can't be
+    /// re-exported
+    pub pwm_recovery: PwmRecoveryConfig,
+    /// [Hz] the PWM frequency to drive the fan at, unless the active profile
+    /// says otherwise
+    pub pwm_frequency: f64,
+    /// [ms] the shortest time between two writes of the duty cycle to the
+    /// hardware, or 0 for no limit
+    pub min_write_interval_ms: u64,
+    /// The lowest duty cycle ever written to the hardware, between 0.0 and
+    /// 1.0, for fans whose controller needs some PWM to stay alive. The fan
+    /// is still reported as off while it's held at this floor.
+    pub hardware_min_duty: f32,
+    /// Named sets of overrides that the schedule can switch between
+    pub profiles: BTreeMap<String, Profile>,
+    /// The daily windows that profiles are active during. Outside of them,
+    /// the top-level settings apply.
+    pub schedule: Vec<ScheduleEntry>,
+    /// The BCM GPIO pin that switches power to the fan, if any. It's driven
+    /// high only while the fan should be running.
+    pub power_pin: Option<u8>,
+    /// The BCM GPIO pin of a status LED, if any. It's off while the fan is
+    /// off, blinks while the fan spins up, is solid while it runs, and blinks
+    /// fast when it has stalled or its speed is out of range.
+    pub led_pin: Option<u8>,
+    /// The BCM GPIO pin of a momentary button (to ground) that runs the fan
+    /// at full speed for `boost_secs` when pressed, if any
+    pub boost_button_pin: Option<u8>,
+    /// [s] how long each press of the boost button runs the fan at full speed
+    pub boost_secs: f32,
+    /// Drives the fan to hold a fixed RPM using the tachometer rather than
+    /// following the temperature
+    pub rpm_target: Option<RpmTargetConfig>,
+    /// Holds the slowest fan speed that keeps the temperature under a soft
+    /// ceiling rather than following the curve, if set
+    pub comfort: Option<ComfortConfig>,
+    /// Whether to briefly run the fan at full speed when it reads 0 RPM while
+    /// it should be running, to tell a stalled fan from a faulty tachometer.
+    /// This perturbs the fan speed, so it's off by default.
+    pub tach_loss_test: bool,
+    /// Whether to run the fan at full speed for a moment at startup, and stop
+    /// reading its speed if no tachometer edges come in, for a fan that's
+    /// missing or has no tachometer wire
+    pub detect_absent_fan: bool,
+    /// More fans, each following its own temperature sensor independently of
+    /// the main fan
+    pub zones: Vec<ZoneConfig>,
+    /// What to do when started on a machine that isn't a Raspberry Pi
+    pub on_non_pi: OnNonPi,
+    /// What the fan does when the CPU temperature can't be read
+    pub sensor_failure: SensorFailure,
+    /// The number of failed temperature readings in a row that
+    /// `sensor_failure = "hold"` holds the last speed through, before running
+    /// the fan at full speed
+    pub sensor_failure_grace: u32,
+    /// What the fan is left doing once the daemon shuts down
+    pub shutdown_fan_state: ShutdownFanState,
+    /// A named pipe that `key=value` lines can be written to, to change
+    /// thresholds and other tunables without restarting
+    pub control_fifo: Option<PathBuf>,
+    /// A file that pauses automatic control for as long as it exists
+    pub pause_file: Option<PathBuf>,
+    /// The speed (between 0.0 and 1.0) that the fan is set to while paused,
+    /// or `None` to hold whatever speed it had
+    pub pause_speed: Option<f32>,
+}
+
+/// The units of the temperatures in the config
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+/// How the RPM samples taken during a cycle are reduced to a single reading
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RpmAveraging {
+    /// The arithmetic mean of all samples
+    #[default]
+    Mean,
+    /// The median sample, which ignores the occasional outlier
+    Median,
+    /// The most recent sample
+    Last,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            units: Units::default(),
+            precompute_curve: false,
+            curve_lookup: None,
+            active_profile: None,
+            reload_preview_temps: vec![45.0, 55.0, 65.0, 75.0],
+            thresholds: Thresholds::default(),
+            off_temp: crate::OFF_TEMP,
+            min_temp: crate::MIN_TEMP,
+            max_temp: crate::MAX_TEMP,
+            spin_down_lag: 0.0,
+            max_temp_slew_per_cycle: None,
+            max_temp_slew_rejections: 3,
+            smoothing_halflife_secs: None,
+            poll_interval_secs: 5.0,
+            auto_poll: None,
+            emergency_bypass_smoothing: true,
+            startup_delay_secs: 0.0,
+            soft_start_secs: 2.0,
+            cpu_calibration: Calibration::default(),
+            cpu_scale_divisor: default_scale_divisor(),
+            fan_name: "fan0".to_string(),
+            host_label: None,
+            headroom: None,
+            health: HealthConfig::default(),
+            influx: None,
+            log_on_change: None,
+            rollup: None,
+            stdout: true,
+            log_file: None,
+            status_file: None,
+            http: None,
+            precision: Precision::default(),
+            nan_safe_output: true,
+            nan_sentinel: 0.0,
+            rpm_warn_low: None,
+            rpm_warn_high: None,
+            rpm_warn_cycles: 3,
+            max_temp_grace: None,
+            off_confirmation: None,
+            critical_stall: None,
+            watchdog: None,
+            force_off_below: None,
+            always_on: false,
+            idle_speed: crate::FAN_LOW,
+            idle_band: IdleBand::default(),
+            rpm_averaging: RpmAveraging::default(),
+            rpm_rounding: None,
+            accel_feed_forward: false,
+            accel_gain: 2.0,
+            freq_boost: None,
+            curve: CurveMode::default(),
+            learning: false,
+            learning_target_max: 65.0,
+            rpm_source: RpmSource::default(),
+            hwmon_fan_input: None,
+            rpm_i2c: None,
+            tach_edge: TachEdge::default(),
+            pulses_per_rev: crate::FAN_PULSE,
+            duty_deadband: 0.02,
+            min_dwell_secs: 0.0,
+            ramp_up_rate: None,
+            ramp_down_rate: None,
+            tach_pull: TachPull::default(),
+            tach_min_edge_interval_us: 200,
+            ambient: None,
+            gpu: None,
+            sensor_priority: Vec::new(),
+            fan_driver: FanBackend::default(),
+            pwm_channel: None,
+            pwm_lock: None,
+            pwm_recovery: PwmRecoveryConfig::default(),
+            pwm_frequency: crate::PWM_FREQUENCY,
+            min_write_interval_ms: 0,
+            hardware_min_duty: 0.0,
+            profiles: BTreeMap::new(),
+            schedule: Vec::new(),
+            power_pin: None,
+            led_pin: None,
+            boost_button_pin: None,
+            boost_secs: 60.0,
+            rpm_target: None,
+            comfort: None,
+            tach_loss_test: false,
+            detect_absent_fan: false,
+            zones: Vec::new(),
+            on_non_pi: OnNonPi::default(),
+            sensor_failure: SensorFailure::default(),
+            sensor_failure_grace: 3,
+            shutdown_fan_state: ShutdownFanState::default(),
+            control_fifo: None,
+            pause_file: None,
+            pause_speed: None,
+        }
+    }
+}
+
+/// Configuration of the InfluxDB line-protocol output
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+    /// Either `udp://host:port`, or an `http://host:port/path?query` write
+    /// endpoint (e.g. `http://localhost:8086/write?db=telegraf`)
+    pub url: String,
+    /// The measurement name that each record is written under
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+    /// Tags attached to every record, along with a `host` tag holding the
+    /// host label unless one is given here
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// [s] how long records are collected for before being sent together in
+    /// one write, if set. Otherwise each record is sent as soon as it's made.
+    pub batch_secs: Option<f32>,
+    /// Whether HTTP writes are gzip-compressed
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+/// Configuration of the thermal headroom reading
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeadroomConfig {
+    /// [°C] the temperature that the CPU is throttled at. Read from the
+    /// firmware's `temp_limit` when unset.
+    pub throttle_temp: Option<f32>,
+}
+
+/// Configuration of the health verdict
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// [°C] the CPU temperature at or above which the health is `WARN`.
+    /// Defaults to halfway between `min_temp` and `max_temp`.
+    pub warn_temp: Option<f32>,
+    /// A file that the health word is written to each cycle, or
+    /// `udp://host:port` to send it to, if anywhere
+    pub output: Option<String>,
+}
+
+/// Configuration of the check that the fan stops once it's commanded off
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OffConfirmationConfig {
+    /// The number of cycles the fan gets to stop in after being commanded off
+    pub cycles: u32,
+    /// [RPM] the fastest that a stopped fan can read, to allow for noise on
+    /// the tachometer
+    pub max_rpm: f32,
+}
+
+impl Default for OffConfirmationConfig {
+    fn default() -> Self {
+        Self {
+            cycles: 3,
+            max_rpm: 100.0,
+        }
+    }
+}
+
+/// Configuration of the response to the fan reading 0 RPM at full speed while
+/// the CPU is at or above `critical_temp`, when there's nothing left that the
+/// fan can do
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CriticalStallConfig {
+    /// [°C] the temperature at or above which a stall is critical, which
+    /// defaults to `max_temp` and can't be below it
+    pub critical_temp: Option<f32>,
+    /// The number of cycles in a row that the condition has to hold for
+    pub cycles: u32,
+    /// What's done once the condition has held for `cycles` cycles
+    pub action: CriticalStallAction,
+}
+
+impl Default for CriticalStallConfig {
+    fn default() -> Self {
+        Self {
+            critical_temp: None,
+            cycles: 3,
+            action: CriticalStallAction::default(),
+        }
+    }
+}
+
+/// The response to a critical stall, given as `"log"`, `"shutdown"`, or
+/// `{ command = "..." }`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CriticalStallAction {
+    /// Log an error and keep going
+    #[default]
+    Log,
+    /// Power the machine off with `shutdown -h now`
+    Shutdown,
+    /// Run a shell command, then keep going
+    Command(String),
+}
+
+/// Configuration of the watchdog on the control loop
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// [s] how long the loop can go without completing a cycle before the
+    /// fan is forced to `failsafe_speed`
+    pub timeout_secs: f32,
+    /// The speed (between 0.0 and 1.0) that the fan is forced to
+    pub failsafe_speed: f32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60.0,
+            failsafe_speed: crate::FAN_MAX,
+        }
+    }
+}
+
+/// Configuration of the automatic poll interval, which is a fraction of the
+/// thermal time constant measured at startup
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoPollConfig {
+    /// [s] the shortest interval that can be picked
+    pub min_secs: f32,
+    /// [s] the longest interval that can be picked
+    pub max_secs: f32,
+}
+
+impl Default for AutoPollConfig {
+    fn default() -> Self {
+        Self {
+            min_secs: 1.0,
+            max_secs: 30.0,
+        }
+    }
+}
+
+/// Configuration of the grace period above `max_temp`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaxTempGraceConfig {
+    /// [s] how long it takes to ramp up to full speed above `max_temp`
+    pub secs: f32,
+    /// [°C] temperature above which the fan goes to full speed immediately
+    pub critical_temp: f32,
+}
+
+/// Configuration of the CPU frequency feed-forward
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FreqBoostConfig {
+    /// The fraction of the maximum frequency above which duty is added
+    #[serde(default = "default_freq_boost_above")]
+    pub above: f32,
+    /// The fan speed added with the CPUs at their maximum frequency, scaled
+    /// down linearly to nothing at `above`
+    #[serde(default = "default_freq_boost")]
+    pub boost: f32,
+}
+
+fn default_freq_boost_above() -> f32 {
+    0.9
+}
+
+fn default_freq_boost() -> f32 {
+    0.1
+}
+
+/// The number of decimal places that each reading is printed with in the
+/// per-cycle output
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Precision {
+    pub temp: usize,
+    pub duty: usize,
+    pub rpm: usize,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            temp: 2,
+            duty: 2,
+            rpm: 2,
+        }
+    }
+}
+
+/// How much each reading has to change since the last printed line for a new
+/// one to be printed
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogOnChangeConfig {
+    /// [°C]
+    pub temp: f32,
+    /// [%]
+    pub duty: f32,
+    /// [RPM]
+    pub rpm: f32,
+    /// [min] how long to go without printing a line before printing one
+    /// regardless
+    pub heartbeat_mins: f32,
+}
+
+impl Default for LogOnChangeConfig {
+    fn default() -> Self {
+        Self {
+            temp: 1.0,
+            duty: 5.0,
+            rpm: 200.0,
+            heartbeat_mins: 10.0,
+        }
+    }
+}
+
+/// Configuration of rollups, which summarise the readings over each window
+/// in a single line
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollupConfig {
+    /// [s] how long each window is
+    pub secs: f32,
+    /// Whether to keep printing the per-cycle lines as well
+    #[serde(default)]
+    pub raw_lines: bool,
+}
+
+/// Configuration of the log file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+/// Configuration of the HTTP server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The address to listen on, e.g. `0.0.0.0:8080`
+    pub listen: String,
+}
+
+/// Configuration of the ambient temperature sensor
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AmbientConfig {
+    /// The sysfs-style file that the ambient temperature is read from
+    pub path: PathBuf,
+    /// What the number in `path` is divided by to get degrees
+    #[serde(default = "default_scale_divisor")]
+    pub scale_divisor: f32,
+    /// The correction applied to the sensor's readings
+    #[serde(default)]
+    pub calibration: Calibration,
+    /// [°C] ambient temperature above which the fan never drops below
+    /// `min_speed`
+    pub hot_above: f32,
+    /// The speed (between 0.0 and 1.0) that the fan is kept at or above while
+    /// the ambient temperature is above `hot_above`
+    pub min_speed: f32,
+}
+
+/// Configuration of the GPU temperature sensor
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GpuConfig {
+    /// The sysfs-style file that the GPU temperature is read from
+    pub path: PathBuf,
+    /// What the number in `path` is divided by to get degrees
+    #[serde(default = "default_scale_divisor")]
+    pub scale_divisor: f32,
+    /// The correction applied to the sensor's readings
+    #[serde(default)]
+    pub calibration: Calibration,
+    /// [°C] the temperature that the GPU throttles at
+    #[serde(default = "default_throttle_temp")]
+    pub throttle_temp: f32,
+    /// [°C] the temperature that the CPU throttles at. Defaults to the
+    /// firmware's `temp_limit`.
+    #[serde(default)]
+    pub cpu_throttle_temp: Option<f32>,
+    /// How the two temperatures are combined into the one the fan follows
+    #[serde(default)]
+    pub fusion: TempFusion,
+}
+
+/// One of the sensors in `sensor_priority`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    /// The sysfs-style file that the temperature is read from
+    pub path: PathBuf,
+    /// What the number in `path` is divided by to get degrees
+    #[serde(default = "default_scale_divisor")]
+    pub scale_divisor: f32,
+    /// The correction applied to the sensor's readings
+    #[serde(default)]
+    pub calibration: Calibration,
+}
+
+fn default_throttle_temp() -> f32 {
+    crate::DEFAULT_THROTTLE_TEMP
+}
+
+/// How the CPU and GPU temperatures are combined into the one that the fan
+/// is controlled from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TempFusion {
+    /// The hotter of the two
+    #[default]
+    Max,
+    /// Whichever has less headroom below its own throttle temperature. The
+    /// GPU's is shifted onto the CPU's scale, so that the curve sees the
+    /// same headroom either way.
+    HeadroomMax,
+}
+
+/// A fan that follows its own temperature sensor, independently of the main
+/// fan
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// Labels the zone's status lines. Defaults to `fanN`, counting the main
+    /// fan as `fan0` and the zones from 1 in order.
+    #[serde(default)]
+    pub name: String,
+    /// The sysfs-style file that the zone's temperature is read from
+    pub sensor: PathBuf,
+    /// What the number in `sensor` is divided by to get degrees
+    #[serde(default = "default_scale_divisor")]
+    pub scale_divisor: f32,
+    /// The correction applied to the sensor's readings
+    #[serde(default)]
+    pub calibration: Calibration,
+    /// The PWM channel that drives the zone's fan
+    pub pwm_channel: u8,
+    /// The BCM GPIO pin of the zone fan's tachometer, if it's wired up
+    pub tach_pin: Option<u8>,
+    /// [°C] temperature below which to stop the zone's fan
+    #[serde(default = "default_off_temp")]
+    pub off_temp: f32,
+    /// [°C] temperature above which to start the zone's fan
+    #[serde(default = "default_min_temp")]
+    pub min_temp: f32,
+    /// [°C] temperature above which to run the zone's fan at full speed
+    #[serde(default = "default_max_temp")]
+    pub max_temp: f32,
+    /// The shape of the zone's fan curve
+    #[serde(default)]
+    pub curve: CurveMode,
+}
+
+fn default_off_temp() -> f32 {
+    crate::OFF_TEMP
+}
+
+fn default_min_temp() -> f32 {
+    crate::MIN_TEMP
+}
+
+fn default_max_temp() -> f32 {
+    crate::MAX_TEMP
+}
+
+/// Configuration of closed-loop RPM control, where a PID loop on the
+/// tachometer reading drives the fan towards a target speed. Temperatures at
+/// or above `max_temp` still run the fan at full speed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpmTargetConfig {
+    /// [RPM] the fan speed to hold
+    pub rpm: f32,
+    /// The proportional gain, in duty per RPM of error
+    #[serde(default = "default_rpm_kp")]
+    pub kp: f32,
+    /// The integral gain, in duty per RPM·s of accumulated error
+    #[serde(default = "default_rpm_ki")]
+    pub ki: f32,
+    /// The derivative gain, in duty per RPM/s of change in error
+    #[serde(default)]
+    pub kd: f32,
+}
+
+/// Configuration of comfort mode, which trades temperature for quiet by
+/// running the fan as slowly as it can while the temperature stays under a
+/// soft ceiling. Temperatures at or above `max_temp` still run the fan at full
+/// speed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComfortConfig {
+    /// [°C] the temperature to stay under, which must be below `max_temp`
+    pub soft_ceiling: f32,
+    /// How fast the fan speeds up as the ceiling is approached, in duty per
+    /// °C·s
+    #[serde(default = "default_comfort_aggressiveness")]
+    pub aggressiveness: f32,
+}
+
+fn default_comfort_aggressiveness() -> f32 {
+    0.01
+}
+
+fn default_rpm_kp() -> f32 {
+    0.0002
+}
+
+fn default_rpm_ki() -> f32 {
+    0.00005
+}
+
+fn default_scale_divisor() -> f32 {
+    1000.0
+}
+
+fn default_measurement() -> String {
+    "rpi_fan".to_string()
+}
+
+/// Configuration of how a PWM channel that has gone missing is recovered
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PwmRecoveryConfig {
+    /// The number of times in a row to try re-exporting the channel before
+    /// escalating
+    pub max_attempts: u32,
+    /// What to do once every attempt has failed
+    pub on_failure: PwmFailureAction,
+}
+
+impl Default for PwmRecoveryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            on_failure: PwmFailureAction::default(),
+        }
+    }
+}
+
+/// The escalation once the PWM channel can't be re-exported, given as
+/// `"exit"` or `{ command = "..." }`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PwmFailureAction {
+    /// Exit with an error, so that a supervisor like systemd restarts the
+    /// daemon
+    #[default]
+    Exit,
+    /// Run a shell command, then keep going and try again next cycle
+    Command(String),
+}
+
+/// What drives the fan's PWM signal
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum FanBackend {
+    /// One of the Pi's own PWM channels, picked with `pwm_channel`
+    #[default]
+    Pwm,
+    /// A PCA9685 PWM expander on an I2C bus, as found on fan HATs
+    I2c(I2cConfig),
+    /// A hwmon `pwmN` node, like the Pi 5's fan connector
+    Hwmon(HwmonPwmConfig),
+}
+
+/// Configuration of a hwmon PWM node
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HwmonPwmConfig {
+    /// The `pwmN` node, e.g. `/sys/class/hwmon/hwmon2/pwm1`
+    pub path: PathBuf,
+    /// The value that the node takes for full speed. Most take 0 to 255, but
+    /// some take 0 to 100.
+    #[serde(default = "default_pwm_max_value")]
+    pub pwm_max_value: u32,
+}
+
+fn default_pwm_max_value() -> u32 {
+    255
+}
+
+/// Configuration of a PCA9685 PWM expander
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct I2cConfig {
+    /// The N in `/dev/i2c-N`
+    #[serde(default = "default_i2c_bus")]
+    pub bus: u8,
+    /// The expander's 7-bit address
+    #[serde(default = "default_i2c_address")]
+    pub address: u16,
+    /// The expander's output that the fan is wired to, from 0 to 15
+    pub channel: u8,
+    /// [Hz] the PWM frequency, unless the active profile says otherwise
+    #[serde(default = "default_i2c_frequency")]
+    pub frequency: f64,
+}
+
+fn default_i2c_bus() -> u8 {
+    1
+}
+
+fn default_i2c_address() -> u16 {
+    0x40
+}
+
+fn default_i2c_frequency() -> f64 {
+    1000.0
+}
+
+/// What the telemetry is tagged with
+#[derive(Debug, Clone)]
+pub struct Labels {
+    /// The host label, or the system hostname
+    pub host: String,
+    /// The main fan's name
+    pub fan: String,
+}
+
+/// Replaces anything but ASCII letters, digits, `.`, `-` and `_` with `_`
+pub fn label(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Where the temperature thresholds come from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Thresholds {
+    /// `off_temp`, `min_temp` and `max_temp` as configured
+    #[default]
+    Configured,
+    /// The kernel's cooling trip points for the CPU's thermal zone, falling
+    /// back to the configured thresholds if there aren't enough of them
+    AutoTrip,
+}
+
+/// What to do when started on a machine that isn't a Raspberry Pi
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnNonPi {
+    /// Explain why and exit successfully
+    #[default]
+    Exit,
+    /// Run the control loop without touching any hardware, at a fixed
+    /// temperature if there's no sensor
+    DryRun,
+}
+
+/// What the fan does when the CPU temperature can't be read, or reads as
+/// something implausible
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SensorFailure {
+    /// Run at full speed until the temperature can be read again
+    Full,
+    /// Hold the last speed for up to `sensor_failure_grace` readings in a
+    /// row, then run at full speed
+    Hold,
+    /// Carry on as if the CPU were at [`crate::FALLBACK_TEMP`]
+    #[default]
+    AssumeTemp,
+}
+
+/// What the fan is left doing once the daemon shuts down, given as `full`,
+/// `off`, a duty cycle between 0.0 and 1.0, or `keep`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "ShutdownFanStateValue")]
+pub enum ShutdownFanState {
+    /// Leave the fan running at its last commanded speed
+    Keep,
+    /// Run the fan at full speed, so it keeps cooling whatever runs next
+    #[default]
+    Full,
+    /// Stop the fan and disable the PWM output
+    Off,
+    /// Run the fan at a fixed duty cycle
+    Duty(f32),
+}
+
+/// How [`ShutdownFanState`] is written in the config
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ShutdownFanStateValue {
+    Name(String),
+    Duty(f32),
+}
+
+impl TryFrom<ShutdownFanStateValue> for ShutdownFanState {
+    type Error = String;
+
+    fn try_from(value: ShutdownFanStateValue) -> Result<Self, Self::Error> {
+        match value {
+            ShutdownFanStateValue::Name(name) => match name.as_str() {
+                "keep" => Ok(Self::Keep),
+                "full" => Ok(Self::Full),
+                "off" => Ok(Self::Off),
+                _ => Err(format!(
+                    "{name:?} isn't a shutdown fan state, expected keep, full, off, or a duty \
+                     cycle"
+                )),
+            },
+            ShutdownFanStateValue::Duty(duty) if (0.0..=1.0).contains(&duty) => {
+                Ok(Self::Duty(duty))
+            }
+            ShutdownFanStateValue::Duty(duty) => Err(format!(
+                "shutdown_fan_state must be between 0.0 and 1.0, got {duty}"
+            )),
+        }
+    }
+}
+
+/// Where the fan speed is read from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RpmSource {
+    /// Counting edges from the tachometer on [`crate::TACH_PIN`]
+    #[default]
+    Tach,
+    /// Polling a hwmon `fan1_input` node once per cycle, on boards that
+    /// expose the fan's tachometer through hwmon
+    Hwmon,
+    /// Polling a register of a fan controller on an I2C bus once per cycle,
+    /// on HATs that count the tachometer pulses themselves
+    I2c,
+    /// Nowhere, for a fan without a tachometer. It's driven as usual, but
+    /// never alarms on its speed.
+    None,
+}
+
+/// Configuration of an I2C register that holds the fan speed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct I2cRpmConfig {
+    /// The N in `/dev/i2c-N`
+    #[serde(default = "default_i2c_bus")]
+    pub bus: u8,
+    /// The fan controller's 7-bit address
+    pub address: u16,
+    /// The register that holds the fan speed
+    pub register: u8,
+    /// The register's width, 8 or 16 bits
+    #[serde(default = "default_register_bits")]
+    pub bits: u8,
+    /// Whether a 16-bit register holds its high byte first. SMBus sends the
+    /// low byte first.
+    #[serde(default)]
+    pub big_endian: bool,
+    /// What the register's value is multiplied by to get RPM
+    #[serde(default = "default_rpm_scale")]
+    pub scale: f32,
+}
+
+fn default_register_bits() -> u8 {
+    16
+}
+
+fn default_rpm_scale() -> f32 {
+    1.0
+}
+
+/// What the fan does in the idle band, between `off_temp` and `min_temp`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleBand {
+    /// Runs at a flat [`crate::FAN_LOW`]
+    #[default]
+    Step,
+    /// Ramps from off at `off_temp` up to [`crate::FAN_LOW`] at `min_temp`
+    Ramp,
+    /// Stays off until `min_temp`
+    Off,
+}
+
+/// Which edges of the tachometer signal are counted.
+///
+/// A typical open-collector tachometer output (like Noctua's) pulls the line
+/// low on each pulse and relies on the pull-up to bring it back high, so the
+/// falling edge is sharp while the rising edge is comparatively slow and more
+/// prone to noise. Falling is the right choice unless your wiring inverts the
+/// signal.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TachEdge {
+    #[default]
+    Falling,
+    Rising,
+    /// Both edges, which counts every pulse twice
+    Both,
+}
+
+impl TachEdge {
+    pub fn trigger(self) -> Trigger {
+        match self {
+            Self::Falling => Trigger::FallingEdge,
+            Self::Rising => Trigger::RisingEdge,
+            Self::Both => Trigger::Both,
+        }
+    }
+
+    /// The number of edges seen per tachometer pulse
+    pub fn edges_per_pulse(self) -> f32 {
+        match self {
+            Self::Falling | Self::Rising => 1.0,
+            Self::Both => 2.0,
+        }
+    }
+}
+
+/// The internal pull resistor enabled on the tachometer input.
+///
+/// An open-collector tachometer output only ever pulls the line low, so it
+/// needs a pull-up, either this one or an external resistor to 3.3V. Use
+/// `none` when an external pull resistor is fitted, or the tachometer drives
+/// the line both ways. A wrong setting leads to missed or spurious edges.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TachPull {
+    #[default]
+    Pullup,
+    Pulldown,
+    None,
+}
+
+impl TachPull {
+    pub fn input(self, pin: Pin) -> InputPin {
+        match self {
+            Self::Pullup => pin.into_input_pullup(),
+            Self::Pulldown => pin.into_input_pulldown(),
+            Self::None => pin.into_input(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from `path`, or from stdin if `path` is `-`. A missing
+    /// file is only an error if the path was explicitly requested, otherwise
+    /// the defaults are used. The format is `format` if given, or else picked
+    /// from the file's extension, falling back to TOML.
+    pub fn load(
+        path: &Path,
+        explicit: bool,
+        format: Option<ConfigFormat>,
+    ) -> Result<Self, AppError> {
+        if path == Path::new("-") {
+            let mut contents = String::new();
+            std::io::stdin()
+                .read_to_string(&mut contents)
+                .map_err(|source| AppError::ConfigRead {
+                    path: "stdin".into(),
+                    source,
+                })?;
+            return Self::parse(&contents, "stdin", format.unwrap_or(ConfigFormat::Toml));
+        }
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound && !explicit => return Ok(Self::default()),
+            Err(source) => {
+                return Err(AppError::ConfigRead {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        let format = format
+            .or_else(|| ConfigFormat::from_path(path))
+            .unwrap_or(ConfigFormat::Toml);
+        Self::parse(&contents, &path.display().to_string(), format)
+    }
+
+    /// Parses a config in the given format. `source_name` names where it came
+    /// from in errors, which already point at the offending line and column.
+    fn parse(contents: &str, source_name: &str, format: ConfigFormat) -> Result<Self, AppError> {
+        let mut config: Self =
+            format
+                .deserialize(contents)
+                .map_err(|reason| AppError::ConfigParse {
+                    source_name: source_name.to_string(),
+                    format,
+                    source: reason.into(),
+                })?;
+        if config.units == Units::Fahrenheit {
+            config.convert_to_celsius();
+        }
+        for (i, zone) in config.zones.iter_mut().enumerate() {
+            if zone.name.is_empty() {
+                zone.name = format!("fan{}", i + 1);
+            }
+        }
+        config
+            .validate()
+            .map_err(|reason| AppError::ConfigInvalid {
+                source_name: source_name.to_string(),
+                reason,
+            })?;
+        config.bake_curve();
+        Ok(config)
+    }
+
+    /// Changes a single tunable while running, given in the same units as the
+    /// config file. The change is rejected (and the config left untouched) if
+    /// the key is unknown, or the value is malformed or fails validation. An
+    /// optional tunable is cleared by an empty value or `none`.
+    pub fn set_tunable(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parsed = match value.trim() {
+            "" | "none" => None,
+            value => Some(
+                value
+                    .parse::<f32>()
+                    .map_err(|e| format!("{value:?} isn't a number: {e}"))?,
+            ),
+        };
+        let temp = parsed.map(|parsed| match self.units {
+            Units::Celsius => parsed,
+            Units::Fahrenheit => fahrenheit_to_celsius(parsed),
+        });
+        let required =
+            |value: Option<f32>| value.ok_or_else(|| format!("{key:?} can't be cleared"));
+
+        let mut updated = self.clone();
+        match key {
+            "off_temp" => updated.off_temp = required(temp)?,
+            "min_temp" => updated.min_temp = required(temp)?,
+            "max_temp" => updated.max_temp = required(temp)?,
+            "force_off_below" => updated.force_off_below = temp,
+            "poll_interval_secs" => updated.poll_interval_secs = required(parsed)?,
+            "duty_deadband" => updated.duty_deadband = required(parsed)?,
+            _ => return Err(format!("{key:?} isn't a tunable")),
+        }
+        // Against the whole config, so that the rules tying the tunable to
+        // the other settings are checked too
+        updated.validate()?;
+        updated.bake_curve();
+        *self = updated;
+        Ok(())
+    }
+
+    /// Replaces `off_temp`, `min_temp` and `max_temp` with the CPU thermal
+    /// zone's cooling trip points (calibrated like the CPU temperature): the
+    /// lowest as `off_temp`, the next as `min_temp`, and the highest as
+    /// `max_temp`. With only two, the lower is both `off_temp` and `min_temp`.
+    /// The configured thresholds are kept if there are fewer trip points, or
+    /// they can't be read.
+    pub fn apply_trip_points(&mut self) {
+        let trips = match temperature::cooling_trip_points(Path::new(CPU_THERMAL_ZONE)) {
+            Ok(trips) => trips,
+            Err(e) => {
+                warn!(
+                    "Failed to read the thermal trip points, using the configured thresholds: {e}"
+                );
+                return;
+            }
+        };
+        let trips: Vec<f32> = trips
+            .into_iter()
+            .map(|trip| self.cpu_calibration.apply(trip))
+            .collect();
+        let (off_temp, min_temp, max_temp) = match trips[..] {
+            [low, high] => (low, low, high),
+            [off, min, .., max] => (off, min, max),
+            _ => {
+                warn!(
+                    "Found {} cooling trip points, but at least 2 are needed; using the \
+                     configured thresholds",
+                    trips.len()
+                );
+                return;
+            }
+        };
+        let mut derived = self.clone();
+        (derived.off_temp, derived.min_temp, derived.max_temp) = (off_temp, min_temp, max_temp);
+        if let Err(e) = derived.validate() {
+            warn!(
+                "The thermal trip points don't make valid thresholds, using the configured ones: \
+                 {e}"
+            );
+            return;
+        }
+        info!(
+            "Using the thermal trip points as thresholds: off at {off_temp:.1}°C, min at \
+             {min_temp:.1}°C, max at {max_temp:.1}°C"
+        );
+        (self.off_temp, self.min_temp, self.max_temp) = (off_temp, min_temp, max_temp);
+        self.bake_curve();
+    }
+
+    /// Returns the host label and the main fan's name, with anything but
+    /// ASCII letters, digits, `.`, `-` and `_` replaced by `_` so that they
+    /// can go into a metric label, an InfluxDB tag or a JSON string as they are
+    pub fn labels(&self) -> Labels {
+        Labels {
+            host: label(
+                &self
+                    .host_label
+                    .clone()
+                    .unwrap_or_else(crate::board::hostname),
+            ),
+            fan: label(&self.fan_name),
+        }
+    }
+
+    /// Bakes the fan speed between `off_temp` and `max_temp` into
+    /// `curve_lookup` if `precompute_curve` is set, which has to be redone
+    /// whenever the thresholds or the curve change
+    pub fn bake_curve(&mut self) {
+        self.curve_lookup = None;
+        if self.precompute_curve {
+            let lookup = CurveLookup::bake(self.off_temp, self.max_temp, |temp| {
+                crate::evaluate_fan_speed(temp, self)
+            });
+            self.curve_lookup = Some(lookup);
+        }
+    }
+
+    /// Whether the temperature is an emergency that the fan has to react to
+    /// right away, skipping the temperature smoothing, the minimum dwell time
+    /// and the deadband. That's at or above `max_temp`, unless
+    /// `emergency_bypass_smoothing` is off.
+    pub fn emergency(&self, temp: f32) -> bool {
+        self.emergency_bypass_smoothing && temp >= self.max_temp
+    }
+
+    /// Makes the fan speed follow the given profile (or none), rebaking the
+    /// curve if it's precomputed
+    pub fn set_profile(&mut self, profile: Option<&str>) {
+        self.active_profile = profile.map(str::to_string);
+        self.bake_curve();
+    }
+
+    /// Returns the idle band of the active profile, or the top-level one if
+    /// it doesn't set one
+    pub fn idle_band(&self) -> IdleBand {
+        self.active_profile
+            .as_ref()
+            .and_then(|name| self.profiles.get(name))
+            .and_then(|profile| profile.idle_band)
+            .unwrap_or(self.idle_band)
+    }
+
+    /// [%] the duty cycle that's actually written while the fan is commanded
+    /// below `hardware_min_duty`, given the commanded one [%], if it is
+    pub fn pwm_floor(&self, duty: f32) -> Option<f32> {
+        let floor = self.hardware_min_duty * 100.0;
+        (duty < floor).then_some(floor)
+    }
+
+    /// What non-finite readings are replaced with in the output, if they are
+    pub fn output_sentinel(&self) -> Option<f32> {
+        self.nan_safe_output.then_some(self.nan_sentinel)
+    }
+
+    /// Rounds a fan speed to `rpm_rounding`, for printing and sending
+    pub fn reported_rpm(&self, rpm: f32) -> f32 {
+        match self.rpm_rounding {
+            Some(step) => (rpm / step).round() * step,
+            None => rpm,
+        }
+    }
+
+    /// Takes the settings that shape the fan speed from a freshly loaded
+    /// config. Everything else (the hardware, the outputs, the zones, and
+    /// the profiles) only changes on a restart. The result is validated as a
+    /// whole, since some of the settings taken are checked against ones that
+    /// aren't, and the config is left alone if it's invalid.
+    pub fn reload(&mut self, new: Self) -> Result<(), String> {
+        let mut updated = self.clone();
+        updated.reload_preview_temps = new.reload_preview_temps;
+        updated.thresholds = new.thresholds;
+        updated.off_temp = new.off_temp;
+        updated.min_temp = new.min_temp;
+        updated.max_temp = new.max_temp;
+        updated.spin_down_lag = new.spin_down_lag;
+        // The measured interval stands until a restart
+        if updated.auto_poll.is_none() {
+            updated.poll_interval_secs = new.poll_interval_secs;
+        }
+        updated.max_temp_slew_per_cycle = new.max_temp_slew_per_cycle;
+        updated.max_temp_slew_rejections = new.max_temp_slew_rejections;
+        updated.smoothing_halflife_secs = new.smoothing_halflife_secs;
+        updated.emergency_bypass_smoothing = new.emergency_bypass_smoothing;
+        updated.cpu_calibration = new.cpu_calibration;
+        updated.cpu_scale_divisor = new.cpu_scale_divisor;
+        updated.max_temp_grace = new.max_temp_grace;
+        updated.comfort = new.comfort;
+        updated.force_off_below = new.force_off_below;
+        updated.always_on = new.always_on;
+        updated.idle_speed = new.idle_speed;
+        updated.idle_band = new.idle_band;
+        updated.accel_feed_forward = new.accel_feed_forward;
+        updated.accel_gain = new.accel_gain;
+        updated.freq_boost = new.freq_boost;
+        updated.curve = new.curve;
+        updated.precompute_curve = new.precompute_curve;
+        updated.duty_deadband = new.duty_deadband;
+        updated.min_dwell_secs = new.min_dwell_secs;
+        updated.ramp_up_rate = new.ramp_up_rate;
+        updated.ramp_down_rate = new.ramp_down_rate;
+        updated.boost_secs = new.boost_secs;
+        updated.sensor_failure = new.sensor_failure;
+        updated.sensor_failure_grace = new.sensor_failure_grace;
+        updated.validate()?;
+        updated.bake_curve();
+        *self = updated;
+        Ok(())
+    }
+
+    /// Converts every temperature in the config from Fahrenheit to Celsius
+    fn convert_to_celsius(&mut self) {
+        for temp in [
+            &mut self.off_temp,
+            &mut self.min_temp,
+            &mut self.max_temp,
+            &mut self.learning_target_max,
+        ]
+        .into_iter()
+        .chain(&mut self.reload_preview_temps)
+        {
+            *temp = fahrenheit_to_celsius(*temp);
+        }
+        if let Some(temp) = &mut self.force_off_below {
+            *temp = fahrenheit_to_celsius(*temp);
+        }
+        if let Some(grace) = &mut self.max_temp_grace {
+            grace.critical_temp = fahrenheit_to_celsius(grace.critical_temp);
+        }
+        if let Some(temp) = self
+            .headroom
+            .as_mut()
+            .and_then(|headroom| headroom.throttle_temp.as_mut())
+        {
+            *temp = fahrenheit_to_celsius(*temp);
+        }
+        if let Some(temp) = &mut self.health.warn_temp {
+            *temp = fahrenheit_to_celsius(*temp);
+        }
+        if let Some(temp) = self
+            .critical_stall
+            .as_mut()
+            .and_then(|stall| stall.critical_temp.as_mut())
+        {
+            *temp = fahrenheit_to_celsius(*temp);
+        }
+        if let Some(comfort) = &mut self.comfort {
+            comfort.soft_ceiling = fahrenheit_to_celsius(comfort.soft_ceiling);
+            // Per degree, so a Celsius degree is 9/5 as much
+            comfort.aggressiveness *= 9.0 / 5.0;
+        }
+        // Differences rather than temperatures, so there's no offset
+        self.spin_down_lag *= 5.0 / 9.0;
+        if let Some(slew) = &mut self.max_temp_slew_per_cycle {
+            *slew *= 5.0 / 9.0;
+        }
+        if let CurveMode::Table(table) = &mut self.curve {
+            table.convert_to_celsius();
+        }
+        for zone in &mut self.zones {
+            for temp in [&mut zone.off_temp, &mut zone.min_temp, &mut zone.max_temp] {
+                *temp = fahrenheit_to_celsius(*temp);
+            }
+            zone.calibration.offset *= 5.0 / 9.0;
+            if let CurveMode::Table(table) = &mut zone.curve {
+                table.convert_to_celsius();
+            }
+        }
+        if let Some(ambient) = &mut self.ambient {
+            ambient.hot_above = fahrenheit_to_celsius(ambient.hot_above);
+            ambient.calibration.offset *= 5.0 / 9.0;
+        }
+        if let Some(gpu) = &mut self.gpu {
+            gpu.throttle_temp = fahrenheit_to_celsius(gpu.throttle_temp);
+            gpu.cpu_throttle_temp = gpu.cpu_throttle_temp.map(fahrenheit_to_celsius);
+            gpu.calibration.offset *= 5.0 / 9.0;
+        }
+        for sensor in &mut self.sensor_priority {
+            sensor.calibration.offset *= 5.0 / 9.0;
+        }
+        self.cpu_calibration.offset *= 5.0 / 9.0;
+    }
+
+    /// Returns the hwmon node to read the fan speed from, or `None` if the
+    /// tachometer is read directly
+    pub fn hwmon_fan(&self) -> Result<Option<PathBuf>, AppError> {
+        if self.rpm_source != RpmSource::Hwmon {
+            return Ok(None);
+        }
+        match self
+            .hwmon_fan_input
+            .clone()
+            .or_else(crate::rpm::find_hwmon_fan)
+        {
+            Some(path) => Ok(Some(path)),
+            None => Err(AppError::io("find a hwmon fan1_input node")(
+                ErrorKind::NotFound.into(),
+            )),
+        }
+    }
+
+    /// Returns the config that a zone is controlled with, which takes its
+    /// thresholds and curve from the zone and the rest of the control
+    /// settings from the main config. Everything tied to the main fan's
+    /// hardware or sensors is left off.
+    pub fn for_zone(&self, zone: &ZoneConfig) -> Self {
+        let mut config = Self {
+            off_temp: zone.off_temp,
+            min_temp: zone.min_temp,
+            max_temp: zone.max_temp,
+            curve: zone.curve.clone(),
+            spin_down_lag: self.spin_down_lag,
+            poll_interval_secs: self.poll_interval_secs,
+            duty_deadband: self.duty_deadband,
+            min_dwell_secs: self.min_dwell_secs,
+            ramp_up_rate: self.ramp_up_rate,
+            ramp_down_rate: self.ramp_down_rate,
+            emergency_bypass_smoothing: self.emergency_bypass_smoothing,
+            always_on: self.always_on,
+            idle_speed: self.idle_speed,
+            idle_band: self.idle_band,
+            pwm_frequency: self.pwm_frequency,
+            tach_edge: self.tach_edge,
+            tach_pull: self.tach_pull,
+            tach_min_edge_interval_us: self.tach_min_edge_interval_us,
+            pulses_per_rev: self.pulses_per_rev,
+            precompute_curve: self.precompute_curve,
+            sensor_failure: self.sensor_failure,
+            sensor_failure_grace: self.sensor_failure_grace,
+            ..Self::default()
+        };
+        config.bake_curve();
+        config
+    }
+
+    /// Turns off everything that relies on reading the fan speed, which a dry
+    /// run has no way to do
+    pub fn prepare_dry_run(&mut self) {
+        self.rpm_source = RpmSource::Tach;
+        self.without_rpm();
+    }
+
+    /// Turns off everything that relies on reading the fan speed, for when
+    /// it can't be read
+    pub fn without_rpm(&mut self) {
+        self.rpm_warn_low = None;
+        self.rpm_warn_high = None;
+        self.rpm_target = None;
+        self.tach_loss_test = false;
+        self.detect_absent_fan = false;
+        self.off_confirmation = None;
+        self.critical_stall = None;
+    }
+
+    /// Returns the slowest speed (between 0.0 and 1.0) that the fan is ever
+    /// commanded to, which is [`crate::FAN_OFF`] unless `always_on` is set
+    pub fn speed_floor(&self) -> f32 {
+        if self.always_on {
+            self.idle_speed
+        } else {
+            crate::FAN_OFF
+        }
+    }
+
+    /// [°C] the CPU temperature at or above which the health is `WARN`
+    pub fn warn_temp(&self) -> f32 {
+        self.health
+            .warn_temp
+            .unwrap_or((self.min_temp + self.max_temp) / 2.0)
+    }
+
+    /// Returns the PWM frequency to use while the given profile is active
+    pub fn pwm_frequency(&self, profile: Option<&str>) -> f64 {
+        profile
+            .and_then(|name| self.profiles.get(name))
+            .and_then(|profile| profile.pwm_frequency)
+            .unwrap_or(match &self.fan_driver {
+                FanBackend::Pwm | FanBackend::Hwmon(_) => self.pwm_frequency,
+                FanBackend::I2c(i2c) => i2c.frequency,
+            })
+    }
+
+    /// Checks that no two of the GPIO pins in use, including the PWM outputs
+    /// on the given board, are the same pin
+    fn check_pins(&self, board: Board) -> Result<(), String> {
+        let mut pins: Vec<(u8, String)> = Vec::new();
+        if matches!(self.fan_driver, FanBackend::Pwm) {
+            let channel = self
+                .pwm_channel
+                .unwrap_or_else(|| board.default_pwm_channel());
+            if let Some(pin) = board.pwm_pin(channel) {
+                pins.push((pin, format!("the PWM output of {}", self.fan_name)));
+            }
+        }
+        if self.rpm_source == RpmSource::Tach {
+            pins.push((
+                crate::TACH_PIN,
+                format!("the tachometer of {}", self.fan_name),
+            ));
+        }
+        pins.extend(self.power_pin.map(|pin| (pin, "power_pin".to_string())));
+        pins.extend(self.led_pin.map(|pin| (pin, "led_pin".to_string())));
+        pins.extend(
+            self.boost_button_pin
+                .map(|pin| (pin, "boost_button_pin".to_string())),
+        );
+        for zone in &self.zones {
+            if let Some(pin) = board.pwm_pin(zone.pwm_channel) {
+                pins.push((pin, format!("the PWM output of {}", zone.name)));
+            }
+            pins.extend(
+                zone.tach_pin
+                    .map(|pin| (pin, format!("the tachometer of {}", zone.name))),
+            );
+        }
+
+        for (i, (pin, user)) in pins.iter().enumerate() {
+            if let Some((_, other)) = pins[..i].iter().find(|(other, _)| other == pin) {
+                return Err(format!(
+                    "BCM GPIO {pin} is used as both {other} and {user} on the {}",
+                    board.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the values that can't be checked while parsing
+    fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs.is_nan() || self.poll_interval_secs <= 0.0 {
+            return Err(format!(
+                "poll_interval_secs must be positive, got {}",
+                self.poll_interval_secs
+            ));
+        }
+        if let Some(slew) = self.max_temp_slew_per_cycle {
+            if slew.is_nan() || slew <= 0.0 {
+                return Err(format!(
+                    "max_temp_slew_per_cycle must be positive, got {slew}"
+                ));
+            }
+        }
+        if let Some(halflife) = self.smoothing_halflife_secs {
+            if halflife.is_nan() || halflife <= 0.0 {
+                return Err(format!(
+                    "smoothing_halflife_secs must be positive, got {halflife}"
+                ));
+            }
+        }
+        if let Some(freq_boost) = &self.freq_boost {
+            if !(0.0..1.0).contains(&freq_boost.above) {
+                return Err(format!(
+                    "freq_boost.above must be between 0.0 and 1.0, got {}",
+                    freq_boost.above
+                ));
+            }
+            if !(0.0..=1.0).contains(&freq_boost.boost) {
+                return Err(format!(
+                    "freq_boost.boost must be between 0.0 and 1.0, got {}",
+                    freq_boost.boost
+                ));
+            }
+        }
+        if let Some(grace) = &self.max_temp_grace {
+            if grace.secs.is_nan() || grace.secs <= 0.0 {
+                return Err(format!(
+                    "max_temp_grace.secs must be positive, got {}",
+                    grace.secs
+                ));
+            }
+            if grace.critical_temp <= self.max_temp {
+                return Err(format!(
+                    "max_temp_grace.critical_temp must be above max_temp, got {:.1}°C",
+                    grace.critical_temp
+                ));
+            }
+        }
+        if let Some(step) = self.rpm_rounding {
+            if step.is_nan() || step <= 0.0 {
+                return Err(format!("rpm_rounding must be positive, got {step}"));
+            }
+        }
+        if !self.nan_sentinel.is_finite() {
+            return Err("nan_sentinel must be a finite number".to_string());
+        }
+        if self.cpu_scale_divisor == 0.0 || self.cpu_scale_divisor.is_nan() {
+            return Err("cpu_scale_divisor must not be zero".to_string());
+        }
+        if self.pulses_per_rev.is_nan() || self.pulses_per_rev <= 0.0 {
+            return Err(format!(
+                "pulses_per_rev must be positive, got {}",
+                self.pulses_per_rev
+            ));
+        }
+        if self.boost_secs.is_nan() || self.boost_secs <= 0.0 {
+            return Err(format!(
+                "boost_secs must be positive, got {}",
+                self.boost_secs
+            ));
+        }
+        if self.min_dwell_secs.is_nan() || self.min_dwell_secs < 0.0 {
+            return Err(format!(
+                "min_dwell_secs must not be negative, got {}",
+                self.min_dwell_secs
+            ));
+        }
+        for (name, rate) in [
+            ("ramp_up_rate", self.ramp_up_rate),
+            ("ramp_down_rate", self.ramp_down_rate),
+        ] {
+            if let Some(rate) = rate {
+                if rate.is_nan() || rate <= 0.0 {
+                    return Err(format!("{name} must be positive, got {rate}"));
+                }
+            }
+        }
+        if self.spin_down_lag.is_nan() || self.spin_down_lag < 0.0 {
+            return Err(format!(
+                "spin_down_lag must not be negative, got {}",
+                self.spin_down_lag
+            ));
+        }
+        if self.startup_delay_secs.is_nan() || self.startup_delay_secs < 0.0 {
+            return Err(format!(
+                "startup_delay_secs must not be negative, got {}",
+                self.startup_delay_secs
+            ));
+        }
+        if self.soft_start_secs.is_nan() || self.soft_start_secs < 0.0 {
+            return Err(format!(
+                "soft_start_secs must not be negative, got {}",
+                self.soft_start_secs
+            ));
+        }
+        // A measured poll interval is only known once the fan has started
+        if self.auto_poll.is_none() && self.soft_start_secs > self.poll_interval_secs {
+            return Err(format!(
+                "soft_start_secs must not be longer than poll_interval_secs, got {}s and {}s",
+                self.soft_start_secs, self.poll_interval_secs
+            ));
+        }
+        if !(self.off_temp <= self.min_temp && self.min_temp < self.max_temp) {
+            return Err(format!(
+                "thresholds must satisfy off_temp <= min_temp < max_temp, got {:.1}°C, {:.1}°C, \
+                 and {:.1}°C",
+                self.off_temp, self.min_temp, self.max_temp
+            ));
+        }
+        if self.always_on {
+            if !(self.idle_speed > 0.0 && self.idle_speed <= 1.0) {
+                return Err(format!(
+                    "idle_speed must be above 0.0 and at most 1.0, got {}",
+                    self.idle_speed
+                ));
+            }
+            if self.force_off_below.is_some() {
+                return Err("always_on can't be combined with force_off_below".to_string());
+            }
+        }
+        if !(0.0..1.0).contains(&self.duty_deadband) {
+            return Err(format!(
+                "duty_deadband must be between 0.0 and 1.0, got {}",
+                self.duty_deadband
+            ));
+        }
+        match &self.curve {
+            CurveMode::Table(table) => table.validate()?,
+            CurveMode::Exponential { exponent } if exponent.is_nan() || *exponent <= 0.0 => {
+                return Err(format!("curve.exponent must be positive, got {exponent}"));
+            }
+            _ => {}
+        }
+        if self.pwm_frequency.is_nan() || self.pwm_frequency <= 0.0 {
+            return Err(format!(
+                "pwm_frequency must be positive, got {}",
+                self.pwm_frequency
+            ));
+        }
+        if !(0.0..1.0).contains(&self.hardware_min_duty) {
+            return Err(format!(
+                "hardware_min_duty must be at least 0.0 and below 1.0, got {}",
+                self.hardware_min_duty
+            ));
+        }
+        profile::validate(&self.profiles, &self.schedule)?;
+        if self.rpm_source == RpmSource::None {
+            let needs_rpm = [
+                ("rpm_warn_low", self.rpm_warn_low.is_some()),
+                ("rpm_warn_high", self.rpm_warn_high.is_some()),
+                ("rpm_target", self.rpm_target.is_some()),
+                ("tach_loss_test", self.tach_loss_test),
+                ("detect_absent_fan", self.detect_absent_fan),
+                ("off_confirmation", self.off_confirmation.is_some()),
+                ("critical_stall", self.critical_stall.is_some()),
+            ];
+            if let Some((name, _)) = needs_rpm.iter().find(|(_, set)| *set) {
+                return Err(format!(
+                    "{name} needs the fan speed, which rpm_source = \"none\" doesn't read"
+                ));
+            }
+        }
+        if self.rpm_source == RpmSource::I2c {
+            let Some(rpm_i2c) = &self.rpm_i2c else {
+                return Err("rpm_source = \"i2c\" needs an [rpm_i2c] section".to_string());
+            };
+            if !matches!(rpm_i2c.bits, 8 | 16) {
+                return Err(format!(
+                    "rpm_i2c.bits must be 8 or 16, got {}",
+                    rpm_i2c.bits
+                ));
+            }
+            if !(rpm_i2c.scale.is_finite() && rpm_i2c.scale > 0.0) {
+                return Err(format!(
+                    "rpm_i2c.scale must be positive, got {}",
+                    rpm_i2c.scale
+                ));
+            }
+        }
+        if let FanBackend::Hwmon(hwmon) = &self.fan_driver {
+            if hwmon.pwm_max_value == 0 {
+                return Err("fan_driver.pwm_max_value must be positive".to_string());
+            }
+        }
+        if let FanBackend::I2c(i2c) = &self.fan_driver {
+            if i2c.channel > 15 {
+                return Err(format!(
+                    "fan_driver.channel must be between 0 and 15, got {}",
+                    i2c.channel
+                ));
+            }
+            let frequencies = self
+                .profiles
+                .values()
+                .filter_map(|profile| profile.pwm_frequency);
+            for frequency in std::iter::once(i2c.frequency).chain(frequencies) {
+                if !PCA9685_FREQUENCIES.contains(&frequency) {
+                    return Err(format!(
+                        "the PCA9685 can only run at {} to {} Hz, got {frequency} Hz",
+                        PCA9685_FREQUENCIES.start(),
+                        PCA9685_FREQUENCIES.end()
+                    ));
+                }
+            }
+        }
+        if self
+            .host_label
+            .as_ref()
+            .is_some_and(|label| label.trim().is_empty())
+        {
+            return Err("host_label must not be empty".to_string());
+        }
+        if let Some(influx) = &self.influx {
+            if let Some(batch_secs) = influx.batch_secs {
+                if batch_secs.is_nan() || batch_secs <= 0.0 {
+                    return Err(format!(
+                        "influx.batch_secs must be positive, got {batch_secs}"
+                    ));
+                }
+            }
+            if influx.gzip && !influx.url.starts_with("http://") {
+                return Err("influx.gzip is only supported for http:// urls".to_string());
+            }
+        }
+        if let Some(rollup) = &self.rollup {
+            if rollup.secs.is_nan() || rollup.secs <= 0.0 {
+                return Err(format!("rollup.secs must be positive, got {}", rollup.secs));
+            }
+        }
+        if let Some(log_on_change) = &self.log_on_change {
+            if log_on_change.heartbeat_mins.is_nan() || log_on_change.heartbeat_mins <= 0.0 {
+                return Err(format!(
+                    "log_on_change.heartbeat_mins must be positive, got {}",
+                    log_on_change.heartbeat_mins
+                ));
+            }
+        }
+        if let Some(auto_poll) = &self.auto_poll {
+            if auto_poll.min_secs.is_nan() || auto_poll.min_secs <= 0.0 {
+                return Err(format!(
+                    "auto_poll.min_secs must be positive, got {}",
+                    auto_poll.min_secs
+                ));
+            }
+            if auto_poll.max_secs.is_nan() || auto_poll.max_secs < auto_poll.min_secs {
+                return Err(format!(
+                    "auto_poll.max_secs must be at least min_secs, got {}",
+                    auto_poll.max_secs
+                ));
+            }
+        }
+        if let Some(watchdog) = &self.watchdog {
+            let longest_poll = self
+                .auto_poll
+                .map_or(self.poll_interval_secs, |auto_poll| auto_poll.max_secs);
+            if watchdog.timeout_secs.is_nan() || watchdog.timeout_secs <= longest_poll {
+                return Err(format!(
+                    "watchdog.timeout_secs must be longer than poll_interval_secs (or \
+                     auto_poll.max_secs), got {}",
+                    watchdog.timeout_secs
+                ));
+            }
+            if !(watchdog.failsafe_speed > 0.0 && watchdog.failsafe_speed <= 1.0) {
+                return Err(format!(
+                    "watchdog.failsafe_speed must be above 0.0 and at most 1.0, got {}",
+                    watchdog.failsafe_speed
+                ));
+            }
+        }
+        if let Some(temp) = self
+            .critical_stall
+            .as_ref()
+            .and_then(|stall| stall.critical_temp)
+        {
+            if temp.is_nan() || temp < self.max_temp {
+                return Err(format!(
+                    "critical_stall.critical_temp must be at least max_temp, got {temp:.1}°C"
+                ));
+            }
+        }
+        if let Some(target) = &self.rpm_target {
+            if target.rpm <= 0.0 {
+                return Err(format!(
+                    "rpm_target.rpm must be positive, got {}",
+                    target.rpm
+                ));
+            }
+        }
+        if let Some(comfort) = &self.comfort {
+            if comfort.soft_ceiling >= self.max_temp {
+                return Err(format!(
+                    "comfort.soft_ceiling must be below max_temp, got {:.1}°C",
+                    comfort.soft_ceiling
+                ));
+            }
+            if comfort.aggressiveness.is_nan() || comfort.aggressiveness <= 0.0 {
+                return Err(format!(
+                    "comfort.aggressiveness must be positive, got {}",
+                    comfort.aggressiveness
+                ));
+            }
+        }
+        if self.warn_temp() >= self.max_temp {
+            return Err(format!(
+                "health.warn_temp must be below max_temp, got {}",
+                self.warn_temp()
+            ));
+        }
+        if self.fan_name.trim().is_empty() {
+            return Err("fan_name must not be empty".to_string());
+        }
+        for (i, zone) in self.zones.iter().enumerate() {
+            let earlier = &self.zones[..i];
+            if zone.name == self.fan_name || earlier.iter().any(|other| other.name == zone.name) {
+                return Err(format!("zone {:?} is defined twice", zone.name));
+            }
+            if earlier
+                .iter()
+                .any(|other| other.pwm_channel == zone.pwm_channel)
+            {
+                return Err(format!(
+                    "zone {:?} uses PWM channel {}, which another zone already drives",
+                    zone.name, zone.pwm_channel
+                ));
+            }
+            if zone.scale_divisor == 0.0 {
+                return Err(format!("zone {:?} has a scale_divisor of zero", zone.name));
+            }
+            self.for_zone(zone)
+                .validate()
+                .map_err(|e| format!("zone {:?}: {e}", zone.name))?;
+        }
+        self.check_pins(Board::detect())?;
+        if let Some(ambient) = &self.ambient {
+            if ambient.scale_divisor == 0.0 {
+                return Err("ambient.scale_divisor must not be zero".to_string());
+            }
+            if !(0.0..=1.0).contains(&ambient.min_speed) {
+                return Err(format!(
+                    "ambient.min_speed must be between 0.0 and 1.0, got {}",
+                    ambient.min_speed
+                ));
+            }
+        }
+        if let Some(gpu) = &self.gpu {
+            if gpu.scale_divisor == 0.0 {
+                return Err("gpu.scale_divisor must not be zero".to_string());
+            }
+            for (name, temp) in [
+                ("throttle_temp", Some(gpu.throttle_temp)),
+                ("cpu_throttle_temp", gpu.cpu_throttle_temp),
+            ] {
+                if temp.is_some_and(|temp| !temp.is_finite()) {
+                    return Err(format!("gpu.{name} must be a number"));
+                }
+            }
+        }
+        for (i, sensor) in self.sensor_priority.iter().enumerate() {
+            if sensor.scale_divisor == 0.0 {
+                return Err(format!(
+                    "the scale_divisor of sensor {} in sensor_priority ({}) must not be zero",
+                    i + 1,
+                    sensor.path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_parses() {
+        Config::parse(EXAMPLE, "the example", ConfigFormat::Toml).unwrap();
+    }
+
+    /// With every commented-out setting enabled, the example still has to
+    /// parse, so it can't fall behind the fields that the config accepts
+    #[test]
+    fn example_covers_valid_settings() {
+        let uncommented = EXAMPLE
+            .lines()
+            .filter(|line| !line.starts_with("##"))
+            .map(|line| line.strip_prefix('#').unwrap_or(line))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Config::parse(&uncommented, "the uncommented example", ConfigFormat::Toml).unwrap();
+    }
+
+    fn parse(toml: &str) -> Result<Config, AppError> {
+        Config::parse(toml, "a test", ConfigFormat::Toml)
+    }
+
+    /// Every validation rule, with a config that breaks it and part of the
+    /// error it should give
+    #[test]
+    fn each_rule_rejects_bad_input() {
+        let cases = [
+            (
+                "poll_interval_secs = 0.0",
+                "poll_interval_secs must be positive",
+            ),
+            (
+                "max_temp_slew_per_cycle = -1.0",
+                "max_temp_slew_per_cycle must be positive",
+            ),
+            (
+                "smoothing_halflife_secs = 0.0",
+                "smoothing_halflife_secs must be positive",
+            ),
+            (
+                "freq_boost = { above = 1.5, boost = 0.1 }",
+                "freq_boost.above",
+            ),
+            (
+                "freq_boost = { above = 0.9, boost = 2.0 }",
+                "freq_boost.boost",
+            ),
+            (
+                "max_temp_grace = { secs = 0.0, critical_temp = 85.0 }",
+                "max_temp_grace.secs",
+            ),
+            (
+                "max_temp_grace = { secs = 30.0, critical_temp = 70.0 }",
+                "critical_temp must be above max_temp",
+            ),
+            ("rpm_rounding = 0.0", "rpm_rounding must be positive"),
+            ("nan_sentinel = inf", "nan_sentinel must be a finite number"),
+            (
+                "cpu_scale_divisor = 0.0",
+                "cpu_scale_divisor must not be zero",
+            ),
+            ("pulses_per_rev = 0.0", "pulses_per_rev must be positive"),
+            ("boost_secs = 0.0", "boost_secs must be positive"),
+            (
+                "min_dwell_secs = -1.0",
+                "min_dwell_secs must not be negative",
+            ),
+            ("ramp_down_rate = 0.0", "ramp_down_rate must be positive"),
+            ("spin_down_lag = -1.0", "spin_down_lag must not be negative"),
+            (
+                "startup_delay_secs = -1.0",
+                "startup_delay_secs must not be negative",
+            ),
+            (
+                "soft_start_secs = -1.0",
+                "soft_start_secs must not be negative",
+            ),
+            (
+                "poll_interval_secs = 1.0",
+                "soft_start_secs must not be longer than poll_interval_secs",
+            ),
+            (
+                "off_temp = 50.0\nmin_temp = 45.0",
+                "off_temp <= min_temp < max_temp",
+            ),
+            ("min_temp = 75.0", "off_temp <= min_temp < max_temp"),
+            (
+                "always_on = true\nidle_speed = 0.0",
+                "idle_speed must be above 0.0",
+            ),
+            (
+                "always_on = true\nforce_off_below = 50.0",
+                "can't be combined with force_off_below",
+            ),
+            (
+                "duty_deadband = 1.0",
+                "duty_deadband must be between 0.0 and 1.0",
+            ),
+            (
+                "curve = { mode = \"exponential\", exponent = 0.0 }",
+                "curve.exponent must be positive",
+            ),
+            ("pwm_frequency = 0.0", "pwm_frequency must be positive"),
+            (
+                "hardware_min_duty = 1.0",
+                "hardware_min_duty must be at least 0.0",
+            ),
+            (
+                "rpm_source = \"none\"\ntach_loss_test = true",
+                "tach_loss_test needs the fan speed",
+            ),
+            (
+                "fan_driver = { backend = \"hwmon\", path = \"/x\", pwm_max_value = 0 }",
+                "pwm_max_value",
+            ),
+            (
+                "fan_driver = { backend = \"i2c\", channel = 16 }",
+                "fan_driver.channel",
+            ),
+            (
+                "fan_driver = { backend = \"i2c\", channel = 0, frequency = 2000.0 }",
+                "24 to 1526 Hz",
+            ),
+            ("host_label = \"\"", "host_label must not be empty"),
+            ("fan_name = \"\"", "fan_name must not be empty"),
+            ("rollup = { secs = 0.0 }", "rollup.secs must be positive"),
+            (
+                "auto_poll = { min_secs = 0.0 }",
+                "auto_poll.min_secs must be positive",
+            ),
+            (
+                "auto_poll = { min_secs = 10.0, max_secs = 5.0 }",
+                "auto_poll.max_secs",
+            ),
+            ("watchdog = { timeout_secs = 1.0 }", "watchdog.timeout_secs"),
+            (
+                "watchdog = { timeout_secs = 60.0, failsafe_speed = 0.0 }",
+                "watchdog.failsafe_speed",
+            ),
+            (
+                "critical_stall = { critical_temp = 70.0 }",
+                "critical_stall.critical_temp",
+            ),
+            (
+                "rpm_target = { rpm = 0.0 }",
+                "rpm_target.rpm must be positive",
+            ),
+            (
+                "comfort = { soft_ceiling = 80.0 }",
+                "comfort.soft_ceiling must be below max_temp",
+            ),
+            (
+                "comfort = { soft_ceiling = 60.0, aggressiveness = 0.0 }",
+                "comfort.aggressiveness",
+            ),
+            (
+                "health = { warn_temp = 80.0 }",
+                "health.warn_temp must be below max_temp",
+            ),
+            (
+                "ambient = { path = \"/x\", scale_divisor = 0.0, hot_above = 30.0, min_speed = \
+                 0.2 }",
+                "ambient.scale_divisor",
+            ),
+            (
+                "ambient = { path = \"/x\", hot_above = 30.0, min_speed = 2.0 }",
+                "ambient.min_speed",
+            ),
+            (
+                "gpu = { path = \"/x\", scale_divisor = 0.0 }",
+                "gpu.scale_divisor",
+            ),
+            (
+                "gpu = { path = \"/x\", throttle_temp = nan }",
+                "gpu.throttle_temp must be a number",
+            ),
+            (
+                "sensor_priority = [{ path = \"/x\" }, { path = \"/y\", scale_divisor = 0.0 }]",
+                "sensor 2 in sensor_priority (/y)",
+            ),
+            (
+                "shutdown_fan_state = 1.5",
+                "shutdown_fan_state must be between 0.0 and 1.0",
+            ),
+            ("boost_button_pin = 24", "BCM GPIO 24"),
+            ("power_pin = 17\nled_pin = 17", "BCM GPIO 17"),
+        ];
+        for (toml, expected) in cases {
+            let e = parse(toml).expect_err(toml);
+            let message = format!("{e:?}");
+            assert!(message.contains(expected), "{toml:?} gave {message}");
+        }
+    }
+
+    #[test]
+    fn unset_settings_take_their_defaults() {
+        let config = parse("max_temp = 80.0").unwrap();
+        assert_eq!(config.max_temp, 80.0);
+        assert_eq!(config.min_temp, Config::default().min_temp);
+        assert_eq!(
+            config.poll_interval_secs,
+            Config::default().poll_interval_secs
+        );
+        assert!(parse("no_such_setting = 1").is_err());
+    }
+
+    #[test]
+    fn fahrenheit_is_converted_before_validation() {
+        let config =
+            parse("units = \"fahrenheit\"\noff_temp = 104.0\nmin_temp = 113.0\nmax_temp = 167.0");
+        let config = config.unwrap();
+        assert!((config.off_temp - 40.0).abs() < 1e-4);
+        assert!((config.max_temp - 75.0).abs() < 1e-4);
+        // Checked against max_temp in the same units: 158°F is below 167°F,
+        // but 170°F isn't
+        let hot = "units = \"fahrenheit\"\nmin_temp = 113.0\nmax_temp = 167.0\n";
+        assert!(parse(&format!("{hot}comfort = {{ soft_ceiling = 158.0 }}")).is_ok());
+        assert!(parse(&format!("{hot}comfort = {{ soft_ceiling = 170.0 }}")).is_err());
+    }
+
+    #[test]
+    fn profiles_override_the_top_level() {
+        let config = parse(
+            "pwm_frequency = 25000.0\nidle_band = \"step\"\n[profiles.night]\npwm_frequency = \
+             18000.0\nidle_band = \"off\"\n[profiles.day]\nidle_band = \"ramp\"",
+        );
+        let mut config = config.unwrap();
+        assert_eq!(config.pwm_frequency(Some("night")), 18000.0);
+        assert_eq!(config.pwm_frequency(Some("day")), 25000.0);
+        assert_eq!(config.pwm_frequency(None), 25000.0);
+        assert_eq!(config.idle_band(), IdleBand::Step);
+        config.set_profile(Some("night"));
+        assert_eq!(config.idle_band(), IdleBand::Off);
+        config.set_profile(Some("day"));
+        assert_eq!(config.idle_band(), IdleBand::Ramp);
+
+        // The I2C controller's own frequency stands in for the top-level one
+        let config =
+            parse("[fan_driver]\nbackend = \"i2c\"\nchannel = 0\nfrequency = 500.0").unwrap();
+        assert_eq!(config.pwm_frequency(None), 500.0);
+    }
+
+    #[test]
+    fn tunables_are_validated_against_the_rest() {
+        let mut config = Config::default();
+        config.set_tunable("max_temp", "70").unwrap();
+        assert_eq!(config.max_temp, 70.0);
+        // Below min_temp, so the whole change is rejected
+        assert!(config.set_tunable("max_temp", "40").is_err());
+        assert_eq!(config.max_temp, 70.0);
+        assert!(config.set_tunable("pwm_frequency", "100").is_err());
+        assert!(config.set_tunable("min_temp", "warm").is_err());
+
+        // Optional tunables can be cleared, and only those
+        config.set_tunable("force_off_below", "50").unwrap();
+        assert_eq!(config.force_off_below, Some(50.0));
+        config.set_tunable("force_off_below", "none").unwrap();
+        assert_eq!(config.force_off_below, None);
+        config.set_tunable("force_off_below", "50").unwrap();
+        config.set_tunable("force_off_below", "").unwrap();
+        assert_eq!(config.force_off_below, None);
+        let error = config.set_tunable("max_temp", "none").unwrap_err();
+        assert!(error.contains("can't be cleared"), "{error}");
+        assert_eq!(config.max_temp, 70.0);
+
+        let mut config = parse("units = \"fahrenheit\"").unwrap();
+        config.set_tunable("max_temp", "176").unwrap();
+        assert!((config.max_temp - 80.0).abs() < 1e-4);
+
+        // The watchdog has to outlast a cycle, so the poll interval can't
+        // be raised past it
+        let mut config = parse("watchdog = { timeout_secs = 60.0 }").unwrap();
+        let error = config.set_tunable("poll_interval_secs", "120").unwrap_err();
+        assert!(error.contains("watchdog.timeout_secs"), "{error}");
+        assert_eq!(config.poll_interval_secs, 5.0);
+        config.set_tunable("poll_interval_secs", "30").unwrap();
+    }
+
+    #[test]
+    fn reload_takes_the_control_settings_only() {
+        let mut config = parse("pwm_frequency = 25000.0\npoll_interval_secs = 5.0").unwrap();
+        let new = parse("pwm_frequency = 18000.0\npoll_interval_secs = 2.0\nmax_temp = 70.0");
+        config.reload(new.unwrap()).unwrap();
+        assert_eq!(config.max_temp, 70.0);
+        assert_eq!(config.poll_interval_secs, 2.0);
+        assert_eq!(config.pwm_frequency, 25000.0);
+
+        // A measured poll interval outlasts the reload
+        let mut config = parse("auto_poll = {}\npoll_interval_secs = 3.0").unwrap();
+        config
+            .reload(parse("auto_poll = {}\npoll_interval_secs = 9.0").unwrap())
+            .unwrap();
+        assert_eq!(config.poll_interval_secs, 3.0);
+
+        // Checked against the settings that aren't reloaded, and dropped
+        // whole if it doesn't fit them
+        let mut config = parse("health = { warn_temp = 70.0 }").unwrap();
+        let error = config
+            .reload(parse("max_temp = 65.0\nduty_deadband = 0.1").unwrap())
+            .unwrap_err();
+        assert!(
+            error.contains("health.warn_temp must be below max_temp"),
+            "{error}"
+        );
+        assert_eq!(config.max_temp, 75.0);
+        assert_eq!(config.duty_deadband, Config::default().duty_deadband);
+    }
+
+    #[test]
+    fn zones_take_their_own_thresholds() {
+        let config = parse(
+            "duty_deadband = 0.05\nmax_temp = 80.0\n[[zones]]\nsensor = \"/x\"\npwm_channel = \
+             1\nmax_temp = 60.0",
+        );
+        let config = config.unwrap();
+        let zone = config.for_zone(&config.zones[0]);
+        assert_eq!(config.zones[0].name, "fan1");
+        assert_eq!(zone.max_temp, 60.0);
+        assert_eq!(zone.duty_deadband, 0.05);
+        assert_eq!(zone.sensor_failure, SensorFailure::AssumeTemp);
+        assert!(zone.rpm_target.is_none());
+
+        // A reload reaches the zone once it's rebuilt, but its own
+        // thresholds stay
+        let mut config = config;
+        config
+            .reload(
+                parse(
+                    "duty_deadband = 0.1
+sensor_failure = \"full\"",
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let zone = config.for_zone(&config.zones[0]);
+        assert_eq!(zone.duty_deadband, 0.1);
+        assert_eq!(zone.sensor_failure, SensorFailure::Full);
+        assert_eq!(zone.max_temp, 60.0);
+    }
+
+    #[test]
+    fn i2c_rpm_needs_its_register() {
+        let e = Config::parse("rpm_source = \"i2c\"", "a test", ConfigFormat::Toml).unwrap_err();
+        assert!(format!("{e:?}").contains("[rpm_i2c]"));
+        let toml = "rpm_source = \"i2c\"\n[rpm_i2c]\naddress = 0x2f\nregister = 0x3e\nbits = 12";
+        let e = Config::parse(toml, "a test", ConfigFormat::Toml).unwrap_err();
+        assert!(format!("{e:?}").contains("8 or 16"));
+    }
+
+    #[test]
+    fn pin_conflicts_are_rejected() {
+        let e = Config::parse("boost_button_pin = 18", "a test", ConfigFormat::Toml).unwrap_err();
+        assert!(format!("{e:?}").contains("BCM GPIO 18"));
+
+        // Channel 0 is on BCM GPIO 18 on earlier boards, but 12 on the Pi 5
+        let config = Config {
+            pwm_channel: Some(0),
+            power_pin: Some(18),
+            ..Config::default()
+        };
+        assert!(config.check_pins(Board::Legacy).is_err());
+        assert!(config.check_pins(Board::Pi5).is_ok());
+    }
+}
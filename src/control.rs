@@ -0,0 +1,131 @@
+use std::{collections::VecDeque, time::Instant};
+
+use log::{info, warn};
+
+use crate::{comfort::Comfort, pid::Pid, Band};
+
+/// State carried between iterations of the control loop
+#[derive(Default)]
+pub struct ControlState {
+    /// The last few temperature readings, oldest first
+    temps: VecDeque<(Instant, f32)>,
+    /// When the smoothed temperature was last updated, and its value
+    smoothed_temp: Option<(Instant, f32)>,
+    /// [°C] the last temperature reading that wasn't rejected as a glitch
+    accepted_temp: Option<f32>,
+    /// The number of readings in a row that have been rejected as glitches
+    glitches: u32,
+    /// The temperature band that the last reading fell in
+    pub band: Option<Band>,
+    /// When the temperature rose above `max_temp`, and the fan speed at the
+    /// time, while ramping up through the grace period
+    pub hot_since: Option<(Instant, f32)>,
+    /// [°C] the ambient temperature read this cycle, if there's a sensor and
+    /// it could be read
+    pub ambient_temp: Option<f32>,
+    /// The CPU frequency as a fraction of its maximum, read this cycle if the
+    /// frequency boost is enabled and it could be read
+    pub cpu_freq: Option<f32>,
+    /// When the boost started by the boost button runs out, while it's active
+    pub boost_until: Option<Instant>,
+    /// [RPM] the fan speed measured over the last cycle
+    pub rpm: Option<f32>,
+    /// The controller used in RPM target mode
+    pub rpm_pid: Option<Pid>,
+    /// The controller used in comfort mode
+    pub comfort: Option<Comfort>,
+    /// The duty cycle that was last written to the PWM channel
+    pub commanded_duty: Option<f32>,
+    /// When the commanded duty cycle last changed
+    pub last_change: Option<Instant>,
+    /// When the fan speed was last worked out
+    pub last_decision: Option<Instant>,
+    /// The number of cycles in a row that the fan has read 0 RPM while it
+    /// should have been running
+    pub zero_rpm_cycles: u32,
+    /// The number of temperature readings in a row that have failed
+    pub failed_reads: u32,
+}
+
+impl ControlState {
+    /// Records a new temperature reading
+    pub fn record_temp(&mut self, temp: f32) {
+        if self.temps.len() == 3 {
+            self.temps.pop_front();
+        }
+        self.temps.push_back((Instant::now(), temp));
+    }
+
+    /// Whether the latest reading was rejected as a glitch
+    pub fn glitching(&self) -> bool {
+        self.glitches > 0
+    }
+
+    /// Returns the reading, or the previous one if the reading is more than
+    /// `max_slew` away from it (or from the smoothed temperature, if it's
+    /// smoothed) and fewer than `max_rejections` readings in a row have been
+    /// rejected already
+    pub fn reject_glitch(&mut self, temp: f32, max_slew: f32, max_rejections: u32) -> f32 {
+        let previous = self
+            .smoothed_temp
+            .map(|(_, smoothed)| smoothed)
+            .or(self.accepted_temp);
+        if let Some(previous) = previous {
+            if (temp - previous).abs() > max_slew && self.glitches < max_rejections {
+                self.glitches += 1;
+                warn!(
+                    "Rejecting a temperature reading of {temp:.2}°C as a glitch, since it's more \
+                     than {max_slew:.1}°C from {previous:.2}°C ({} of {max_rejections} in a row)",
+                    self.glitches
+                );
+                return self.accepted_temp.unwrap_or(previous);
+            }
+            if self.glitches > 0 {
+                info!(
+                    "Accepting {temp:.2}°C after rejecting {} readings in a row",
+                    self.glitches
+                );
+            }
+        }
+        self.glitches = 0;
+        self.accepted_temp = Some(temp);
+        temp
+    }
+
+    /// Folds a temperature reading into the exponential moving average with
+    /// the given half-life, and returns the new average. The weight of the
+    /// reading comes from the time since the last one, so the smoothing is
+    /// the same whatever the poll interval.
+    pub fn smooth_temp(&mut self, temp: f32, halflife_secs: f32) -> f32 {
+        let now = Instant::now();
+        let smoothed = match self.smoothed_temp {
+            Some((last, smoothed)) => {
+                let tau = halflife_secs / std::f32::consts::LN_2;
+                let alpha = 1.0 - (-(now - last).as_secs_f32() / tau).exp();
+                smoothed + alpha * (temp - smoothed)
+            }
+            None => temp,
+        };
+        self.smoothed_temp = Some((now, smoothed));
+        smoothed
+    }
+
+    /// Returns the acceleration of the temperature in °C/s², computed from
+    /// the last three readings, or `None` if there haven't been three yet.
+    pub fn temp_acceleration(&self) -> Option<f32> {
+        if self.temps.len() < 3 {
+            return None;
+        }
+        let (t0, temp0) = self.temps[0];
+        let (t1, temp1) = self.temps[1];
+        let (t2, temp2) = self.temps[2];
+        let dt0 = (t1 - t0).as_secs_f32();
+        let dt1 = (t2 - t1).as_secs_f32();
+        if dt0 <= 0.0 || dt1 <= 0.0 {
+            return None;
+        }
+        let slope0 = (temp1 - temp0) / dt0;
+        let slope1 = (temp2 - temp1) / dt1;
+        Some((slope1 - slope0) / ((dt0 + dt1) / 2.0))
+    }
+}
@@ -0,0 +1,41 @@
+use std::{io, path::Path};
+
+/// Where each CPU's frequency scaling files live
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+
+/// Returns the current frequency of the CPUs as a fraction of their maximum,
+/// averaged over every CPU that has frequency scaling
+pub fn fraction_of_max() -> io::Result<f32> {
+    let (mut current, mut max) = (0.0, 0.0);
+    for entry in std::fs::read_dir(CPU_DIR)? {
+        let path = entry?.path();
+        let is_cpu = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("cpu"))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+        let cpufreq = path.join("cpufreq");
+        if !is_cpu || !cpufreq.exists() {
+            continue;
+        }
+        current += read_khz(&cpufreq.join("scaling_cur_freq"))?;
+        max += read_khz(&cpufreq.join("cpuinfo_max_freq"))?;
+    }
+    if max <= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no CPU reports its frequency",
+        ));
+    }
+    Ok(current / max)
+}
+
+fn read_khz(path: &Path) -> io::Result<f32> {
+    let raw = std::fs::read_to_string(path)?;
+    raw.trim().parse::<f32>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{raw:?} in {} isn't a number: {e}", path.display()),
+        )
+    })
+}
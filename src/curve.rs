@@ -0,0 +1,198 @@
+use serde::Deserialize;
+
+/// The shape of the fan curve between `min_temp` and `max_temp`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum CurveMode {
+    /// The built-in sinusoidal curve
+    #[default]
+    Sine,
+    /// Linear interpolation between configured points
+    Table(CurveTable),
+    /// A convex curve that stays quiet through the low and middle of the
+    /// range and ramps up hard near `max_temp`
+    Exponential {
+        /// The power that the position between `min_temp` and `max_temp` is
+        /// raised to. Above 1.0, the curve is convex.
+        exponent: f32,
+    },
+}
+
+/// A fan curve given as `[temperature, speed]` points, interpolated linearly
+/// in between and held flat beyond either end
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CurveTable {
+    /// `[°C, speed]` pairs in order of increasing temperature, with speeds
+    /// between 0.0 and 1.0
+    pub points: Vec<[f32; 2]>,
+    /// How the speed is interpolated between points
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+/// How a curve table is interpolated between its points
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    /// Straight lines between points, which leaves a kink at each one
+    #[default]
+    Linear,
+    /// A monotone cubic (PCHIP) spline, which is smooth through each point
+    /// without overshooting either of its neighbours
+    Spline,
+}
+
+impl CurveTable {
+    /// Converts every temperature in the table from Fahrenheit to Celsius
+    pub fn convert_to_celsius(&mut self) {
+        for point in &mut self.points {
+            point[0] = fahrenheit_to_celsius(point[0]);
+        }
+    }
+
+    /// Checks that there's at least one point, that the points are in order
+    /// of increasing temperature, and that every speed is within 0.0..=1.0.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.points.is_empty() {
+            return Err("curve table has no points".to_string());
+        }
+        if let Some(w) = self.points.windows(2).find(|w| w[0][0] >= w[1][0]) {
+            return Err(format!(
+                "curve table temperatures must be increasing, but {} is followed by {}",
+                w[0][0], w[1][0]
+            ));
+        }
+        if let Some([_, speed]) = self.points.iter().find(|[_, s]| !(0.0..=1.0).contains(s)) {
+            return Err(format!(
+                "curve table speed {speed} is outside of 0.0 to 1.0"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the fan speed at `temp`
+    pub fn speed_at(&self, temp: f32) -> f32 {
+        let [first_temp, first_speed] = self.points[0];
+        if temp <= first_temp {
+            return first_speed;
+        }
+        for (i, w) in self.points.windows(2).enumerate() {
+            let [[t0, s0], [t1, s1]] = [w[0], w[1]];
+            if temp <= t1 {
+                return match self.interpolation {
+                    Interpolation::Linear => s0 + (s1 - s0) * (temp - t0) / (t1 - t0),
+                    Interpolation::Spline => {
+                        let h = t1 - t0;
+                        let t = (temp - t0) / h;
+                        let (d0, d1) = (self.slope_at(i), self.slope_at(i + 1));
+                        // The cubic Hermite basis functions
+                        let h00 = (1.0 + 2.0 * t) * (1.0 - t) * (1.0 - t);
+                        let h10 = t * (1.0 - t) * (1.0 - t);
+                        let h01 = t * t * (3.0 - 2.0 * t);
+                        let h11 = t * t * (t - 1.0);
+                        // The slopes already keep it within the segment, but
+                        // rounding could nudge it just outside
+                        (h00 * s0 + h10 * h * d0 + h01 * s1 + h11 * h * d1)
+                            .clamp(s0.min(s1), s0.max(s1))
+                    }
+                };
+            }
+        }
+        self.points[self.points.len() - 1][1]
+    }
+
+    /// Returns the slope of the spline at point `i`, chosen as in the
+    /// Fritsch-Carlson method so that the spline is monotone wherever the
+    /// points are: zero at a local extremum, and otherwise the weighted
+    /// harmonic mean of the slopes of the segments on either side.
+    fn slope_at(&self, i: usize) -> f32 {
+        let secant = |k: usize| {
+            let ([t0, s0], [t1, s1]) = (self.points[k], self.points[k + 1]);
+            ((s1 - s0) / (t1 - t0), t1 - t0)
+        };
+        if i == 0 {
+            return secant(0).0;
+        }
+        if i == self.points.len() - 1 {
+            return secant(i - 1).0;
+        }
+        let ((before, h_before), (after, h_after)) = (secant(i - 1), secant(i));
+        if before * after <= 0.0 {
+            return 0.0;
+        }
+        let w_before = 2.0 * h_after + h_before;
+        let w_after = h_after + 2.0 * h_before;
+        (w_before + w_after) / (w_before / before + w_after / after)
+    }
+}
+
+/// The number of entries per degree in a [`CurveLookup`]
+const LOOKUP_STEPS_PER_DEGREE: f32 = 10.0;
+
+/// A fan curve baked into a table with an entry every tenth of a degree, so
+/// that each cycle costs an index rather than evaluating the curve
+#[derive(Debug, Clone)]
+pub struct CurveLookup {
+    /// [°C] the temperature of the first entry
+    start: f32,
+    speeds: Vec<f32>,
+}
+
+impl CurveLookup {
+    /// Evaluates `speed_at` every tenth of a degree from `start` up to `end`
+    pub fn bake(start: f32, end: f32, speed_at: impl Fn(f32) -> f32) -> Self {
+        let steps = ((end - start) * LOOKUP_STEPS_PER_DEGREE).ceil().max(0.0) as usize;
+        Self {
+            start,
+            speeds: (0..=steps)
+                .map(|i| speed_at(start + i as f32 / LOOKUP_STEPS_PER_DEGREE))
+                .collect(),
+        }
+    }
+
+    /// Returns the speed at the tenth of a degree at or below `temp`, clamped
+    /// to the ends of the table
+    pub fn speed_at(&self, temp: f32) -> f32 {
+        let index = ((temp - self.start) * LOOKUP_STEPS_PER_DEGREE)
+            .floor()
+            .max(0.0) as usize;
+        self.speeds[index.min(self.speeds.len() - 1)]
+    }
+}
+
+pub fn fahrenheit_to_celsius(temp: f32) -> f32 {
+    (temp - 32.0) * 5.0 / 9.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spline_stays_monotone_between_neighbours() {
+        let table = CurveTable {
+            points: vec![
+                [45.0, 0.1],
+                [50.0, 0.12],
+                [55.0, 0.5],
+                [60.0, 0.5],
+                [75.0, 1.0],
+            ],
+            interpolation: Interpolation::Spline,
+        };
+        let mut last = table.speed_at(45.0);
+        for w in table.points.windows(2) {
+            let ([t0, s0], [t1, s1]) = (w[0], w[1]);
+            for step in 0..=100 {
+                let speed = table.speed_at(t0 + (t1 - t0) * step as f32 / 100.0);
+                assert!(
+                    (s0..=s1).contains(&speed),
+                    "{speed} is outside of {s0}..={s1} between {t0}°C and {t1}°C"
+                );
+                assert!(speed >= last, "{speed} dropped below {last}");
+                last = speed;
+            }
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    io::{self, ErrorKind},
+    ops::RangeInclusive,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use rppal::{
+    i2c::I2c,
+    pwm::{Channel, Polarity, Pwm},
+};
+
+use crate::{
+    board::Board,
+    config::{Config, FanBackend, HwmonPwmConfig, I2cConfig, PwmFailureAction, PwmRecoveryConfig},
+    error::AppError,
+};
+
+/// How long to wait before each attempt to re-export the PWM channel
+const PWM_REEXPORT_DELAY: Duration = Duration::from_secs(1);
+
+/// [Hz] the PCA9685's internal oscillator
+const PCA9685_OSCILLATOR: f64 = 25_000_000.0;
+/// The PCA9685's mode register, with the sleep and auto-increment bits
+const PCA9685_MODE1: u8 = 0x00;
+const PCA9685_SLEEP: u8 = 0x10;
+const PCA9685_AUTO_INCREMENT: u8 = 0x20;
+/// The first of the four registers that set channel 0's on and off times.
+/// Each following channel's registers are four higher.
+const PCA9685_LED0_ON_L: u8 = 0x06;
+/// The register that sets the PWM frequency as a divider of the oscillator
+const PCA9685_PRE_SCALE: u8 = 0xfe;
+/// The bit in a channel's ON_H or OFF_H register that holds the output fully
+/// on or off
+const PCA9685_FULL: u8 = 0x10;
+/// The number of steps in each PWM period
+const PCA9685_STEPS: f32 = 4096.0;
+/// [Hz] the frequencies that the prescaler can divide the oscillator down to
+pub const PCA9685_FREQUENCIES: RangeInclusive<f64> = 24.0..=1526.0;
+/// How long the oscillator takes to start after leaving sleep
+const PCA9685_WAKE_DELAY: Duration = Duration::from_micros(500);
+
+/// Something that sets the fan's speed through a PWM signal
+pub trait FanDriver {
+    /// Sets the duty cycle, between 0.0 and 1.0
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError>;
+
+    /// Changes the PWM frequency, leaving the duty cycle at 0%
+    fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError>;
+
+    /// Stops the fan and disables the output
+    fn disable(&mut self) -> Result<(), AppError>;
+
+    /// Describes the output for the startup log
+    fn describe(&self) -> String;
+
+    /// Opens a second handle on the same output, without changing its
+    /// speed, for another thread to drive the fan through
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError>;
+}
+
+impl<T: FanDriver + ?Sized> FanDriver for Box<T> {
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        (**self).set_duty(duty)
+    }
+
+    fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError> {
+        (**self).set_frequency(frequency)
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        (**self).disable()
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        (**self).reopen()
+    }
+}
+
+/// Never writes a duty cycle below `min`, for fans whose controller needs a
+/// trickle of PWM to stay alive even while the fan is meant to be off.
+/// [`FanDriver::disable`] still stops the output outright.
+pub struct MinDuty<D> {
+    inner: D,
+    min: f32,
+}
+
+/// Wraps `driver` so that it never writes a duty cycle below `min`, unless
+/// `min` is 0
+pub fn with_min_duty(driver: Box<dyn FanDriver>, min: f32) -> Box<dyn FanDriver> {
+    if min > 0.0 {
+        Box::new(MinDuty { inner: driver, min })
+    } else {
+        driver
+    }
+}
+
+impl<D: FanDriver> FanDriver for MinDuty<D> {
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        self.inner.set_duty(duty.max(self.min))
+    }
+
+    fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError> {
+        self.inner.set_frequency(frequency)?;
+        self.inner.set_duty(self.min)
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        self.inner.disable()
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{}, writing at least {:.1}%",
+            self.inner.describe(),
+            self.min * 100.0
+        )
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        Ok(Box::new(MinDuty {
+            inner: self.inner.reopen()?,
+            min: self.min,
+        }))
+    }
+}
+
+/// Spaces out the hardware writes of the duty cycle to at most one per
+/// `min_interval`. A duty cycle that comes too soon after the last write is
+/// held back, replacing any that was already held, and written once the
+/// interval is up.
+#[derive(Default)]
+pub struct WriteLimit {
+    min_interval: Duration,
+    last_write: Option<Instant>,
+    written: Option<f32>,
+    pending: Option<f32>,
+}
+
+impl WriteLimit {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a write can go ahead now
+    pub fn ready(&self) -> bool {
+        self.last_write
+            .is_none_or(|last| last.elapsed() >= self.min_interval)
+    }
+
+    /// Holds back `duty` until the interval is up
+    pub fn hold(&mut self, duty: f32) {
+        self.pending = Some(duty);
+    }
+
+    /// Records that `duty` was just written, which supersedes any held back
+    pub fn wrote(&mut self, duty: f32) {
+        self.last_write = Some(Instant::now());
+        self.written = Some(duty);
+        self.pending = None;
+    }
+
+    /// The duty cycle last written, if there's been one
+    pub fn written(&self) -> Option<f32> {
+        self.written
+    }
+
+    /// Returns the duty cycle held back, once it's time to write it
+    pub fn due(&self) -> Option<f32> {
+        self.pending.filter(|_| self.ready())
+    }
+}
+
+/// Opens the configured driver, with the fan stopped, or a driver that drives
+/// nothing in a dry run
+pub fn open(config: &Config, board: Board, dry_run: bool) -> Result<Box<dyn FanDriver>, AppError> {
+    if dry_run {
+        return Ok(with_min_duty(Box::new(DryRun), config.hardware_min_duty));
+    }
+    let driver: Box<dyn FanDriver> = match &config.fan_driver {
+        FanBackend::Pwm => Box::new(PwmDriver::open(
+            crate::pwm_channel(config, board)?,
+            board,
+            config.pwm_frequency,
+            config.pwm_recovery.clone(),
+        )?),
+        FanBackend::I2c(i2c) => Box::new(Pca9685::open(i2c)?),
+        FanBackend::Hwmon(hwmon) => Box::new(HwmonPwm::open(hwmon)?),
+    };
+    Ok(with_min_duty(driver, config.hardware_min_duty))
+}
+
+/// Logs each duty cycle instead of driving a fan, for trying the daemon out
+/// off a Pi
+pub struct DryRun;
+
+impl FanDriver for DryRun {
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        debug!("Dry run, not setting the duty cycle to {duty:.3}");
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, _frequency: f64) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        "Dry run: no fan is driven".to_string()
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        Ok(Box::new(Self))
+    }
+}
+
+/// One of the Pi's own PWM channels
+pub struct PwmDriver {
+    pwm: Pwm,
+    channel: Channel,
+    board: Board,
+    /// [Hz] the frequency that the channel was last set to
+    frequency: f64,
+    /// The number of times in a row that the channel has been re-exported
+    reexports: u32,
+    recovery: PwmRecoveryConfig,
+}
+
+impl PwmDriver {
+    pub fn open(
+        channel: Channel,
+        board: Board,
+        frequency: f64,
+        recovery: PwmRecoveryConfig,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            pwm: export(channel, frequency, 0.0)
+                .map_err(AppError::pwm("export the PWM channel"))?,
+            channel,
+            board,
+            frequency,
+            reexports: 0,
+            recovery,
+        })
+    }
+
+    /// Reacts to every re-export having failed, as `pwm_recovery.on_failure`
+    /// says: by returning `error`, or by running the command and carrying on
+    fn escalate(&mut self, error: AppError) -> Result<(), AppError> {
+        let attempts = std::mem::take(&mut self.reexports);
+        match &self.recovery.on_failure {
+            PwmFailureAction::Exit => {
+                error!("Giving up on the PWM channel after {attempts} failed re-exports");
+                Err(error)
+            }
+            PwmFailureAction::Command(command) => {
+                error!(
+                    "The PWM channel is still missing after {attempts} re-exports, running \
+                     {command:?}"
+                );
+                match Command::new("sh").arg("-c").arg(command).status() {
+                    Ok(status) if status.success() => info!("The PWM recovery command succeeded"),
+                    Ok(status) => warn!("The PWM recovery command failed with {status}"),
+                    Err(e) => warn!("Failed to run the PWM recovery command: {e}"),
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Puts a PWM channel back in a clean state, whatever a previous run left it
+/// in: exported if it wasn't, at 0% duty and disabled, and then unexported if
+/// `unexport` is set
+pub fn reset_pwm(channel: Channel, unexport: bool) -> Result<(), AppError> {
+    let mut pwm = Pwm::new(channel).map_err(AppError::pwm("export the PWM channel"))?;
+    pwm.set_reset_on_drop(false);
+    pwm.set_duty_cycle(0.0)
+        .and_then(|_| pwm.disable())
+        .map_err(AppError::pwm("stop the fan"))?;
+    if unexport {
+        // Dropping it with the reset on unexports the channel
+        pwm.set_reset_on_drop(true);
+    }
+    Ok(())
+}
+
+/// Exports and enables the PWM channel at the given frequency and duty cycle
+fn export(channel: Channel, frequency: f64, duty: f32) -> Result<Pwm, rppal::pwm::Error> {
+    let mut pwm = Pwm::with_frequency(channel, frequency, f64::from(duty), Polarity::Normal, true)?;
+    // Leave the fan running at its last speed on exit, rather than disabling
+    // the PWM channel
+    pwm.set_reset_on_drop(false);
+    Ok(pwm)
+}
+
+impl FanDriver for PwmDriver {
+    /// Sets the duty cycle. If something else has unexported the PWM channel,
+    /// it's re-exported at the same frequency (up to
+    /// `pwm_recovery.max_attempts` times in a row) rather than failing
+    /// straight away, and escalated after that.
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        loop {
+            match self.pwm.set_duty_cycle(f64::from(duty)) {
+                Ok(()) => {
+                    self.reexports = 0;
+                    return Ok(());
+                }
+                Err(rppal::pwm::Error::Io(e))
+                    if e.kind() == ErrorKind::NotFound
+                        && self.reexports == self.recovery.max_attempts =>
+                {
+                    return self.escalate(AppError::pwm("set the PWM duty cycle")(
+                        rppal::pwm::Error::Io(e),
+                    ));
+                }
+                Err(rppal::pwm::Error::Io(e)) if e.kind() == ErrorKind::NotFound => {
+                    self.reexports += 1;
+                    warn!(
+                        "PWM channel has gone missing ({e}), re-exporting it (attempt {} of {})",
+                        self.reexports, self.recovery.max_attempts
+                    );
+                    std::thread::sleep(PWM_REEXPORT_DELAY);
+                    match export(self.channel, self.frequency, duty) {
+                        Ok(new) => self.pwm = new,
+                        Err(e) => warn!("Failed to re-export the PWM channel: {e}"),
+                    }
+                }
+                Err(e) => return Err(AppError::pwm("set the PWM duty cycle")(e)),
+            }
+        }
+    }
+
+    fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError> {
+        self.pwm
+            .set_duty_cycle(0.0)
+            .and_then(|_| self.pwm.set_frequency(frequency, 0.0))
+            .map_err(AppError::pwm("change the PWM frequency"))?;
+        self.frequency = frequency;
+        Ok(())
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        self.pwm
+            .set_duty_cycle(0.0)
+            .and_then(|_| self.pwm.disable())
+            .map_err(AppError::pwm("stop the fan"))
+    }
+
+    fn describe(&self) -> String {
+        let frequency = self
+            .pwm
+            .frequency()
+            .map_or_else(|_| "unknown".to_string(), |f| format!("{f} Hz"));
+        let pin = self
+            .board
+            .pwm_pin(self.channel as u8)
+            .map_or_else(|| "unknown".to_string(), |pin| pin.to_string());
+        format!(
+            "PWM: {} on BCM GPIO {pin} at {frequency} (configured {} Hz), detected as a {}",
+            self.channel,
+            self.frequency,
+            self.board.name()
+        )
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        let mut pwm = Pwm::new(self.channel).map_err(AppError::pwm("export the PWM channel"))?;
+        pwm.set_reset_on_drop(false);
+        Ok(Box::new(Self {
+            pwm,
+            channel: self.channel,
+            board: self.board,
+            frequency: self.frequency,
+            reexports: 0,
+            recovery: self.recovery.clone(),
+        }))
+    }
+}
+
+/// A hwmon `pwmN` node, which takes an integer from 0 up to its maximum
+pub struct HwmonPwm {
+    path: PathBuf,
+    max: u32,
+}
+
+impl HwmonPwm {
+    /// Switches the node to manual control, with the fan stopped
+    pub fn open(config: &HwmonPwmConfig) -> Result<Self, AppError> {
+        let mut enable = config.path.as_os_str().to_owned();
+        enable.push("_enable");
+        let enable = PathBuf::from(enable);
+        // Not every node has one, in which case it's always under manual control
+        if enable.exists() {
+            std::fs::write(&enable, "1").map_err(AppError::io(format!(
+                "enable manual control through {}",
+                enable.display()
+            )))?;
+        }
+        let mut hwmon = Self {
+            path: config.path.clone(),
+            max: config.pwm_max_value,
+        };
+        hwmon.set_duty(0.0)?;
+        Ok(hwmon)
+    }
+
+    /// Reads back the duty cycle that the node holds, between 0.0 and 1.0
+    fn duty(&self) -> io::Result<f32> {
+        let raw = std::fs::read_to_string(&self.path)?;
+        let value = raw.trim().parse::<u32>().map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{raw:?} in {} isn't a PWM value: {e}", self.path.display()),
+            )
+        })?;
+        Ok(value as f32 / self.max as f32)
+    }
+}
+
+impl FanDriver for HwmonPwm {
+    /// Writes the duty cycle, scaled to the node's maximum, and warns if it
+    /// reads back differently
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        let value = (duty.clamp(0.0, 1.0) * self.max as f32).round() as u32;
+        std::fs::write(&self.path, value.to_string())
+            .map_err(AppError::io(format!("write to {}", self.path.display())))?;
+        match self.duty() {
+            Ok(read_back) if (read_back - duty).abs() > 1.0 / self.max as f32 => warn!(
+                "Set {} to {duty:.3} but it reads back {read_back:.3}; check that pwm_max_value \
+                 ({}) matches its range",
+                self.path.display(),
+                self.max
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to read back {}: {e}", self.path.display()),
+        }
+        Ok(())
+    }
+
+    /// The node picks its own frequency, so this does nothing
+    fn set_frequency(&mut self, _frequency: f64) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        self.set_duty(0.0)
+    }
+
+    fn describe(&self) -> String {
+        format!("hwmon: {} (0 to {})", self.path.display(), self.max)
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        Ok(Box::new(Self {
+            path: self.path.clone(),
+            max: self.max,
+        }))
+    }
+}
+
+/// One channel of a PCA9685 PWM expander on an I2C bus, as found on most fan
+/// and servo HATs
+pub struct Pca9685 {
+    i2c: I2c,
+    bus: u8,
+    address: u16,
+    channel: u8,
+    /// [Hz] the frequency that the expander was last set to
+    frequency: f64,
+}
+
+impl Pca9685 {
+    /// Opens the expander, reporting [`AppError::I2cNoAck`] if nothing
+    /// answers at its address. Nothing is written to it.
+    pub fn probe(config: &I2cConfig) -> Result<Self, AppError> {
+        let bus = config.bus;
+        let mut i2c = I2c::with_bus(bus).map_err(AppError::i2c(format!("open /dev/i2c-{bus}")))?;
+        i2c.set_slave_address(config.address)
+            .map_err(AppError::i2c(format!(
+                "address {:#04x} on /dev/i2c-{bus}",
+                config.address
+            )))?;
+        i2c.smbus_read_byte(PCA9685_MODE1)
+            .map_err(AppError::i2c_read(
+                bus,
+                config.address,
+                format!(
+                    "read from the fan controller at {:#04x} on /dev/i2c-{bus}",
+                    config.address
+                ),
+            ))?;
+        Ok(Self {
+            i2c,
+            bus,
+            address: config.address,
+            channel: config.channel,
+            frequency: config.frequency,
+        })
+    }
+
+    /// Opens the expander and sets it to the configured frequency, with the
+    /// fan stopped
+    pub fn open(config: &I2cConfig) -> Result<Self, AppError> {
+        let mut pca9685 = Self::probe(config)?;
+        pca9685.set_frequency(config.frequency)?;
+        Ok(pca9685)
+    }
+
+    /// Writes a channel's on and off times, which start at its ON_L register
+    fn write_channel(&self, on: u16, off: u16) -> Result<(), rppal::i2c::Error> {
+        let [on_l, on_h] = on.to_le_bytes();
+        let [off_l, off_h] = off.to_le_bytes();
+        self.i2c.block_write(
+            PCA9685_LED0_ON_L + 4 * self.channel,
+            &[on_l, on_h, off_l, off_h],
+        )
+    }
+}
+
+/// Returns the PCA9685 prescaler that comes closest to the given frequency
+fn pca9685_prescale(frequency: f64) -> f64 {
+    (PCA9685_OSCILLATOR / (f64::from(PCA9685_STEPS) * frequency)).round() - 1.0
+}
+
+impl FanDriver for Pca9685 {
+    fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+        let steps = (duty.clamp(0.0, 1.0) * PCA9685_STEPS).round() as u16;
+        let full = u16::from(PCA9685_FULL) << 8;
+        let (on, off) = match steps {
+            0 => (0, full),
+            s if s >= PCA9685_STEPS as u16 => (full, 0),
+            s => (0, s),
+        };
+        self.write_channel(on, off).map_err(AppError::i2c(format!(
+            "set the duty cycle of channel {} on the fan controller",
+            self.channel
+        )))
+    }
+
+    /// Changes the prescaler, which the expander only accepts while asleep.
+    /// Every channel's output stops until it wakes.
+    fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError> {
+        let prescale = pca9685_prescale(frequency) as u8;
+        self.write_channel(0, u16::from(PCA9685_FULL) << 8)
+            .and_then(|_| {
+                self.i2c
+                    .smbus_write_byte(PCA9685_MODE1, PCA9685_SLEEP | PCA9685_AUTO_INCREMENT)
+            })
+            .and_then(|_| self.i2c.smbus_write_byte(PCA9685_PRE_SCALE, prescale))
+            .and_then(|_| {
+                self.i2c
+                    .smbus_write_byte(PCA9685_MODE1, PCA9685_AUTO_INCREMENT)
+            })
+            .map_err(AppError::i2c(
+                "change the PWM frequency of the fan controller",
+            ))?;
+        std::thread::sleep(PCA9685_WAKE_DELAY);
+        self.frequency = frequency;
+        Ok(())
+    }
+
+    fn disable(&mut self) -> Result<(), AppError> {
+        self.write_channel(0, u16::from(PCA9685_FULL) << 8)
+            .map_err(AppError::i2c("stop the fan"))
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "I2C: PCA9685 channel {} at {:#04x} on /dev/i2c-{} at {} Hz",
+            self.channel, self.address, self.bus, self.frequency
+        )
+    }
+
+    fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+        Ok(Box::new(Self::probe(&I2cConfig {
+            bus: self.bus,
+            address: self.address,
+            channel: self.channel,
+            frequency: self.frequency,
+        })?))
+    }
+}
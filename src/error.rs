@@ -0,0 +1,251 @@
+use std::{fmt, io, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::{board::Board, format::ConfigFormat};
+
+const UDEV_ERROR: &str = r#"
+As of kernel version 4.14.34, released on April 16 2018, it's possible to configure your Raspberry Pi to allow non-root access to PWM. 
+4.14.34 includes a patch that allows udev to change file permissions when a PWM channel is exported. 
+This will let any user that is a member of the GPIO group configure PWM without having to use sudo.
+
+The udev rules needed to make this work haven't been patched in yet as of June 2018, but you can easily add them yourself. 
+Make sure you're running 4.14.34 or later, and append the following snippet to /etc/udev/rules.d/99-com.rules. Reboot the Raspberry Pi afterwards.
+
+```
+SUBSYSTEM=="pwm*", PROGRAM="/bin/sh -c '\
+    chown -R root:gpio /sys/class/pwm && chmod -R 770 /sys/class/pwm;\
+    chown -R root:gpio /sys/devices/platform/soc/*.pwm/pwm/pwmchip* &&\
+    chmod -R 770 /sys/devices/platform/soc/*.pwm/pwm/pwmchip*\
+'"
+```
+"#;
+
+const PWM_PERMISSION_ERROR: &str = r#"
+By default, both channels are disabled.
+
+To enable only PWM0 on its default pin (BCM GPIO 18, physical pin 12), add dtoverlay=pwm to /boot/config.txt on Raspberry Pi OS or boot/firmware/usercfg.txt on Ubuntu.
+If you need both PWM channels, replace pwm with pwm-2chan, which enables PWM0 on BCM GPIO 18 (physical pin 12), and PWM1 on BCM GPIO 19 (physical pin 35).
+More details on enabling and configuring PWM on other GPIO pins than the default ones can be found in /boot/overlays/README.
+"#;
+
+const PI5_PWM_PERMISSION_ERROR: &str = r#"
+On the Raspberry Pi 5, PWM is provided by the RP1, which has four channels: PWM0 and PWM1 on BCM GPIO 12 and 13, and PWM2 and PWM3 on BCM GPIO 18 and 19.
+None of them are enabled by default.
+
+To enable PWM2 on BCM GPIO 18 (physical pin 12), add dtoverlay=pwm,pin=18,func=2 to /boot/firmware/config.txt and reboot.
+If you need two channels, dtoverlay=pwm-2chan,pin=18,func=2,pin2=19,func2=2 enables PWM2 and PWM3 on BCM GPIO 18 and 19.
+"#;
+
+const GPIO_PERMISSION_ERROR: &str = r#"
+In recent releases of Raspberry Pi OS (December 2017 or later), users that are part of the gpio group (like the default pi user) can access /dev/gpiomem and /dev/gpiochipN (N = 0-2) without needing additional permissions. 
+Either the current user isn’t a member of the gpio group, or your Raspberry Pi OS distribution isn't up-to-date and doesn't automatically configure permissions for the above-mentioned files. 
+Updating Raspberry Pi OS to the latest release should fix any permission issues. 
+Alternatively, although not recommended, you can run your application with superuser privileges by using sudo.
+
+If you’re unable to update Raspberry Pi OS and its packages (namely raspberrypi-sys-mods) to the latest available release, or updating hasn't fixed the issue, you might be able to manually update your udev rules to set the appropriate permissions. 
+More information can be found at https://github.com/raspberrypi/linux/issues/1225 and https://github.com/raspberrypi/linux/issues/2289.
+"#;
+
+/// Everything that can stop the daemon, along with what it was doing at the
+/// time
+#[derive(Error)]
+pub enum AppError {
+    #[error("Failed to read config from {}", path.display())]
+    ConfigRead {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to parse the {format} config from {source_name}")]
+    ConfigParse {
+        source_name: String,
+        format: ConfigFormat,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("Invalid config from {source_name}: {reason}")]
+    ConfigInvalid { source_name: String, reason: String },
+    #[error("Invalid InfluxDB output: {0}")]
+    Influx(String),
+    #[error("PWM channel {channel} isn't available on the {}", board.name())]
+    PwmChannel { board: Board, channel: u8 },
+    #[error(
+        "The PWM channel is already in use by {}, which holds the lock on {}",
+        holder.map_or("another process".to_string(), |pid| format!("process {pid}")),
+        path.display()
+    )]
+    PwmLocked { path: PathBuf, holder: Option<u32> },
+    #[error("Failed to {context}")]
+    Pwm {
+        context: &'static str,
+        #[source]
+        source: rppal::pwm::Error,
+    },
+    #[error("Failed to {context}")]
+    I2c {
+        context: String,
+        #[source]
+        source: rppal::i2c::Error,
+    },
+    #[error("Nothing acknowledged I2C address {address:#04x} on /dev/i2c-{bus}")]
+    I2cNoAck { bus: u8, address: u16 },
+    #[error("Failed to {context}")]
+    Gpio {
+        context: &'static str,
+        #[source]
+        source: rppal::gpio::Error,
+    },
+    #[error("Failed to {context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("There's no profile named {name:?} in the config")]
+    UnknownProfile { name: String, defined: Vec<String> },
+    #[error("{failed} of {total} checks failed")]
+    Validation { failed: usize, total: usize },
+}
+
+impl AppError {
+    pub fn pwm(context: &'static str) -> impl FnOnce(rppal::pwm::Error) -> Self {
+        move |source| Self::Pwm { context, source }
+    }
+
+    pub fn i2c(context: impl Into<String>) -> impl FnOnce(rppal::i2c::Error) -> Self {
+        move |source| Self::I2c {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Maps a failed read from an I2C device, reporting [`Self::I2cNoAck`] if
+    /// nothing answered at its address
+    pub fn i2c_read(
+        bus: u8,
+        address: u16,
+        context: impl Into<String>,
+    ) -> impl FnOnce(rppal::i2c::Error) -> Self {
+        move |source| match source {
+            rppal::i2c::Error::Io(e)
+                if matches!(e.raw_os_error(), Some(libc::ENXIO | libc::EREMOTEIO)) =>
+            {
+                Self::I2cNoAck { bus, address }
+            }
+            source => Self::i2c(context)(source),
+        }
+    }
+
+    pub fn gpio(context: &'static str) -> impl FnOnce(rppal::gpio::Error) -> Self {
+        move |source| Self::Gpio { context, source }
+    }
+
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Returns advice on fixing the error, for the errors that have a common
+    /// cause
+    fn hint(&self) -> Option<String> {
+        match self {
+            Self::Pwm {
+                source: rppal::pwm::Error::Io(e),
+                ..
+            } => match e.kind() {
+                io::ErrorKind::PermissionDenied => Some(format!(
+                    "Make sure /sys/class/pwm and all of its subdirectories are owned by \
+                     root:gpio, the current user is a member of the gpio group, and udev is \
+                     properly configured as mentioned below. Alternatively, you can launch your \
+                     application using sudo.\n\n{UDEV_ERROR}"
+                )),
+                io::ErrorKind::NotFound => Some(format!(
+                    "You may have forgotten to enable the selected PWM channel. The configuration \
+                     options to enable the PWM channels are listed below.\n\n{}",
+                    pwm_overlay_help(Board::detect())
+                )),
+                _ => None,
+            },
+            Self::Pwm {
+                source: rppal::pwm::Error::InvalidChannel,
+                ..
+            } => Some(pwm_overlay_help(Board::detect()).to_string()),
+            Self::Pwm {
+                source: rppal::pwm::Error::UnknownModel,
+                ..
+            } => Some(
+                "The Raspberry Pi model couldn't be identified, so the right PWM chip couldn't be \
+                 picked. Make sure you're running on a Raspberry Pi with /proc/device-tree/model \
+                 readable."
+                    .to_string(),
+            ),
+            Self::PwmChannel { board, channel } => Some(format!(
+                "Set pwm_channel to one of the channels below, or leave it unset to use channel \
+                 {} on BCM GPIO 18. Channel {channel} was likely meant for a different \
+                 board.\n\n{}",
+                board.default_pwm_channel(),
+                pwm_overlay_help(*board)
+            )),
+            Self::I2c {
+                source: rppal::i2c::Error::Io(e),
+                ..
+            } => match e.kind() {
+                io::ErrorKind::PermissionDenied => Some(
+                    "Make sure the current user is a member of the i2c group, or launch your \
+                     application using sudo."
+                        .to_string(),
+                ),
+                io::ErrorKind::NotFound => Some(
+                    "The I2C bus isn't enabled. Add dtparam=i2c_arm=on to \
+                     /boot/firmware/config.txt (or enable I2C under Interface Options in \
+                     raspi-config) and reboot."
+                        .to_string(),
+                ),
+                _ => None,
+            },
+            Self::I2cNoAck { bus, address } => Some(format!(
+                "Make sure the fan HAT is seated and powered, and that address matches its \
+                 address jumpers. `i2cdetect -y {bus}` lists the addresses that answer on the \
+                 bus, which should include {address:#04x}."
+            )),
+            Self::UnknownProfile { defined, .. } if defined.is_empty() => {
+                Some("The config doesn't define any [profiles.<name>] sections.".to_string())
+            }
+            Self::UnknownProfile { defined, .. } => {
+                Some(format!("The config defines: {}", defined.join(", ")))
+            }
+            Self::Gpio {
+                source: rppal::gpio::Error::PermissionDenied(_),
+                ..
+            } => Some(GPIO_PERMISSION_ERROR.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn pwm_overlay_help(board: Board) -> &'static str {
+    match board {
+        Board::Pi5 => PI5_PWM_PERMISSION_ERROR,
+        Board::Legacy => PWM_PERMISSION_ERROR,
+    }
+}
+
+/// Prints the error along with everything that caused it and any hint, which
+/// is what gets shown when `main` returns an error
+impl fmt::Debug for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")?;
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            write!(f, "\n  Caused by: {cause}")?;
+            source = cause.source();
+        }
+        if let Some(hint) = self.hint() {
+            write!(f, "\n\n{hint}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    path::Path,
+};
+
+/// A named pipe that `key=value` commands are read from without blocking
+pub struct ControlFifo {
+    file: File,
+    /// Anything read after the last complete line
+    pending: String,
+}
+
+impl ControlFifo {
+    /// Opens the named pipe at `path`, creating it first if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            // SAFETY: `c_path` is a valid NUL-terminated string
+            if unsafe { libc::mkfifo(c_path.as_ptr(), 0o620) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        Ok(Self {
+            file,
+            pending: String::new(),
+        })
+    }
+
+    /// Returns every complete `key=value` command written since the last
+    /// call. Lines that aren't in that form are returned as an error.
+    pub fn poll(&mut self) -> Vec<Result<(String, String), String>> {
+        let mut buf = [0; 512];
+        loop {
+            match self.file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.pending.push_str(&String::from_utf8_lossy(&buf[..n])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // Nothing more to read right now
+                Err(_) => break,
+            }
+        }
+
+        let Some(end) = self.pending.rfind('\n') else {
+            return Vec::new();
+        };
+        let complete: String = self.pending.drain(..=end).collect();
+        complete
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.split_once('=') {
+                Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+                None => Err(format!("{line:?} isn't a key=value command")),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,561 @@
+//! Reading the config from JSON or YAML as well as TOML. Both are parsed into
+//! the same [`toml::Value`] tree that a TOML config is, so that every format
+//! is deserialized into [`crate::config::Config`] the same way.
+//!
+//! JSON is read in full. YAML is read as the subset that covers a config:
+//! block mappings and sequences, flow collections on one line (`[45, 0.1]`),
+//! plain, single-quoted and double-quoted scalars, and comments. Anchors,
+//! tags, multi-line scalars and multiple documents aren't supported.
+//!
+//! The parsers are written here, rather than taken from `serde_json` and
+//! `serde_yaml`, because those crates aren't among the dependencies that the
+//! daemon can be built with. A YAML error names the subset, so that a config
+//! using more of YAML than that is told why it's rejected.
+
+use std::{fmt, path::Path};
+
+use serde::de::DeserializeOwned;
+use toml::{map::Map, Value};
+
+/// The part of YAML that's read, as it's named in errors
+const YAML_SUBSET: &str = "block mappings and sequences, one-line flow collections, quoted and \
+                           plain scalars, and comments";
+
+/// The formats that the config can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from a `.toml`, `.json`, `.yaml` or `.yml` extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Deserializes the contents. TOML is read by the `toml` crate, whose
+    /// errors point at the offending line and column, as syntax errors in the
+    /// other formats do.
+    pub fn deserialize<T: DeserializeOwned>(self, contents: &str) -> Result<T, String> {
+        let value = match self {
+            Self::Toml => return toml::from_str(contents).map_err(|e| e.to_string()),
+            Self::Json => parse_json(contents)?,
+            Self::Yaml => parse_yaml(contents)
+                .map_err(|e| format!("{e} (only a subset of YAML is read: {YAML_SUBSET})"))?,
+        };
+        value
+            .try_into()
+            .map_err(|e: toml::de::Error| e.message().to_string())
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Toml => "TOML",
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+        })
+    }
+}
+
+fn parse_json(contents: &str) -> Result<Value, String> {
+    let mut flow = Flow {
+        text: contents,
+        pos: 0,
+        json: true,
+    };
+    let value = flow.value()?;
+    flow.skip_whitespace();
+    if flow.pos < contents.len() {
+        return Err(flow.error("expected the end of the document"));
+    }
+    match value {
+        Some(table @ Value::Table(_)) => Ok(table),
+        _ => Err("the document has to be an object".to_string()),
+    }
+}
+
+/// A run of text that's parsed as a JSON value or a YAML flow collection
+struct Flow<'a> {
+    text: &'a str,
+    pos: usize,
+    /// Whether only JSON is accepted, rather than YAML's plain scalars too
+    json: bool,
+}
+
+impl Flow<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Describes a problem at the current position, by line and column
+    fn error(&self, problem: &str) -> String {
+        let before = &self.text[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        format!("{problem} at line {line}, column {column}")
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected `{c}`")));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Parses a value, which is `None` for a null
+    fn value(&mut self) -> Result<Option<Value>, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.mapping().map(Some),
+            Some('[') => self.sequence().map(Some),
+            Some('"') => self.double_quoted().map(|s| Some(Value::String(s))),
+            Some('\'') if !self.json => self.single_quoted().map(|s| Some(Value::String(s))),
+            Some(_) => {
+                let start = self.pos;
+                let end = self.text[start..]
+                    .find([',', ']', '}'])
+                    .map_or(self.text.len(), |i| start + i);
+                let plain = self.text[start..end].trim_end();
+                self.pos = start + plain.len();
+                scalar(plain, self.json).map_err(|problem| {
+                    self.pos = start;
+                    self.error(&problem)
+                })
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn mapping(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut table = Map::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = match self.peek() {
+                Some('"') => self.double_quoted()?,
+                Some('\'') if !self.json => self.single_quoted()?,
+                _ if self.json => return Err(self.error("expected a quoted key")),
+                _ => {
+                    let start = self.pos;
+                    let end = self.text[start..]
+                        .find(':')
+                        .ok_or_else(|| self.error("expected `key: value`"))?;
+                    self.pos += end;
+                    self.text[start..start + end].trim_end().to_string()
+                }
+            };
+            self.expect(':')?;
+            let at = self.pos;
+            if let Some(value) = self.value()? {
+                if table.insert(key.clone(), value).is_some() {
+                    self.pos = at;
+                    return Err(self.error(&format!("{key:?} is defined twice")));
+                }
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Table(table));
+                }
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn sequence(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            let at = self.pos;
+            match self.value()? {
+                Some(value) => items.push(value),
+                None => {
+                    self.pos = at;
+                    return Err(self.error("null isn't allowed in a list"));
+                }
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn double_quoted(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(string);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('0') if !self.json => '\0',
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error(&format!("bad escape \\u{hex}")))?
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        _ => return Err(self.error("bad escape in a string")),
+                    };
+                    string.push(escaped);
+                }
+                c => string.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn single_quoted(&mut self) -> Result<String, String> {
+        self.expect('\'')?;
+        let mut string = String::new();
+        let rest = &self.text[self.pos..];
+        let mut chars = rest.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '\'' {
+                string.push(c);
+            } else if chars.peek().is_some_and(|&(_, next)| next == '\'') {
+                chars.next();
+                string.push('\'');
+            } else {
+                self.pos += i + 1;
+                return Ok(string);
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+/// Reads an unquoted scalar, which is `None` for a null. In JSON it has to be
+/// a number, `true`, `false` or `null`; in YAML, anything else is a string.
+fn scalar(plain: &str, json: bool) -> Result<Option<Value>, String> {
+    let value = match plain {
+        "null" => return Ok(None),
+        "~" | "" if !json => return Ok(None),
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => {
+            let (digits, radix) = match plain.strip_prefix("0x") {
+                Some(hex) if !json => (hex, 16),
+                _ => (plain, 10),
+            };
+            match (i64::from_str_radix(digits, radix), plain.parse::<f64>()) {
+                (Ok(int), _) => Value::Integer(int),
+                (_, Ok(float)) if plain.starts_with(|c: char| c.is_ascii_digit() || c == '-') => {
+                    Value::Float(float)
+                }
+                _ if json => return Err(format!("unexpected `{plain}`")),
+                _ => Value::String(plain.to_string()),
+            }
+        }
+    };
+    Ok(Some(value))
+}
+
+/// A line of YAML with its comment and indentation stripped off
+#[derive(Clone, Copy)]
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+fn parse_yaml(contents: &str) -> Result<Value, String> {
+    let mut lines = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let number = i + 1;
+        let text = strip_comment(line).trim_end();
+        let trimmed = text.trim_start();
+        if trimmed.is_empty() || lines.is_empty() && trimmed == "---" {
+            continue;
+        }
+        let indent = text.len() - trimmed.len();
+        if text[..indent].contains('\t') {
+            return Err(format!(
+                "tabs can't be used for indentation, at line {number}"
+            ));
+        }
+        if trimmed == "---" || trimmed == "..." {
+            return Err(format!("only one document is supported, at line {number}"));
+        }
+        lines.push(Line {
+            number,
+            indent,
+            text: trimmed,
+        });
+    }
+    let Some(&first) = lines.first() else {
+        return Ok(Value::Table(Map::new()));
+    };
+    if is_item(first.text) || split_key(first.text).is_none() {
+        return Err(format!(
+            "the document has to be a mapping, at line {}",
+            first.number
+        ));
+    }
+    let mut pos = 0;
+    let value = block(&mut lines, &mut pos, first.indent)?;
+    match lines.get(pos) {
+        Some(line) => Err(format!("unexpected indentation at line {}", line.number)),
+        None => Ok(value),
+    }
+}
+
+/// Cuts off a `#` comment, which starts a line or follows whitespace, and
+/// isn't inside quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            None if c == '#' && previous.is_whitespace() => return &line[..i],
+            None if c == '"' || c == '\'' => quote = Some(c),
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Splits `key: value` (or `key:`) into the key and the rest, if the line is
+/// a mapping entry
+fn split_key(text: &str) -> Option<(String, &str)> {
+    if text.starts_with(['"', '\'']) {
+        let mut flow = Flow {
+            text,
+            pos: 0,
+            json: false,
+        };
+        let key = match text.as_bytes()[0] {
+            b'"' => flow.double_quoted(),
+            _ => flow.single_quoted(),
+        }
+        .ok()?;
+        let rest = text[flow.pos..].trim_start().strip_prefix(':')?;
+        return (rest.is_empty() || rest.starts_with(' ')).then(|| (key, rest.trim_start()));
+    }
+    if text.starts_with(['[', '{']) {
+        return None;
+    }
+    let colon = text
+        .match_indices(':')
+        .map(|(i, _)| i)
+        .find(|&i| text[i + 1..].is_empty() || text[i + 1..].starts_with(' '))?;
+    Some((
+        text[..colon].trim_end().to_string(),
+        text[colon + 1..].trim_start(),
+    ))
+}
+
+/// Parses the block that starts at `lines[pos]`, at the given indentation
+fn block(lines: &mut [Line], pos: &mut usize, indent: usize) -> Result<Value, String> {
+    if is_item(lines[*pos].text) {
+        sequence(lines, pos, indent)
+    } else {
+        mapping(lines, pos, indent)
+    }
+}
+
+fn mapping(lines: &mut [Line], pos: &mut usize, indent: usize) -> Result<Value, String> {
+    let mut table = Map::new();
+    while let Some(&line) = lines.get(*pos).filter(|line| line.indent == indent) {
+        let (key, rest) = split_key(line.text)
+            .ok_or_else(|| format!("expected `key: value` at line {}", line.number))?;
+        *pos += 1;
+        let value = if rest.is_empty() {
+            match lines.get(*pos) {
+                Some(next) if next.indent > indent => Some(block(lines, pos, next.indent)?),
+                // A sequence can sit at the same indentation as its key
+                Some(next) if next.indent == indent && is_item(next.text) => {
+                    Some(sequence(lines, pos, indent)?)
+                }
+                _ => None,
+            }
+        } else {
+            inline(rest, line.number)?
+        };
+        if let Some(value) = value {
+            if table.insert(key.clone(), value).is_some() {
+                return Err(format!("{key:?} is defined twice, at line {}", line.number));
+            }
+        }
+    }
+    Ok(Value::Table(table))
+}
+
+fn sequence(lines: &mut [Line], pos: &mut usize, indent: usize) -> Result<Value, String> {
+    let mut items = Vec::new();
+    while let Some(&line) = lines
+        .get(*pos)
+        .filter(|line| line.indent == indent && is_item(line.text))
+    {
+        let rest = line.text[1..].trim_start();
+        let item = if rest.is_empty() {
+            *pos += 1;
+            match lines.get(*pos) {
+                Some(next) if next.indent > indent => Some(block(lines, pos, next.indent)?),
+                _ => None,
+            }
+        } else if is_item(rest) || split_key(rest).is_some() {
+            // The item's first line carries on as its own block, indented to
+            // where it starts after the `- `
+            lines[*pos] = Line {
+                number: line.number,
+                indent: indent + line.text.len() - rest.len(),
+                text: rest,
+            };
+            let item_indent = lines[*pos].indent;
+            Some(block(lines, pos, item_indent)?)
+        } else {
+            *pos += 1;
+            inline(rest, line.number)?
+        };
+        items.push(
+            item.ok_or_else(|| format!("null isn't allowed in a list, at line {}", line.number))?,
+        );
+    }
+    Ok(Value::Array(items))
+}
+
+/// Parses a scalar or flow collection that fills the rest of a line
+fn inline(text: &str, number: usize) -> Result<Option<Value>, String> {
+    if text.starts_with(['|', '>']) {
+        return Err(format!(
+            "multi-line scalars aren't supported, at line {number}"
+        ));
+    }
+    let mut flow = Flow {
+        text,
+        pos: 0,
+        json: false,
+    };
+    let located = |e: String| format!("{e} (of line {number})");
+    let value = if text.starts_with(['[', '{', '"', '\'']) {
+        let value = flow.value().map_err(located)?;
+        flow.skip_whitespace();
+        if flow.pos < text.len() {
+            return Err(located(flow.error("expected the end of the line")));
+        }
+        value
+    } else {
+        // A plain scalar runs to the end of the line, commas and all
+        scalar(text, false)?
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        min_temp = 45.0
+        host_label = "pi # 1"
+
+        [curve]
+        mode = "table"
+        points = [[45, 0.1], [60, 0.5]]
+
+        [[schedule]]
+        profile = "night"
+        start = "22:00"
+    "#;
+
+    #[test]
+    fn json_matches_toml() {
+        let json = r#"{
+            "min_temp": 45.0,
+            "host_label": "pi # 1",
+            "pause_file": null,
+            "curve": {"mode": "table", "points": [[45, 0.1], [60, 0.5]]},
+            "schedule": [{"profile": "night", "start": "22:00"}]
+        }"#;
+        assert_eq!(
+            ConfigFormat::Json.deserialize::<Value>(json),
+            ConfigFormat::Toml.deserialize::<Value>(TOML)
+        );
+        assert!(ConfigFormat::Json
+            .deserialize::<Value>("{\"min_temp\": 45,}")
+            .is_err());
+    }
+
+    #[test]
+    fn yaml_matches_toml() {
+        let yaml = "---
+min_temp: 45.0   # a comment
+host_label: 'pi # 1'
+pause_file: ~
+curve:
+  mode: table
+  points:
+    - [45, 0.1]
+    - [60, 0.5]
+schedule:
+- profile: night
+  start: \"22:00\"
+";
+        assert_eq!(
+            ConfigFormat::Yaml.deserialize::<Value>(yaml),
+            ConfigFormat::Toml.deserialize::<Value>(TOML)
+        );
+        assert!(ConfigFormat::Yaml
+            .deserialize::<Value>("curve:\n  mode: table\n   points: []")
+            .is_err());
+        let anchored = ConfigFormat::Yaml
+            .deserialize::<Value>("curve: &curve\n  mode: table\n---\n")
+            .unwrap_err();
+        assert!(anchored.contains(YAML_SUBSET), "{anchored}");
+    }
+}
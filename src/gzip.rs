@@ -0,0 +1,263 @@
+//! A small gzip encoder for telemetry payloads. It finds repeats with a
+//! single-entry hash table and codes them with deflate's fixed Huffman codes,
+//! which is far from the best compression but does well on line protocol,
+//! where every record repeats the measurement, tags and field names.
+
+/// The furthest back that a repeat can be found, as deflate allows
+const WINDOW: usize = 32 * 1024;
+/// The bits of each 3-byte prefix's hash
+const HASH_BITS: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compresses `data` into a complete gzip member
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no modification time, no extra flags, Unix
+    let mut bits = BitWriter {
+        out: vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3],
+        buffer: 0,
+        count: 0,
+    };
+    // A single final block with the fixed Huffman codes
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut i = 0;
+    while i < data.len() {
+        let Some(slot) = hash(data, i) else {
+            bits.literal(data[i]);
+            i += 1;
+            continue;
+        };
+        let candidate = head[slot];
+        head[slot] = i;
+        let length = if candidate != usize::MAX && i - candidate <= WINDOW {
+            data[i..]
+                .iter()
+                .zip(&data[candidate..])
+                .take(MAX_MATCH)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            0
+        };
+        if length < MIN_MATCH {
+            bits.literal(data[i]);
+            i += 1;
+            continue;
+        }
+        bits.repeat(length, i - candidate);
+        for j in i + 1..i + length {
+            if let Some(slot) = hash(data, j) {
+                head[slot] = j;
+            }
+        }
+        i += length;
+    }
+    bits.symbol(256);
+
+    let mut out = bits.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// The CRC-32 (IEEE) of `data`, as the gzip trailer holds
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Hashes the 3 bytes at `i`, if there are that many left
+fn hash(data: &[u8], i: usize) -> Option<usize> {
+    let prefix = data.get(i..i + MIN_MATCH)?;
+    let key = u32::from(prefix[0]) << 16 | u32::from(prefix[1]) << 8 | u32::from(prefix[2]);
+    Some((key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize)
+}
+
+/// Packs bits into bytes least significant bit first, as deflate does
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `count` bits of `value`
+    fn write(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes most significant bit first
+    fn code(&mut self, code: u32, length: u32) {
+        self.write(code.reverse_bits() >> (32 - length), length);
+    }
+
+    /// Writes a literal/length symbol with its fixed Huffman code
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn literal(&mut self, byte: u8) {
+        self.symbol(u16::from(byte));
+    }
+
+    /// Writes a repeat of `length` bytes from `distance` bytes back
+    fn repeat(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASES.partition_point(|&base| usize::from(base) <= length) - 1;
+        self.symbol(257 + code as u16);
+        self.write(
+            (length - usize::from(LENGTH_BASES[code])) as u32,
+            LENGTH_EXTRA_BITS[code].into(),
+        );
+        let code = DISTANCE_BASES.partition_point(|&base| usize::from(base) <= distance) - 1;
+        self.code(code as u32, 5);
+        self.write(
+            (distance - usize::from(DISTANCE_BASES[code])) as u32,
+            DISTANCE_EXTRA_BITS[code].into(),
+        );
+    }
+
+    /// Pads the last byte out with zeros
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn repeated_records_shrink() {
+        let records = "rpi_fan,host=pi cpu_temp=52.1,duty=30,rpm=1800,rpm_alerts=0i \
+                       1700000000000000000\n"
+            .repeat(20);
+        let compressed = compress(records.as_bytes());
+        assert!(compressed.len() < records.len() / 4);
+        assert_eq!(decompress(&compressed), records.as_bytes());
+    }
+
+    #[test]
+    fn edge_cases_match_known_good_output() {
+        // Each of these decompresses to its input with zlib
+        let header = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+        let cases: [(Vec<u8>, &[u8]); 3] = [
+            (Vec::new(), &[0x03, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (
+                b"a".to_vec(),
+                &[0x4b, 0x04, 0x00, 0x43, 0xbe, 0xb7, 0xe8, 1, 0, 0, 0],
+            ),
+            // A literal, then the longest repeat there is, from 1 byte back
+            (
+                vec![b'a'; 1 + MAX_MATCH],
+                &[0x4b, 0x1c, 0x05, 0x00, 0x56, 0xfa, 0xc2, 0x34, 3, 1, 0, 0],
+            ),
+        ];
+        for (data, body) in cases {
+            let compressed = compress(&data);
+            assert_eq!(compressed[..10], header);
+            assert_eq!(&compressed[10..], body, "{} bytes", data.len());
+            assert_eq!(decompress(&compressed), data);
+        }
+    }
+
+    /// Decodes a gzip member made of fixed Huffman blocks, checking its
+    /// trailer, to round-trip [`compress`] without another implementation
+    fn decompress(gzip: &[u8]) -> Vec<u8> {
+        let (body, trailer) = gzip[10..].split_at(gzip.len() - 18);
+        let mut bits = body
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| u32::from(byte >> i & 1)));
+        let mut read =
+            |count: u32| (0..count).fold(0, |value, i| value | bits.next().unwrap() << i);
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = read(1);
+            assert_eq!(read(2), 1, "only fixed Huffman blocks are written");
+            loop {
+                // The fixed codes go most significant bit first, 7 to 9 bits
+                let mut code = (0..7).fold(0, |code, _| code << 1 | read(1));
+                let symbol = match code {
+                    0..=0x17 => code + 256,
+                    _ => {
+                        code = code << 1 | read(1);
+                        match code {
+                            0x30..=0xbf => code - 0x30,
+                            0xc0..=0xc7 => code - 0xc0 + 280,
+                            _ => (code << 1 | read(1)) - 0x190 + 144,
+                        }
+                    }
+                };
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let code = symbol as usize - 257;
+                        let length = usize::from(LENGTH_BASES[code])
+                            + read(LENGTH_EXTRA_BITS[code].into()) as usize;
+                        let code = (0..5).fold(0, |code, _| code << 1 | read(1)) as usize;
+                        let distance = usize::from(DISTANCE_BASES[code])
+                            + read(DISTANCE_EXTRA_BITS[code].into()) as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                break;
+            }
+        }
+        assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+        assert_eq!(trailer[4..], (out.len() as u32).to_le_bytes());
+        out
+    }
+}
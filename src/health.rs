@@ -0,0 +1,115 @@
+use std::{fmt, io, net::UdpSocket, path::PathBuf};
+
+use log::warn;
+
+use crate::sink::{StatusSnapshot, TelemetrySink};
+
+/// A one-word verdict on how the cooling is doing, for external monitoring.
+/// From the worst down:
+///
+/// - `CRITICAL`: the CPU is at or above `max_temp`, the fan has read 0 RPM for
+///   [`crate::TACH_LOSS_CYCLES`] cycles in a row while it should be running, or
+///   this cycle's temperature reading (or a zone's) was rejected as a glitch or
+///   couldn't be read
+/// - `WARN`: the CPU is at or above `health.warn_temp`, or the fan speed has
+///   been outside `rpm_warn_low`..`rpm_warn_high` for `rpm_warn_cycles` cycles
+///   in a row
+/// - `OK`: none of the above
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    #[default]
+    Ok,
+    Warn,
+    Critical,
+}
+
+/// What the health verdict is made from, as of the latest cycle
+pub struct Conditions {
+    /// [°C] the CPU temperature
+    pub cpu_temp: f32,
+    /// [°C]
+    pub warn_temp: f32,
+    /// [°C]
+    pub max_temp: f32,
+    pub stalled: bool,
+    pub sensor_fault: bool,
+    /// Whether the fan speed is out of the RPM warning band
+    pub rpm_alarm: bool,
+}
+
+impl Health {
+    pub fn assess(conditions: &Conditions) -> Self {
+        if conditions.cpu_temp >= conditions.max_temp
+            || conditions.stalled
+            || conditions.sensor_fault
+        {
+            Self::Critical
+        } else if conditions.cpu_temp >= conditions.warn_temp || conditions.rpm_alarm {
+            Self::Warn
+        } else {
+            Self::Ok
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warn => "WARN",
+            Self::Critical => "CRITICAL",
+        }
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where the health word is written each cycle
+pub enum HealthOutput {
+    /// A file whose whole contents are replaced with the word and a newline
+    File(PathBuf),
+    /// A `host:port` that the word is sent to as a UDP datagram
+    Udp(String),
+}
+
+impl HealthOutput {
+    /// Parses `udp://host:port`, or anything else as a file path
+    pub fn parse(output: &str) -> Self {
+        match output.strip_prefix("udp://") {
+            Some(addr) => Self::Udp(addr.trim_end_matches('/').to_string()),
+            None => Self::File(PathBuf::from(output)),
+        }
+    }
+
+    pub fn write(&self, health: Health) -> io::Result<()> {
+        match self {
+            // Written to a temporary file that's renamed over the old one, so
+            // a check never reads a partly written word
+            Self::File(path) => {
+                let mut temp = path.as_os_str().to_owned();
+                temp.push(".tmp");
+                std::fs::write(&temp, format!("{health}\n"))?;
+                std::fs::rename(&temp, path)
+            }
+            Self::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.send_to(health.as_str().as_bytes(), addr.as_str())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl TelemetrySink for HealthOutput {
+    /// Writes the verdict once, since it already covers every fan
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        let Some(snapshot) = snapshots.first() else {
+            return;
+        };
+        if let Err(e) = self.write(snapshot.health) {
+            warn!("Failed to write the health status: {e}");
+        }
+    }
+}
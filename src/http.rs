@@ -0,0 +1,319 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::{HttpConfig, Labels},
+    error::AppError,
+    sink::{StatusSnapshot, TelemetrySink},
+};
+
+/// The dashboard page served at `/`, which polls `/status` for its readings
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// The content type of an OpenMetrics exposition
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// How long a client gets to send its request before it's dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl StatusSnapshot {
+    /// Formats the readings as the fields of a JSON object, along with the
+    /// labels (which only ever hold characters that are safe in a JSON
+    /// string)
+    fn json_fields(&self, host: &str, timestamp: u64) -> String {
+        format!(
+            concat!(
+                "\"host\":\"{host}\",\"fan\":\"{}\",\"cpu_temp\":{},\"duty\":{},\"rpm\":{},",
+                "\"iterations\":{},\"rpm_alerts\":{},\"boost\":{},\"no_reading\":{},{}{}",
+                "\"health\":\"{}\",\"timestamp\":{timestamp}",
+            ),
+            self.fan,
+            match self.no_reading {
+                true => "null".to_string(),
+                false => json_number(self.cpu_temp),
+            },
+            json_number(self.duty),
+            json_number(self.rpm),
+            self.iterations,
+            self.rpm_alerts,
+            self.boost,
+            self.no_reading,
+            self.headroom.map_or_else(String::new, |headroom| format!(
+                "\"headroom_c\":{},",
+                json_number(headroom)
+            )),
+            self.pwm_floor.map_or_else(String::new, |floor| format!(
+                "\"pwm_floor\":{},",
+                json_number(floor)
+            )),
+            self.health,
+            host = host,
+            timestamp = timestamp,
+        )
+    }
+}
+
+/// Formats a reading as a JSON number, or `null` if it isn't finite (as with
+/// `nan_safe_output = false`), which JSON has no number for
+fn json_number(value: f32) -> String {
+    match value.is_finite() {
+        true => value.to_string(),
+        false => "null".to_string(),
+    }
+}
+
+/// Formats the main fan's readings as a JSON object, with an object of the
+/// same fields for each zone in `zones`
+fn to_json(snapshots: &[StatusSnapshot], host: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let Some((main, zones)) = snapshots.split_first() else {
+        return "{}".to_string();
+    };
+    let zones: Vec<String> = zones
+        .iter()
+        .map(|zone| format!("{{{}}}", zone.json_fields(host, timestamp)))
+        .collect();
+    format!(
+        "{{{},\"zones\":[{}]}}",
+        main.json_fields(host, timestamp),
+        zones.join(",")
+    )
+}
+
+/// A metric at `/metrics`, sampled from each fan's snapshot
+struct Metric {
+    name: &'static str,
+    /// `gauge` or `counter`
+    kind: &'static str,
+    unit: Option<&'static str>,
+    help: &'static str,
+    /// The sample's value, formatted
+    value: fn(&StatusSnapshot) -> String,
+}
+
+/// Every metric at `/metrics` but the build info, in the order they're
+/// exposed
+const METRICS: [Metric; 7] = [
+    Metric {
+        name: "rpi_fan_cpu_temp_celsius",
+        kind: "gauge",
+        unit: Some("celsius"),
+        help: "The CPU temperature, or a zone's own.",
+        value: |snapshot| snapshot.cpu_temp.to_string(),
+    },
+    Metric {
+        name: "rpi_fan_duty_ratio",
+        kind: "gauge",
+        unit: Some("ratio"),
+        help: "The commanded fan speed, between 0 and 1.",
+        value: |snapshot| (snapshot.duty / 100.0).to_string(),
+    },
+    Metric {
+        name: "rpi_fan_speed_rpm",
+        kind: "gauge",
+        unit: None,
+        help: "The measured fan speed in RPM.",
+        value: |snapshot| snapshot.rpm.to_string(),
+    },
+    Metric {
+        name: "rpi_fan_boost_active",
+        kind: "gauge",
+        unit: None,
+        help: "Whether the boost button is holding the fan at full speed.",
+        value: |snapshot| u8::from(snapshot.boost).to_string(),
+    },
+    Metric {
+        name: "rpi_fan_iterations",
+        kind: "counter",
+        unit: None,
+        help: "The number of control cycles run.",
+        value: |snapshot| snapshot.iterations.to_string(),
+    },
+    Metric {
+        name: "rpi_fan_rpm_alerts",
+        kind: "counter",
+        unit: None,
+        help: "The number of RPM alerts raised.",
+        value: |snapshot| snapshot.rpm_alerts.to_string(),
+    },
+    Metric {
+        name: "rpi_fan_health",
+        kind: "gauge",
+        unit: None,
+        help: "The health verdict: 0 for OK, 1 for WARN, 2 for CRITICAL.",
+        value: |snapshot| (snapshot.health as u8).to_string(),
+    },
+];
+
+/// Formats the readings as an OpenMetrics exposition, with a sample of each
+/// metric for each fan, labelled with `host` and `fan`. `created` is when the
+/// counters started, in seconds since the Unix epoch.
+fn to_openmetrics(snapshots: &[StatusSnapshot], created: f64, host: &str) -> String {
+    let labels = |snapshot: &StatusSnapshot| format!("host=\"{host}\",fan=\"{}\"", snapshot.fan);
+    let mut exposition = String::new();
+    if let Some(main) = snapshots.first() {
+        exposition += &format!(
+            "# TYPE rpi_fan_build info\n# HELP rpi_fan_build The version of rpi_fan_control \
+             that's running.\nrpi_fan_build_info{{{},version=\"{}\"}} 1\n",
+            labels(main),
+            env!("CARGO_PKG_VERSION"),
+        );
+    }
+    for metric in &METRICS {
+        exposition += &format!("# TYPE {} {}\n", metric.name, metric.kind);
+        if let Some(unit) = metric.unit {
+            exposition += &format!("# UNIT {} {unit}\n", metric.name);
+        }
+        exposition += &format!("# HELP {} {}\n", metric.name, metric.help);
+        for snapshot in snapshots {
+            let (labels, value) = (labels(snapshot), (metric.value)(snapshot));
+            match metric.kind {
+                "counter" => {
+                    exposition += &format!(
+                        "{name}_total{{{labels}}} {value}\n{name}_created{{{labels}}} {created}\n",
+                        name = metric.name
+                    )
+                }
+                _ => exposition += &format!("{}{{{labels}}} {value}\n", metric.name),
+            }
+        }
+    }
+    exposition + "# EOF\n"
+}
+
+/// Serves the dashboard and the latest readings over HTTP. Requests are
+/// handled one at a time on a background thread, so a slow client never
+/// holds up the control loop.
+pub struct HttpServer {
+    status: Arc<Mutex<Vec<StatusSnapshot>>>,
+}
+
+/// What the request handler needs to answer a request
+struct Served {
+    /// The main fan's snapshot, then each zone's
+    status: Arc<Mutex<Vec<StatusSnapshot>>>,
+    /// [s] since the Unix epoch, when the server started
+    created: f64,
+    /// What every reading is tagged with
+    labels: Labels,
+}
+
+impl HttpServer {
+    pub fn start(config: &HttpConfig, labels: Labels) -> Result<Self, AppError> {
+        let listener = TcpListener::bind(&config.listen).map_err(AppError::io(format!(
+            "listen for HTTP on {}",
+            config.listen
+        )))?;
+        info!("Serving the dashboard on http://{}/", config.listen);
+
+        let status = Arc::new(Mutex::new(vec![StatusSnapshot {
+            fan: labels.fan.clone(),
+            ..StatusSnapshot::default()
+        }]));
+        let served = Served {
+            status: Arc::clone(&status),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            labels,
+        };
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| respond(stream, &served));
+                if let Err(e) = result {
+                    warn!("Failed to answer an HTTP request: {e}");
+                }
+            }
+        });
+        Ok(Self { status })
+    }
+}
+
+impl TelemetrySink for HttpServer {
+    /// Replaces the readings served at `/status`
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        *self.status.lock().unwrap() = snapshots.to_vec();
+    }
+}
+
+fn respond(mut stream: TcpStream, served: &Served) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (status_line, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/")) => ("200 OK", "text/html; charset=utf-8", DASHBOARD.to_string()),
+        (Some("GET"), Some("/status")) => (
+            "200 OK",
+            "application/json",
+            to_json(&served.status.lock().unwrap(), &served.labels.host),
+        ),
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            OPENMETRICS_CONTENT_TYPE,
+            to_openmetrics(
+                &served.status.lock().unwrap(),
+                served.created,
+                &served.labels.host,
+            ),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status_line}\r\nContent-Type: {content_type}\r\nContent-Length: \
+         {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rpm_alerts_are_reported_for_each_fan() {
+        let main = StatusSnapshot {
+            fan: "fan0".to_string(),
+            rpm_alerts: 2,
+            ..StatusSnapshot::default()
+        };
+        let zone = StatusSnapshot {
+            fan: "fan1".to_string(),
+            rpm: f32::NAN,
+            ..StatusSnapshot::default()
+        };
+        let snapshots = [main, zone];
+        let json = to_json(&snapshots, "pi");
+        assert!(
+            json.starts_with("{\"host\":\"pi\",\"fan\":\"fan0\","),
+            "{json}"
+        );
+        assert!(json.contains("\"rpm_alerts\":2,"), "{json}");
+        assert!(
+            json.contains("\"zones\":[{\"host\":\"pi\",\"fan\":\"fan1\","),
+            "{json}"
+        );
+        // A zone without a tachometer has no speed, even without a sentinel
+        assert!(json.contains("\"rpm\":null,"), "{json}");
+        let exposition = to_openmetrics(&snapshots, 0.0, "pi");
+        assert!(exposition.contains("# TYPE rpi_fan_rpm_alerts counter\n"));
+        assert!(exposition.contains("rpi_fan_rpm_alerts_total{host=\"pi\",fan=\"fan0\"} 2\n"));
+        assert!(exposition.contains("rpi_fan_rpm_alerts_created{host=\"pi\",fan=\"fan1\"} 0\n"));
+        assert!(exposition.ends_with("# EOF\n"));
+    }
+}
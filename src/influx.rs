@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{
+    config::{InfluxConfig, Labels},
+    error::AppError,
+    gzip,
+    sink::{StatusSnapshot, TelemetrySink},
+};
+
+/// How many unsent records can queue up before new ones are dropped
+const QUEUE_DEPTH: usize = 64;
+/// How many batches that failed to send are kept to retry, before the oldest
+/// is dropped
+const MAX_PENDING_BATCHES: usize = 8;
+/// How long a single HTTP write can take before it's abandoned
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where line-protocol records get sent
+enum Endpoint {
+    Udp(String),
+    Http { host: String, path: String },
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self, String> {
+        if let Some(addr) = url.strip_prefix("udp://") {
+            Ok(Self::Udp(addr.trim_end_matches('/').to_string()))
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            let (host, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/write"),
+            };
+            Ok(Self::Http {
+                host: host.to_string(),
+                path: path.to_string(),
+            })
+        } else {
+            Err(format!(
+                "Unsupported InfluxDB url {url:?}, expected udp://host:port or http://host:port/path"
+            ))
+        }
+    }
+
+    /// Sends a batch of records, gzip-compressed if `compress` is set (which
+    /// config validation only allows over HTTP)
+    fn send(&self, batch: &str, compress: bool) -> Result<(), String> {
+        match self {
+            Self::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+                socket
+                    .send_to(batch.as_bytes(), addr.as_str())
+                    .map_err(|e| e.to_string())?;
+                Ok(())
+            }
+            Self::Http { host, path } => {
+                let addr = host
+                    .to_socket_addrs()
+                    .map_err(|e| e.to_string())?
+                    .next()
+                    .ok_or_else(|| format!("{host} did not resolve to an address"))?;
+                let mut stream =
+                    TcpStream::connect_timeout(&addr, HTTP_TIMEOUT).map_err(|e| e.to_string())?;
+                stream
+                    .set_write_timeout(Some(HTTP_TIMEOUT))
+                    .and_then(|_| stream.set_read_timeout(Some(HTTP_TIMEOUT)))
+                    .map_err(|e| e.to_string())?;
+                let (body, encoding) = if compress {
+                    (
+                        gzip::compress(batch.as_bytes()),
+                        "Content-Encoding: gzip\r\n",
+                    )
+                } else {
+                    (batch.as_bytes().to_vec(), "")
+                };
+                write!(
+                    stream,
+                    "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: text/plain; \
+                     charset=utf-8\r\n{encoding}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .and_then(|_| stream.write_all(&body))
+                .map_err(|e| e.to_string())?;
+
+                let mut response = String::new();
+                stream
+                    .read_to_string(&mut response)
+                    .map_err(|e| e.to_string())?;
+                let status = response.lines().next().unwrap_or_default();
+                match status.split_whitespace().nth(1) {
+                    Some(code) if code.starts_with('2') => Ok(()),
+                    _ => Err(format!("unexpected response {status:?}")),
+                }
+            }
+        }
+    }
+}
+
+/// Writes each control cycle to InfluxDB using the line protocol. Records are
+/// handed off to a background thread, so a slow or unreachable server never
+/// holds up the control loop. With `batch_secs` set, the thread collects
+/// records into one write per batch instead.
+pub struct InfluxOutput {
+    measurement: String,
+    /// Every tag but `fan`
+    tags: BTreeMap<String, String>,
+    /// The `fan` tag that the config gives the main fan, if any
+    main_fan: Option<String>,
+    queue: SyncSender<String>,
+}
+
+impl InfluxOutput {
+    /// Starts the background thread. Every record is tagged with `host` and
+    /// its fan's name as `fan`, unless the config gives tags of its own by
+    /// those names. A `fan` tag in the config only applies to the main fan.
+    pub fn new(config: &InfluxConfig, labels: &Labels) -> Result<Self, AppError> {
+        let endpoint = Endpoint::parse(&config.url).map_err(AppError::Influx)?;
+        let mut tags = config.tags.clone();
+        tags.entry("host".to_string())
+            .or_insert_with(|| labels.host.clone());
+        let main_fan = tags.remove("fan");
+
+        let (queue, records) = mpsc::sync_channel(QUEUE_DEPTH);
+        let batching = Batching {
+            interval: config.batch_secs.map(Duration::from_secs_f32),
+            gzip: config.gzip,
+        };
+        std::thread::spawn(move || send_records(&endpoint, &records, &batching));
+
+        Ok(Self {
+            measurement: escape(&config.measurement, ", "),
+            tags,
+            main_fan,
+            queue,
+        })
+    }
+}
+
+impl InfluxOutput {
+    /// Formats a fan's record, tagged as `fan`
+    fn line(&self, snapshot: &StatusSnapshot, fan: &str, timestamp: u128) -> String {
+        let StatusSnapshot {
+            cpu_temp,
+            duty,
+            rpm,
+            rpm_alerts,
+            no_reading,
+            health,
+            ..
+        } = *snapshot;
+        let mut tags = self.tags.clone();
+        tags.insert("fan".to_string(), fan.to_string());
+        let tags: String = tags
+            .iter()
+            .map(|(k, v)| format!(",{}={}", escape(k, ",= "), escape(v, ",= ")))
+            .collect();
+        // Missing readings, like a zone without a tachometer, are left out,
+        // since line protocol has no NaN
+        let field = |name: &str, value: f32, missing: bool| match missing || !value.is_finite() {
+            true => String::new(),
+            false => format!("{name}={value},"),
+        };
+        format!(
+            "{}{tags} {}{}{}rpm_alerts={rpm_alerts}i,health=\"{health}\" {timestamp}\n",
+            self.measurement,
+            field("cpu_temp", cpu_temp, no_reading),
+            field("duty", duty, false),
+            field("rpm", rpm, false),
+        )
+    }
+}
+
+impl TelemetrySink for InfluxOutput {
+    /// Queues a record of each fan for the cycle, to be written together
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let lines: String = snapshots
+            .iter()
+            .enumerate()
+            .map(|(i, snapshot)| {
+                let fan = match (i, &self.main_fan) {
+                    (0, Some(fan)) => fan,
+                    _ => &snapshot.fan,
+                };
+                self.line(snapshot, fan, timestamp)
+            })
+            .collect();
+        match self.queue.try_send(lines) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("InfluxDB output is backed up, dropping record")
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("InfluxDB output thread has stopped"),
+        }
+    }
+}
+
+/// How the background thread groups records into writes
+struct Batching {
+    /// How long records are collected for before a batch is sent, or `None`
+    /// to send each on its own
+    interval: Option<Duration>,
+    gzip: bool,
+}
+
+/// Sends records as they arrive, or once per batch interval. Batches that
+/// fail to send are retried at the next flush, oldest first, and once too
+/// many have piled up the oldest is dropped.
+fn send_records(endpoint: &Endpoint, records: &Receiver<String>, batching: &Batching) {
+    let mut pending: VecDeque<String> = VecDeque::new();
+    let mut batch = String::new();
+    let mut flush_at = Instant::now() + batching.interval.unwrap_or_default();
+    loop {
+        let received = match batching.interval {
+            Some(_) => records.recv_timeout(flush_at.saturating_duration_since(Instant::now())),
+            None => records.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let disconnected = matches!(received, Err(RecvTimeoutError::Disconnected));
+        if let Ok(line) = received {
+            batch.push_str(&line);
+        }
+        if let Some(interval) = batching.interval {
+            if !disconnected && Instant::now() < flush_at {
+                continue;
+            }
+            flush_at = Instant::now() + interval;
+        }
+
+        if !batch.is_empty() {
+            if pending.len() == MAX_PENDING_BATCHES {
+                pending.pop_front();
+                warn!("InfluxDB output is backed up, dropping the oldest batch");
+            }
+            pending.push_back(std::mem::take(&mut batch));
+        }
+        while let Some(oldest) = pending.front() {
+            match endpoint.send(oldest, batching.gzip) {
+                Ok(()) => {
+                    pending.pop_front();
+                }
+                Err(e) => {
+                    warn!("Failed to write to InfluxDB: {e}");
+                    // Without batching, a record that failed isn't retried
+                    if batching.interval.is_none() {
+                        pending.clear();
+                    }
+                    break;
+                }
+            }
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Backslash-escapes every character in `special`, as the line protocol
+/// requires for measurement names and tags.
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,93 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::curve::{CurveTable, Interpolation};
+
+/// [°C] the most the temperature can change between two readings for the
+/// fan speed to count as holding it steady
+const STEADY_TEMP_DELTA: f32 = 0.5;
+
+/// Watches which fan speed held each temperature steady over a session, and
+/// uses that to suggest a curve table that would have kept the temperature
+/// at or below a target.
+pub struct CurveLearner {
+    /// [°C] the temperature that the suggested curve should hold the CPU at
+    /// or below
+    target_max: f32,
+    /// The sum of the steady-state speeds and number of observations at each
+    /// whole degree
+    bins: BTreeMap<i32, (f32, u32)>,
+    prev_temp: Option<f32>,
+}
+
+impl CurveLearner {
+    pub fn new(target_max: f32) -> Self {
+        Self {
+            target_max,
+            bins: BTreeMap::new(),
+            prev_temp: None,
+        }
+    }
+
+    /// Records one cycle, where `speed` (between 0.0 and 1.0) was commanded
+    /// at `temp`
+    pub fn observe(&mut self, temp: f32, speed: f32) {
+        let steady = self
+            .prev_temp
+            .is_some_and(|prev| (temp - prev).abs() < STEADY_TEMP_DELTA);
+        self.prev_temp = Some(temp);
+        if steady {
+            let bin = self.bins.entry(temp.round() as i32).or_insert((0.0, 0));
+            bin.0 += speed;
+            bin.1 += 1;
+        }
+    }
+
+    /// The number of steady-state observations recorded so far
+    pub fn observations(&self) -> u32 {
+        self.bins.values().map(|(_, count)| count).sum()
+    }
+
+    /// Suggests a curve table, or `None` if nothing steady was observed.
+    ///
+    /// Below the target, each point is the average speed that held that
+    /// temperature steady. Since the hardest load seen had to be held at the
+    /// target too, the curve then reaches the highest steady speed observed
+    /// anywhere by the target temperature, and full speed by `max_temp`.
+    pub fn suggestion(&self, max_temp: f32) -> Option<CurveTable> {
+        let highest = self
+            .bins
+            .values()
+            .map(|(sum, count)| sum / *count as f32)
+            .reduce(f32::max)?;
+
+        let mut points = Vec::new();
+        let mut floor = 0.0_f32;
+        for (&temp, (sum, count)) in &self.bins {
+            let temp = temp as f32;
+            if temp >= self.target_max {
+                break;
+            }
+            // Keep the curve from ever lowering the speed as it gets hotter
+            floor = floor.max(sum / *count as f32);
+            points.push([temp, floor]);
+        }
+        if self.target_max < max_temp {
+            points.push([self.target_max, floor.max(highest)]);
+        }
+        points.push([max_temp, 1.0]);
+        Some(CurveTable {
+            points,
+            interpolation: Interpolation::default(),
+        })
+    }
+}
+
+/// Formats a curve table as the config section that selects it
+pub fn format_curve_table(table: &CurveTable) -> String {
+    let mut config = "[curve]\nmode = \"table\"\npoints = [\n".to_string();
+    for [temp, speed] in &table.points {
+        let _ = writeln!(config, "    [{temp:.1}, {speed:.2}],");
+    }
+    config.push(']');
+    config
+}
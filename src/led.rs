@@ -0,0 +1,75 @@
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rppal::gpio::OutputPin;
+
+/// How often the LED's blink pattern advances
+const TICK: Duration = Duration::from_millis(125);
+/// The number of ticks between toggles while the fan is spinning up
+const SLOW_BLINK_TICKS: u32 = 4;
+
+/// What the status LED shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LedState {
+    /// Off, while the fan is off
+    Off,
+    /// Blinking slowly, while the fan is starting but hasn't been seen
+    /// turning yet
+    SpinningUp,
+    /// Solid, while the fan is running
+    Running,
+    /// Blinking fast, while the fan has stalled or its speed is out of range
+    Alarm,
+}
+
+impl LedState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::SpinningUp,
+            2 => Self::Running,
+            3 => Self::Alarm,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// An LED that reflects the fan's state. The blink patterns are driven from
+/// a background thread, so they keep their timing regardless of the poll
+/// interval.
+pub struct StatusLed {
+    state: Arc<AtomicU8>,
+}
+
+impl StatusLed {
+    pub fn start(mut pin: OutputPin) -> Self {
+        let state = Arc::new(AtomicU8::new(LedState::Off as u8));
+        let shown = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for tick in 0u32.. {
+                let on = match LedState::from_u8(shown.load(Ordering::Relaxed)) {
+                    LedState::Off => false,
+                    LedState::SpinningUp => (tick / SLOW_BLINK_TICKS).is_multiple_of(2),
+                    LedState::Running => true,
+                    LedState::Alarm => tick.is_multiple_of(2),
+                };
+                if on {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+                std::thread::sleep(TICK);
+            }
+        });
+        Self { state }
+    }
+
+    pub fn set(&self, state: LedState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::error::AppError;
+
+/// An advisory lock on the PWM channel, held until it's dropped, so that two
+/// processes never drive the fan at once. The lock file holds the PID of the
+/// process holding it.
+pub struct PwmLock {
+    _file: File,
+}
+
+impl PwmLock {
+    pub fn acquire(path: &Path) -> Result<Self, AppError> {
+        let context = || format!("lock the PWM channel with {}", path.display());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(AppError::io(context()))?;
+        // SAFETY: `flock` only operates on the descriptor, which `file` keeps
+        // open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(AppError::io(context())(e));
+            }
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            return Err(AppError::PwmLocked {
+                path: PathBuf::from(path),
+                holder: holder.trim().parse().ok(),
+            });
+        }
+        file.set_len(0)
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .map_err(AppError::io(context()))?;
+        Ok(Self { _file: file })
+    }
+}
@@ -1,189 +1,2299 @@
+mod autopoll;
+mod board;
+mod change;
+mod comfort;
+mod config;
+mod control;
+mod cpufreq;
+mod curve;
+mod driver;
+mod error;
+mod fifo;
+mod format;
+mod gzip;
+mod health;
+mod http;
+mod influx;
+mod learning;
+mod led;
+mod lock;
+mod pid;
+mod profile;
+mod record;
+mod rpm;
+mod sink;
+mod stdout;
+mod summary;
+mod temperature;
+mod validate;
+mod watchdog;
+mod zone;
+
 use std::{
+    env,
     f32::consts::PI,
+    ffi::OsString,
     io::ErrorKind,
-    sync::{Arc, Mutex},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
+use board::Board;
+use clap::{CommandFactory, Parser};
+use comfort::Comfort;
+use config::{
+    AutoPollConfig, Config, FanBackend, IdleBand, OnNonPi, RpmAveraging, RpmSource, SensorFailure,
+    ShutdownFanState, Thresholds, CONFIG_PATH_ENV, DEFAULT_CONFIG_PATH,
+};
+use control::ControlState;
+use curve::CurveMode;
+use driver::{FanDriver, WriteLimit};
+use error::AppError;
+use fifo::ControlFifo;
+use format::ConfigFormat;
+use health::{Conditions, Health};
+use learning::CurveLearner;
+use led::{LedState, StatusLed};
+use lock::PwmLock;
+use log::{debug, info, warn};
 use once_cell::sync::Lazy;
+use pid::Pid;
+use profile::{TimeOfDay, DEFAULT_PROFILE};
+use record::Record;
+use rpm::{CriticalStall, EdgeFilter, OffConfirmation, PolledRpm, RpmBandMonitor};
 use rppal::{
-    gpio::{Gpio, Trigger},
-    pwm::{Channel, Polarity, Pwm},
+    gpio::{Gpio, Level, OutputPin, Trigger},
+    pwm::Channel,
 };
+use sink::StatusSnapshot;
+use summary::RunSummary;
+use temperature::{Calibration, ControlSource, FailoverSource, SysfsSource, TemperatureSource};
+use watchdog::Watchdog;
+use zone::Zone;
 
-/// The PWM frequency that the PWM fan should operate at (for the Noctua A4x10)
+/// The BCM GPIO pin that the fan's tachometer output is connected to
+const TACH_PIN: u8 = 24;
+/// The default PWM frequency that the PWM fan should operate at (for the
+/// Noctua A4x10)
 const PWM_FREQUENCY: f64 = 25_000.0;
-/// [°C] temperature below which to stop the fan
+/// [°C] default temperature below which to stop the fan
 const OFF_TEMP: f32 = 40.0;
-/// [°C] temperature above which to start the fan
+/// [°C] default temperature above which to start the fan
 const MIN_TEMP: f32 = 45.0;
-/// [°C] temperature above which to start the fan at full speed
+/// [°C] default temperature above which to start the fan at full speed
 const MAX_TEMP: f32 = 75.0;
 
+/// [°C] temperature assumed when the sensor gives a bad reading
+const FALLBACK_TEMP: f32 = 45.0;
+/// [°C] the firmware's default throttle point, assumed when it can't be read
+const DEFAULT_THROTTLE_TEMP: f32 = 85.0;
+/// [°C] range of temperatures that a reading has to fall within to be
+/// believed
+const PLAUSIBLE_TEMPS: RangeInclusive<f32> = -20.0..=150.0;
+
 /// The speed (percentage) that the fan is off at
 const FAN_OFF: f32 = 0.0;
 /// The speed (percentage) that the lowest setting of the fan should be
 const FAN_LOW: f32 = 0.1;
 /// The speed (percentage) that the max setting of the fan is
 const FAN_MAX: f32 = 1.0;
-/// The steps that the fan speed should increase per each degree that the
-/// temperature increase
-const FAN_GAIN: f32 = (FAN_MAX - FAN_LOW) / (MAX_TEMP - MIN_TEMP);
-/// The number of GPIO pulses per revolution - Noctua fans puts out two pluses
-/// per revolution
+/// The default number of GPIO pulses per revolution - Noctua fans puts out two
+/// pluses per revolution
 const FAN_PULSE: f32 = 2.0;
+/// How long tachometer edges are counted for to give each RPM sample
+const RPM_WINDOW: Duration = Duration::from_secs(1);
+/// The number of cycles in a row that the fan has to read 0 RPM while running
+/// before the tachometer loss test is run
+const TACH_LOSS_CYCLES: u32 = 2;
+/// How long the fan is run at full speed while testing for tachometer loss
+const TACH_LOSS_TEST_DURATION: Duration = Duration::from_secs(3);
+/// How long the temperature is sampled for at startup to pick the poll
+/// interval with `auto_poll`
+const AUTO_POLL_DURATION: Duration = Duration::from_secs(20);
+/// How often the temperature is sampled while picking the poll interval
+const AUTO_POLL_SAMPLE: Duration = Duration::from_millis(500);
+/// The fraction of the thermal time constant that `auto_poll` polls at, so
+/// that each cycle sees only a small part of any change in temperature
+const AUTO_POLL_FRACTION: f32 = 0.1;
+/// How long the fan is given to reach its shutdown speed before its speed is
+/// checked, which spans at least one [`RPM_WINDOW`]
+const SHUTDOWN_SETTLE: Duration = Duration::from_secs(2);
+/// How long the fan is given to reach full speed before its pulses are
+/// counted by `--calibrate-pulses`
+const CALIBRATION_SPIN_UP: Duration = Duration::from_secs(5);
+/// How long the pulses are counted for by `--calibrate-pulses`
+const CALIBRATION_DURATION: Duration = Duration::from_secs(5);
+/// The number of intermediate duty cycles that the startup ramp steps through
+const SOFT_START_STEPS: u32 = 20;
+/// How long the boost button's contacts are given to settle after each press
+const BOOST_DEBOUNCE: Duration = Duration::from_millis(50);
 
-const UDEV_ERROR: &str = r#"
-As of kernel version 4.14.34, released on April 16 2018, it's possible to configure your Raspberry Pi to allow non-root access to PWM. 
-4.14.34 includes a patch that allows udev to change file permissions when a PWM channel is exported. 
-This will let any user that is a member of the GPIO group configure PWM without having to use sudo.
-
-The udev rules needed to make this work haven't been patched in yet as of June 2018, but you can easily add them yourself. 
-Make sure you're running 4.14.34 or later, and append the following snippet to /etc/udev/rules.d/99-com.rules. Reboot the Raspberry Pi afterwards.
-
-```
-SUBSYSTEM=="pwm*", PROGRAM="/bin/sh -c '\
-    chown -R root:gpio /sys/class/pwm && chmod -R 770 /sys/class/pwm;\
-    chown -R root:gpio /sys/devices/platform/soc/*.pwm/pwm/pwmchip* &&\
-    chmod -R 770 /sys/devices/platform/soc/*.pwm/pwm/pwmchip*\
-'"
-```
-"#;
-
-const PWM_PERMISSION_ERROR: &str = r#"
-By default, both channels are disabled.
-
-To enable only PWM0 on its default pin (BCM GPIO 18, physical pin 12), add dtoverlay=pwm to /boot/config.txt on Raspberry Pi OS or boot/firmware/usercfg.txt on Ubuntu.
-If you need both PWM channels, replace pwm with pwm-2chan, which enables PWM0 on BCM GPIO 18 (physical pin 12), and PWM1 on BCM GPIO 19 (physical pin 35).
-More details on enabling and configuring PWM on other GPIO pins than the default ones can be found in /boot/overlays/README.
-"#;
-
-const GPIO_PERMISSION_ERROR: &str = r#"
-In recent releases of Raspberry Pi OS (December 2017 or later), users that are part of the gpio group (like the default pi user) can access /dev/gpiomem and /dev/gpiochipN (N = 0-2) without needing additional permissions. 
-Either the current user isn’t a member of the gpio group, or your Raspberry Pi OS distribution isn't up-to-date and doesn't automatically configure permissions for the above-mentioned files. 
-Updating Raspberry Pi OS to the latest release should fix any permission issues. 
-Alternatively, although not recommended, you can run your application with superuser privileges by using sudo.
-
-If you’re unable to update Raspberry Pi OS and its packages (namely raspberrypi-sys-mods) to the latest available release, or updating hasn't fixed the issue, you might be able to manually update your udev rules to set the appropriate permissions. 
-More information can be found at https://github.com/raspberrypi/linux/issues/1225 and https://github.com/raspberrypi/linux/issues/2289.
-"#;
-
-/// Returns the temperature of the CPU in degrees Celsius.
-fn get_cpu_temp() -> f32 {
-    let temp_unparsed = match std::fs::read_to_string("/sys/class/thermal/thermal_zone0/temp") {
-        Ok(temp) => temp,
+/// Returns the temperature of the CPU in degrees Celsius, corrected with
+/// `calibration`, or `None` if this reading failed or was implausible. The
+/// sensor's reading is divided by `divisor` to get °C.
+fn get_cpu_temp(calibration: Calibration, divisor: f32) -> Result<Option<f32>, AppError> {
+    let temp_unparsed = match std::fs::read("/sys/class/thermal/thermal_zone0/temp") {
+        Ok(bytes) => {
+            // Left exactly as read, to tell a partial read from bad data
+            debug!(
+                "Read {} bytes from /sys/class/thermal/thermal_zone0/temp: \"{}\", to be divided \
+                 by {divisor}",
+                bytes.len(),
+                bytes.escape_ascii()
+            );
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
         Err(e) => match e.kind() {
             ErrorKind::PermissionDenied => {
-                panic!("Failed to read /sys/class/thermal/thermal_zone0/temp.")
+                return Err(AppError::Io {
+                    context: "read /sys/class/thermal/thermal_zone0/temp".to_string(),
+                    source: e,
+                })
             }
             ErrorKind::NotFound => {
-                panic!("No temperature sensor found. Make sure you're running on a Raspberry Pi.")
+                return Err(AppError::Io {
+                    context: "find a temperature sensor. Make sure you're running on a Raspberry \
+                              Pi"
+                    .to_string(),
+                    source: e,
+                })
+            }
+            _ => {
+                warn!("Failed to read the CPU temperature: {e}");
+                return Ok(None);
             }
-            _ => "45000".to_string(),
         },
     };
-    temp_unparsed.trim().parse::<f32>().unwrap_or(45000.0) / 1000.0
+    match temp_unparsed.trim().parse::<f32>() {
+        Ok(raw) if PLAUSIBLE_TEMPS.contains(&(raw / divisor)) => {
+            let temp = calibration.apply(raw / divisor);
+            debug!(
+                "Read a CPU temperature of {}°C, calibrated to {temp}°C",
+                raw / divisor
+            );
+            Ok(Some(temp))
+        }
+        _ => {
+            warn!("Ignoring implausible temperature reading {temp_unparsed:?}");
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the CPU temperature, from `sensors` if `sensor_priority` is set,
+/// or [`FALLBACK_TEMP`] in a dry run without a sensor. Like [`get_cpu_temp`],
+/// it's `None` if this reading failed.
+fn read_cpu_temp(
+    config: &Config,
+    sensors: Option<&mut FailoverSource>,
+    dry_run: bool,
+) -> Result<Option<f32>, AppError> {
+    if let Some(sensors) = sensors {
+        return Ok(sensors
+            .read()
+            .map_err(|e| warn!("Failed to read the CPU temperature: {e}"))
+            .ok());
+    }
+    match get_cpu_temp(config.cpu_calibration, config.cpu_scale_divisor) {
+        Err(_) if dry_run => Ok(Some(FALLBACK_TEMP)),
+        temp => temp,
+    }
+}
+
+/// Returns the sensors in `sensor_priority` to fail over between, if there
+/// are any
+fn cpu_sensors(config: &Config) -> Option<FailoverSource> {
+    if config.sensor_priority.is_empty() {
+        return None;
+    }
+    let sources = config
+        .sensor_priority
+        .iter()
+        .map(|sensor| {
+            let source: Box<dyn TemperatureSource> = Box::new(SysfsSource {
+                path: sensor.path.clone(),
+                divisor: sensor.scale_divisor,
+                calibration: sensor.calibration,
+            });
+            (sensor.path.display().to_string(), source)
+        })
+        .collect();
+    info!(
+        "Reading the CPU temperature from the first of {} that can be read",
+        config
+            .sensor_priority
+            .iter()
+            .map(|sensor| sensor.path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Some(FailoverSource::new(sources, PLAUSIBLE_TEMPS))
+}
+
+/// [°C] returns the configured temperature that the CPU throttles at, or the
+/// firmware's `temp_limit` if it's unset
+fn cpu_throttle_temp(configured: Option<f32>) -> f32 {
+    configured.or_else(board::throttle_temp).unwrap_or_else(|| {
+        warn!(
+            "Failed to read the throttle temperature from vcgencmd, assuming \
+             {DEFAULT_THROTTLE_TEMP}°C"
+        );
+        DEFAULT_THROTTLE_TEMP
+    })
+}
+
+/// Logs the hardware and software that the fan is being controlled on, which
+/// comes in handy when reporting issues. Anything that can't be read is
+/// logged as unknown.
+fn log_system_info(driver: &dyn FanDriver, tachometer: &str) {
+    let read = |path: &str| {
+        std::fs::read_to_string(path)
+            .map(|s| {
+                s.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+                    .to_string()
+            })
+            .unwrap_or_else(|_| "unknown".to_string())
+    };
+
+    info!("Model: {}", read("/proc/device-tree/model"));
+    info!("Kernel: {}", read("/proc/sys/kernel/osrelease"));
+    info!("{}", driver.describe());
+    info!("Tachometer: {tachometer}");
 }
 
 /// The custom fan curve that determines the speed that the fan should be at
 /// based on the temperature reported back by the Raspberry Pi
 #[inline]
-fn fan_curve(temp: f32) -> f32 {
+fn fan_curve(temp: f32, config: &Config) -> f32 {
+    // The steps that the fan speed should increase per each degree that the
+    // temperature increase
+    let fan_gain = (FAN_MAX - FAN_LOW) / (config.max_temp - config.min_temp);
     (0.5 * (1.0 - ((PI * temp) / 50.0).sin())
-        + (FAN_LOW + ((temp - MIN_TEMP).min(MAX_TEMP) * FAN_GAIN)))
+        + (FAN_LOW + ((temp - config.min_temp).min(config.max_temp) * fan_gain)))
         / 2.0
 }
 
-/// Returns the fan speed as a value between 0.0 and 1.0.
-fn handle_fan_speed(cpu_temp: f32, pwm: &mut Pwm) -> Result<f32, std::io::Error> {
-    let fan_percentage = match cpu_temp {
-        t if t < OFF_TEMP => FAN_OFF,
-        t if t < MIN_TEMP => FAN_LOW,
-        t if t < MAX_TEMP => fan_curve(t),
-        _ => FAN_MAX,
-    };
-    pwm.set_duty_cycle(f64::from(fan_percentage))
-        .map_err(|rppal::pwm::Error::Io(e)| e)?;
-    Ok(fan_percentage * 100.0)
-}
-
-static TIME_DIFF: Lazy<Arc<Mutex<Instant>>> = Lazy::new(|| Arc::new(Mutex::new(Instant::now())));
-static RPM: Lazy<Arc<Mutex<Vec<f32>>>> = Lazy::new(|| Arc::new(Mutex::new(vec![0.0])));
-
-fn main() {
-    let mut pwm_pin =
-        match Pwm::with_frequency(Channel::Pwm0, PWM_FREQUENCY, 0.0, Polarity::Normal, true) {
-            Ok(pin) => pin,
-            Err(rppal::pwm::Error::Io(e)) => match e.kind() {
-                ErrorKind::PermissionDenied => {
-                    eprintln!(
-                        "Make sure /sys/class/pwm and all of its subdirectories are owned by \
-                         root:gpio, the current user is a member of the gpio group, and udev is \
-                         properly configured as mentioned below. Alternatively, you can launch \
-                         your application using sudo.\n\n{}",
-                        UDEV_ERROR
-                    );
-                    std::process::exit(1);
+/// The temperature bands that the fan behaves differently in, from coolest to
+/// hottest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Band {
+    /// Below `force_off_below`, so the fan is off regardless of the curve
+    ForcedOff,
+    /// Below `off_temp`, so the fan is off
+    Off,
+    /// Below `min_temp`, so the fan runs as the active `idle_band` says
+    Idle,
+    /// Below `max_temp`, so the fan follows the curve
+    Active,
+    /// At or above `max_temp`, so the fan runs at full speed
+    Max,
+}
+
+/// Returns the band that the given temperature falls in
+fn band(cpu_temp: f32, config: &Config) -> Band {
+    match cpu_temp {
+        t if t < config.max_temp && config.force_off_below.is_some_and(|quiet| t < quiet) => {
+            Band::ForcedOff
+        }
+        t if t < config.off_temp => Band::Off,
+        t if t < config.min_temp => Band::Idle,
+        t if t < config.max_temp => Band::Active,
+        _ => Band::Max,
+    }
+}
+
+/// Returns the fan speed (as a value between 0.0 and 1.0) that should be
+/// commanded at the given temperature, from the precomputed table if there is
+/// one.
+fn fan_speed(cpu_temp: f32, config: &Config) -> f32 {
+    match &config.curve_lookup {
+        Some(lookup) if (config.off_temp..config.max_temp).contains(&cpu_temp) => {
+            lookup.speed_at(cpu_temp)
+        }
+        _ => evaluate_fan_speed(cpu_temp, config),
+    }
+}
+
+/// Returns the fan speed at the given temperature, evaluating the curve
+fn evaluate_fan_speed(cpu_temp: f32, config: &Config) -> f32 {
+    band_speed(cpu_temp, config).max(config.speed_floor())
+}
+
+/// Returns the speed that the band and curve alone give at the given
+/// temperature, before the always-on floor
+fn band_speed(cpu_temp: f32, config: &Config) -> f32 {
+    match band(cpu_temp, config) {
+        Band::ForcedOff | Band::Off => FAN_OFF,
+        Band::Idle => match config.idle_band() {
+            IdleBand::Step => FAN_LOW,
+            IdleBand::Ramp => {
+                FAN_LOW * (cpu_temp - config.off_temp) / (config.min_temp - config.off_temp)
+            }
+            IdleBand::Off => FAN_OFF,
+        },
+        Band::Active => match &config.curve {
+            CurveMode::Sine => fan_curve(cpu_temp, config),
+            CurveMode::Table(table) => table.speed_at(cpu_temp),
+            CurveMode::Exponential { exponent } => {
+                let position = (cpu_temp - config.min_temp) / (config.max_temp - config.min_temp);
+                FAN_LOW + (FAN_MAX - FAN_LOW) * position.clamp(0.0, 1.0).powf(*exponent)
+            }
+        },
+        Band::Max => FAN_MAX,
+    }
+}
+
+/// Reloads the settings that shape the fan speed from `path`, logging the
+/// fan speed before and after at each of `reload_preview_temps`. The current
+/// config is kept if the new one can't be loaded.
+fn reload_config(config: &mut Config, path: &Path, explicit: bool, format: Option<ConfigFormat>) {
+    let mut new = match Config::load(path, explicit, format) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Failed to reload the config, keeping the current one: {e:?}");
+            return;
+        }
+    };
+    if new.thresholds == Thresholds::AutoTrip {
+        new.apply_trip_points();
+    }
+    let before: Vec<f32> = new
+        .reload_preview_temps
+        .iter()
+        .map(|&temp| fan_speed(temp, config))
+        .collect();
+    if let Err(e) = config.reload(new) {
+        warn!("Ignoring the reloaded config, keeping the current one: {e}");
+        return;
+    }
+    info!("Reloaded the config from {}", path.display());
+    for (&temp, before) in config.reload_preview_temps.iter().zip(before) {
+        let after = fan_speed(temp, config);
+        info!(
+            "  at {temp:.1}°C: {:.1}% -> {:.1}%{}",
+            before * 100.0,
+            after * 100.0,
+            if before == after { " (unchanged)" } else { "" }
+        );
+    }
+}
+
+/// The hardware that drives the fan
+struct Fan {
+    driver: Box<dyn FanDriver>,
+    /// [Hz] the frequency that the PWM signal was last set to
+    frequency: f64,
+    /// A GPIO that switches power to the fan (e.g. through a MOSFET), which is
+    /// only driven high while the fan should be running
+    power: Option<OutputPin>,
+    /// Spaces out the control loop's writes of the duty cycle
+    write_limit: WriteLimit,
+}
+
+impl Fan {
+    fn set_power(&mut self, on: bool) {
+        if let Some(power) = &mut self.power {
+            power.write(if on { Level::High } else { Level::Low });
+        }
+    }
+}
+
+/// Ramps the fan from 0% up to `target` over `duration`, rather than enabling
+/// the PWM channel straight at a nonzero duty, which can make the fan pop
+/// audibly. Only used once, before the main loop starts, on a fan that's
+/// stopped. With no duration, the first cycle starts the fan instead.
+fn soft_start(target: f32, duration: Duration, fan: &mut Fan) -> Result<(), AppError> {
+    if target <= FAN_OFF || duration.is_zero() {
+        return Ok(());
+    }
+    fan.set_power(true);
+    let step_delay = duration / SOFT_START_STEPS;
+    for step in 1..=SOFT_START_STEPS {
+        let duty = target * step as f32 / SOFT_START_STEPS as f32;
+        fan.driver.set_duty(duty)?;
+        std::thread::sleep(step_delay);
+    }
+    fan.write_limit.wrote(target);
+    Ok(())
+}
+
+/// Returns the speed (between 0.0 and 1.0) that the first control cycle
+/// commands at `temp`, or without a reading if it's `None`, for the soft start
+/// to ramp to. It goes through the same pipeline as every cycle (the floor,
+/// comfort and so on, under the active profile), rather than the curve alone.
+fn start_duty(temp: Option<f32>, config: &Config) -> f32 {
+    let mut state = ControlState::default();
+    let temp = match temp {
+        Some(temp) => temp,
+        None => {
+            state.failed_reads = 1;
+            match outage_duty(config, &state) {
+                Some(duty) => return duty,
+                None => FALLBACK_TEMP,
+            }
+        }
+    };
+    decide_speed(temp, config, &mut state).unwrap_or(FAN_OFF)
+}
+
+/// Returns the fan speed as a percentage between 0.0 and 100.0.
+fn handle_fan_speed(
+    cpu_temp: f32,
+    config: &Config,
+    state: &mut ControlState,
+    fan: &mut Fan,
+) -> Result<f32, AppError> {
+    if let Some(duty) = fan.write_limit.due() {
+        set_duty(fan, duty)?;
+    }
+    // What's reported is what the fan is actually running at, not a speed
+    // that's still held back
+    let written = |fan: &Fan| fan.write_limit.written().unwrap_or(FAN_OFF) * 100.0;
+    let Some(fan_percentage) = decide_speed(cpu_temp, config, state) else {
+        return Ok(written(fan));
+    };
+    // Stopping, emergencies and the boost button are never held back
+    if fan.write_limit.ready()
+        || fan_percentage == FAN_OFF
+        || config.emergency(cpu_temp)
+        || state.boost_until.is_some()
+    {
+        set_duty(fan, fan_percentage)?;
+    } else {
+        debug!("Holding back the write of {fan_percentage:.3} until min_write_interval_ms is up");
+        fan.write_limit.hold(fan_percentage);
+    }
+    if state.commanded_duty != Some(fan_percentage) {
+        state.last_change = Some(Instant::now());
+    }
+    state.commanded_duty = Some(fan_percentage);
+    debug!("Commanded a speed of {fan_percentage:.3}");
+    Ok(written(fan))
+}
+
+/// Works out the fan speed (between 0.0 and 1.0) to command at the given
+/// temperature, or `None` if it's within the deadband of the commanded speed
+/// and should be left alone.
+fn decide_speed(cpu_temp: f32, config: &Config, state: &mut ControlState) -> Option<f32> {
+    state.record_temp(cpu_temp);
+    let since_decision = state
+        .last_decision
+        .replace(Instant::now())
+        .map(|at| at.elapsed().as_secs_f32());
+    let mut current_band = band(cpu_temp, config);
+    // While cooling, hold the hotter band (and its speed) until the
+    // temperature is `spin_down_lag` below its lower edge
+    let held = state.band.filter(|&previous| {
+        let lower_edge = match previous {
+            Band::Active => config.min_temp,
+            Band::Idle => config.off_temp,
+            _ => return false,
+        };
+        current_band < previous
+            && current_band != Band::ForcedOff
+            && cpu_temp >= lower_edge - config.spin_down_lag
+    });
+    if let Some(previous) = held {
+        current_band = previous;
+    }
+    match state.band {
+        Some(previous) if current_band > previous => {
+            info!("Entered the {current_band:?} band from {previous:?} at {cpu_temp:.2}°C")
+        }
+        Some(previous) if current_band < previous => {
+            info!("Returned to the {current_band:?} band from {previous:?} at {cpu_temp:.2}°C")
+        }
+        _ => {}
+    }
+    state.band = Some(current_band);
+    let mut fan_percentage = match (held, state.commanded_duty) {
+        (Some(_), Some(commanded)) => commanded,
+        _ => fan_speed(cpu_temp, config),
+    };
+    debug!(
+        "{cpu_temp:.2}°C is in the {current_band:?} band{}, giving a speed of {fan_percentage:.3}",
+        if held.is_some() { " while cooling" } else { "" }
+    );
+    // Ramp up to full speed over the grace period rather than jumping
+    // straight to it, unless the temperature is critical
+    match &config.max_temp_grace {
+        Some(grace) if current_band == Band::Max && cpu_temp < grace.critical_temp => {
+            let (since, from) = *state
+                .hot_since
+                .get_or_insert_with(|| (Instant::now(), state.commanded_duty.unwrap_or(FAN_LOW)));
+            let progress = (since.elapsed().as_secs_f32() / grace.secs).min(1.0);
+            fan_percentage = from + (FAN_MAX - from) * progress;
+            debug!(
+                "{:.0}% of the way through the grace period above max_temp, giving a speed of \
+                 {fan_percentage:.3}",
+                progress * 100.0
+            );
+        }
+        Some(_) if current_band == Band::Max => {}
+        _ => state.hot_since = None,
+    }
+    if let Some(comfort) = &config.comfort {
+        if current_band != Band::Max && current_band != Band::ForcedOff {
+            let controller = state.comfort.get_or_insert_with(|| {
+                Comfort::new(comfort, state.commanded_duty.unwrap_or(fan_percentage))
+            });
+            fan_percentage = controller.update(cpu_temp);
+            debug!(
+                "Staying under {:.2}°C at {cpu_temp:.2}°C gives a speed of {fan_percentage:.3}",
+                comfort.soft_ceiling
+            );
+        } else {
+            // Start over from whatever the fan was last commanded to, rather
+            // than catching up on the time spent outside of the controller
+            state.comfort = None;
+        }
+    }
+    if let (Some(target), Some(rpm)) = (&config.rpm_target, state.rpm) {
+        if current_band != Band::Max {
+            let pid = state
+                .rpm_pid
+                .get_or_insert_with(|| Pid::new(target.kp, target.ki, target.kd, FAN_OFF, FAN_MAX));
+            fan_percentage = pid.update(target.rpm - rpm);
+            debug!(
+                "Holding {:.0} RPM at {rpm:.0} RPM gives a speed of {fan_percentage:.3}",
+                target.rpm
+            );
+        }
+    }
+    // Only the curve region is adjusted, so that neither the off band nor the
+    // emergency full speed are affected
+    if config.accel_feed_forward && fan_percentage > FAN_OFF && fan_percentage < FAN_MAX {
+        if let Some(accel) = state.temp_acceleration() {
+            let adjusted = (fan_percentage + accel * config.accel_gain).clamp(FAN_LOW, FAN_MAX);
+            debug!(
+                "Temperature acceleration of {accel:.4}°C/s² adjusts the speed from \
+                 {fan_percentage:.3} to {adjusted:.3}"
+            );
+            fan_percentage = adjusted;
+        }
+    }
+    if let (Some(freq_boost), Some(freq)) = (&config.freq_boost, state.cpu_freq) {
+        if freq > freq_boost.above && fan_percentage > FAN_OFF && fan_percentage < FAN_MAX {
+            let boost = freq_boost.boost * (freq - freq_boost.above) / (1.0 - freq_boost.above);
+            let adjusted = (fan_percentage + boost).min(FAN_MAX);
+            debug!(
+                "CPU frequency at {:.0}% of its maximum adjusts the speed from \
+                 {fan_percentage:.3} to {adjusted:.3}",
+                freq * 100.0
+            );
+            fan_percentage = adjusted;
+        }
+    }
+    // Keep some airflow going while the room is hot, unless the fan has been
+    // explicitly forced off
+    if let (Some(ambient), Some(ambient_temp)) = (&config.ambient, state.ambient_temp) {
+        if ambient_temp > ambient.hot_above
+            && fan_percentage < ambient.min_speed
+            && current_band != Band::ForcedOff
+        {
+            debug!(
+                "Ambient temperature of {ambient_temp:.2}°C raises the speed from \
+                 {fan_percentage:.3} to {:.3}",
+                ambient.min_speed
+            );
+            fan_percentage = ambient.min_speed;
+        }
+    }
+    if let Some(until) = state.boost_until {
+        if Instant::now() < until {
+            debug!("The boost button is holding full speed");
+            fan_percentage = FAN_MAX;
+        } else {
+            info!("Boost ran out, returning to automatic control");
+            state.boost_until = None;
+        }
+    }
+    // Nothing above can take an always-on fan below its idle speed, including
+    // the RPM target and the feed-forward adjustments
+    if fan_percentage < config.speed_floor() {
+        debug!(
+            "Raising the speed from {fan_percentage:.3} to the always-on idle speed of {:.3}",
+            config.idle_speed
+        );
+        fan_percentage = config.speed_floor();
+    }
+    let emergency = config.emergency(cpu_temp);
+    // Full speed at max_temp is only exempt as an emergency, so that it's
+    // smoothed like any other speed without emergency_bypass_smoothing
+    let exempt = emergency || state.boost_until.is_some();
+    // Hold each new speed for the minimum dwell time, unless it's an
+    // emergency or the boost button
+    if let (Some(commanded), Some(changed)) = (state.commanded_duty, state.last_change) {
+        let dwelled = changed.elapsed().as_secs_f32();
+        if dwelled < config.min_dwell_secs && fan_percentage != commanded && !exempt {
+            debug!(
+                "Holding {commanded:.3} for another {:.1}s rather than changing to \
+                 {fan_percentage:.3}",
+                config.min_dwell_secs - dwelled
+            );
+            return None;
+        }
+    }
+    // Skip changes too small to matter, except for reaching fully off, an
+    // emergency, or the boost button
+    if let Some(commanded) = state.commanded_duty {
+        if (fan_percentage - commanded).abs() < config.duty_deadband
+            && fan_percentage != FAN_OFF
+            && !exempt
+        {
+            debug!(
+                "Speed of {fan_percentage:.3} is within the deadband of {commanded:.3}, keeping \
+                 {commanded:.3}"
+            );
+            return None;
+        }
+    }
+    if let (Some(commanded), Some(secs)) = (state.commanded_duty, since_decision) {
+        fan_percentage = ramp_limited(commanded, fan_percentage, secs, config, emergency);
+    }
+    Some(fan_percentage)
+}
+
+/// Returns how far towards `target` the speed can move from `commanded` in
+/// `secs`, under `ramp_up_rate` or `ramp_down_rate`. Rising in an emergency
+/// is never limited.
+fn ramp_limited(commanded: f32, target: f32, secs: f32, config: &Config, emergency: bool) -> f32 {
+    let rate = if target > commanded {
+        config.ramp_up_rate.filter(|_| !emergency)
+    } else {
+        config.ramp_down_rate
+    };
+    let Some(rate) = rate else {
+        return target;
+    };
+    let step = rate * secs;
+    let limited = target.clamp(commanded - step, commanded + step);
+    if limited != target {
+        debug!("Ramping from {commanded:.3} towards {target:.3} at {rate}/s gives {limited:.3}");
+    }
+    limited
+}
+
+/// Sets the duty cycle. The fan's power is switched on before a nonzero duty
+/// cycle is set, and off only after the duty cycle has been set to
+/// [`FAN_OFF`], so the fan never spins uncontrolled.
+fn set_duty(fan: &mut Fan, duty: f32) -> Result<(), AppError> {
+    if duty > FAN_OFF {
+        fan.set_power(true);
+    }
+    fan.driver.set_duty(duty)?;
+    fan.write_limit.wrote(duty);
+    if duty <= FAN_OFF {
+        fan.set_power(false);
+    }
+    Ok(())
+}
+
+/// Returns the duty cycle to run at while the CPU temperature can't be read,
+/// logging it, or `None` to carry on at [`FALLBACK_TEMP`] instead
+fn outage_duty(config: &Config, state: &ControlState) -> Option<f32> {
+    let failures = state.failed_reads;
+    let held = state.commanded_duty.filter(|_| {
+        config.sensor_failure == SensorFailure::Hold && failures <= config.sensor_failure_grace
+    });
+    match (config.sensor_failure, held) {
+        (SensorFailure::AssumeTemp, _) => None,
+        (_, Some(duty)) => {
+            warn!(
+                "Holding {:.1}% without a temperature reading ({failures} of {} in a row)",
+                duty * 100.0,
+                config.sensor_failure_grace
+            );
+            Some(duty)
+        }
+        (_, None) => {
+            warn!("Running at full speed without a temperature reading ({failures} in a row)");
+            Some(FAN_MAX)
+        }
+    }
+}
+
+/// Sets the duty cycle that `outage_duty` picked, unless the fan is already
+/// there
+fn command_outage_duty(fan: &mut Fan, state: &mut ControlState, duty: f32) -> Result<(), AppError> {
+    if state.commanded_duty != Some(duty) {
+        set_duty(fan, duty)?;
+        state.commanded_duty = Some(duty);
+        state.last_change = Some(Instant::now());
+    }
+    Ok(())
+}
+
+/// Changes the PWM frequency. The duty cycle is dropped to 0% while the period
+/// changes, so the fan never sees a pulse width meant for the old period, and
+/// is restored afterwards.
+fn set_frequency(fan: &mut Fan, frequency: f64, state: &ControlState) -> Result<(), AppError> {
+    fan.driver.set_frequency(frequency)?;
+    fan.frequency = frequency;
+    fan.driver.set_duty(state.commanded_duty.unwrap_or(FAN_OFF))
+}
+
+/// Leaves the fan in the state it should be in once the daemon exits, always
+/// in the same order: the final duty cycle is written, then the fan is given
+/// time to reach it and checked with `confirm` (which waits for and reads its
+/// speed, if it can be read), and only then is its output disabled and its
+/// power cut.
+fn shutdown(
+    fan: &mut Fan,
+    state: ShutdownFanState,
+    confirm: Option<&dyn Fn() -> f32>,
+) -> Result<(), AppError> {
+    let duty = match state {
+        ShutdownFanState::Keep => return Ok(()),
+        ShutdownFanState::Full => FAN_MAX,
+        ShutdownFanState::Duty(duty) => duty,
+        ShutdownFanState::Off => FAN_OFF,
+    };
+    if duty > FAN_OFF {
+        fan.set_power(true);
+    }
+    fan.driver.set_duty(duty)?;
+    if let Some(confirm) = confirm {
+        let rpm = confirm();
+        if duty > FAN_OFF && rpm <= 0.0 {
+            warn!(
+                "The fan reads 0 RPM at its shutdown speed of {:.0}%",
+                duty * 100.0
+            );
+        } else {
+            info!("The fan reads {rpm:.0} RPM at its shutdown speed");
+        }
+    }
+    if state == ShutdownFanState::Off {
+        fan.driver.disable()?;
+    }
+    if duty <= FAN_OFF {
+        fan.set_power(false);
+    }
+    Ok(())
+}
+
+/// Picks the poll interval from the thermal time constant, measured by
+/// running the fan at full speed for [`AUTO_POLL_DURATION`] and watching how
+/// the temperature settles, and then restores `duty`. Falls back to
+/// `poll_interval_secs` if the temperature doesn't settle measurably. Either
+/// way, the result is kept within `auto_poll`'s bounds.
+fn pick_poll_interval(
+    fan: &mut Fan,
+    config: &Config,
+    auto_poll: AutoPollConfig,
+    sensors: &mut Option<FailoverSource>,
+    duty: f32,
+    dry_run: bool,
+) -> Result<f32, AppError> {
+    let fallback = config
+        .poll_interval_secs
+        .clamp(auto_poll.min_secs, auto_poll.max_secs);
+    if dry_run {
+        info!("A dry run can't measure how the fan cools, polling every {fallback:.1}s");
+        return Ok(fallback);
+    }
+    info!(
+        "Running the fan at full speed for {}s to pick the poll interval",
+        AUTO_POLL_DURATION.as_secs()
+    );
+    set_duty(fan, FAN_MAX)?;
+    let mut temps = Vec::new();
+    for _ in 0..AUTO_POLL_DURATION.as_millis() / AUTO_POLL_SAMPLE.as_millis() {
+        std::thread::sleep(AUTO_POLL_SAMPLE);
+        temps.push(read_cpu_temp(config, sensors.as_mut(), false)?.unwrap_or(FALLBACK_TEMP));
+    }
+    set_duty(fan, duty)?;
+
+    let interval = match autopoll::time_constant(&temps, AUTO_POLL_SAMPLE.as_secs_f32()) {
+        Some(tau) => {
+            let interval = (tau * AUTO_POLL_FRACTION).clamp(auto_poll.min_secs, auto_poll.max_secs);
+            info!("Measured a thermal time constant of {tau:.1}s, polling every {interval:.1}s");
+            interval
+        }
+        None => {
+            warn!(
+                "The temperature didn't settle measurably at full speed, polling every \
+                 {fallback:.1}s"
+            );
+            fallback
+        }
+    };
+    Ok(interval)
+}
+
+/// Runs the fan at full speed for [`TACH_LOSS_TEST_DURATION`] and then restores
+/// `duty`, returning whether any tachometer edges were seen in the meantime
+/// (or, with a polled fan speed, whether it reads nonzero at the end). A fan
+/// reading 0 RPM that spins up at full speed has genuinely stalled, whereas
+/// no edges even at full speed points to a faulty tachometer or its wiring.
+fn tach_loss_test(
+    fan: &mut Fan,
+    duty: f32,
+    polled_rpm: Option<&PolledRpm>,
+) -> Result<bool, AppError> {
+    RPM.lock().unwrap().clear();
+    set_duty(fan, FAN_MAX)?;
+    std::thread::sleep(TACH_LOSS_TEST_DURATION);
+    let edges_seen = match polled_rpm {
+        Some(source) => source.read().is_ok_and(|rpm| rpm > 0.0),
+        None => std::mem::take(&mut *RPM.lock().unwrap())
+            .iter()
+            .any(|&rpm| rpm > 0.0),
+    };
+    set_duty(fan, duty)?;
+    Ok(edges_seen)
+}
+
+/// Runs the fan at full speed, and works out `pulses_per_rev` from how fast
+/// the tachometer pulses compared to the fan's rated RPM at full speed. The
+/// RPM samples assume `pulses_per_rev` pulses per revolution, so the ratio
+/// between the measured and rated speed scales it to the right value.
+fn calibrate_pulses(fan: &mut Fan, rated_rpm: f32, pulses_per_rev: f32) -> Result<(), AppError> {
+    println!(
+        "Running the fan at full speed for {}s to measure its tachometer...",
+        (CALIBRATION_SPIN_UP + CALIBRATION_DURATION).as_secs()
+    );
+    set_duty(fan, FAN_MAX)?;
+    std::thread::sleep(CALIBRATION_SPIN_UP);
+    RPM.lock().unwrap().clear();
+    std::thread::sleep(CALIBRATION_DURATION);
+    let mut samples = std::mem::take(&mut *RPM.lock().unwrap());
+    set_duty(fan, FAN_OFF)?;
+
+    let measured = rpm::reduce(&mut samples, RpmAveraging::Median);
+    if measured <= 0.0 {
+        println!("No tachometer pulses were seen, so pulses_per_rev can't be worked out");
+        return Ok(());
+    }
+    let inferred = pulses_per_rev * measured / rated_rpm;
+    println!(
+        "Measured {measured:.0} RPM assuming {pulses_per_rev} pulses per revolution, against a \
+         rated {rated_rpm:.0} RPM: that's {inferred:.2} pulses per revolution"
+    );
+    println!(
+        "Add this to the config:\npulses_per_rev = {:.0}",
+        inferred.round().max(1.0)
+    );
+    Ok(())
+}
+
+/// Returns the PWM channel to drive the fan from, which is the configured one
+/// if it exists on this board, or otherwise the board's channel on BCM GPIO
+/// 18
+fn pwm_channel(config: &Config, board: Board) -> Result<Channel, AppError> {
+    let channel = config
+        .pwm_channel
+        .unwrap_or_else(|| board.default_pwm_channel());
+    let pin = board
+        .pwm_pin(channel)
+        .ok_or(AppError::PwmChannel { board, channel })?;
+    if board == Board::Pi5 && pin != 18 && config.pwm_channel.is_some() {
+        warn!(
+            "PWM channel {channel} is on BCM GPIO {pin} on the {}, rather than BCM GPIO 18 as on \
+             earlier boards. Make sure the fan is wired to it and {} is in config.txt.",
+            board.name(),
+            board.pwm_overlay(channel)
+        );
+    }
+    Channel::try_from(channel).map_err(|_| AppError::PwmChannel { board, channel })
+}
+
+/// Turns the tachometer edges counted over each [`RPM_WINDOW`] into an RPM
+/// sample, forever
+fn count_rpm(edges_per_rev: f32) {
+    let mut window_start = Instant::now();
+    loop {
+        std::thread::sleep(RPM_WINDOW);
+        let edges = EDGES.swap(0, Ordering::Relaxed);
+        let now = Instant::now();
+        let window = (now - window_start).as_secs_f32();
+        window_start = now;
+        if window <= 0.0 {
+            continue;
+        }
+        RPM.lock()
+            .unwrap()
+            .push(edges as f32 / edges_per_rev / window * 60.0);
+    }
+}
+
+/// Controls the speed of a PWM fan based on the CPU temperature of a
+/// Raspberry Pi
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Path to the config file, or `-` to read it from stdin [default:
+    /// $RPI_FAN_CONFIG, or /etc/rpi-fan-control.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// The format of the config, rather than the one its extension implies
+    /// (or TOML, for stdin and any other extension)
+    #[arg(long, value_name = "FORMAT")]
+    config_format: Option<ConfigFormat>,
+    /// Exit after this many iterations of the control loop, or never if 0
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    max_iterations: u64,
+    /// Stop the fan, disable the PWM channel, and exit
+    #[arg(long)]
+    fan_off: bool,
+    /// Set the PWM channel to 0% and disable it, exporting it first if a
+    /// previous run left it unexported, and exit
+    #[arg(long, conflicts_with_all = ["fan_off", "validate"])]
+    reset_pwm: bool,
+    /// With `--reset-pwm`, unexport the channel as well
+    #[arg(long, requires = "reset_pwm")]
+    unexport: bool,
+    /// Follow a log file written with `log_file`, printing each new record
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["fan_off", "validate"])]
+    tail_log: Option<PathBuf>,
+    /// Run with the named profile for the whole session, ignoring the
+    /// schedule [default: the `default` profile, if there is one]
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// Run the fan at full speed, work out `pulses_per_rev` from its rated
+    /// speed at full speed, and exit
+    #[arg(
+        long,
+        value_name = "RATED_RPM",
+        value_parser = parse_positive,
+        conflicts_with_all = ["fan_off", "validate"]
+    )]
+    calibrate_pulses: Option<f32>,
+    /// Print the fan speed that the config gives over a range of
+    /// temperatures, as CSV or JSON, and exit
+    #[arg(long, value_name = "FORMAT", conflicts_with_all = ["fan_off", "validate"])]
+    export_curve: Option<CurveFormat>,
+    /// [°C] with `--export-curve`, the lowest temperature [default: 5 below
+    /// off_temp]
+    #[arg(
+        long,
+        value_name = "TEMP",
+        requires = "export_curve",
+        allow_negative_numbers = true
+    )]
+    curve_from: Option<f32>,
+    /// [°C] with `--export-curve`, the highest temperature [default: 5 above
+    /// max_temp]
+    #[arg(
+        long,
+        value_name = "TEMP",
+        requires = "export_curve",
+        allow_negative_numbers = true
+    )]
+    curve_to: Option<f32>,
+    /// [°C] with `--export-curve`, the step between temperatures
+    #[arg(
+        long,
+        value_name = "STEP",
+        default_value_t = 0.5,
+        value_parser = parse_positive,
+        requires = "export_curve"
+    )]
+    curve_step: f32,
+    /// [°C] print how the fan speed would be picked at each of these
+    /// temperatures, step by step, and exit
+    #[arg(
+        long,
+        value_name = "TEMP",
+        num_args = 1..,
+        allow_negative_numbers = true,
+        conflicts_with_all = ["fan_off", "validate", "export_curve"]
+    )]
+    explain: Vec<f32>,
+    /// Print an annotated example config describing every setting, and exit
+    #[arg(long, conflicts_with_all = ["fan_off", "validate"])]
+    schema: bool,
+    /// Check the config, temperature sensor, PWM channel, and GPIO pins, and
+    /// exit without changing the fan speed
+    #[arg(long, conflicts_with = "fan_off")]
+    validate: bool,
+}
+
+/// Parses a positive number from the command line
+fn parse_positive(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(value) if value > 0.0 => Ok(value),
+        Ok(value) => Err(format!("must be positive, got {value}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The formats that `--export-curve` prints the curve in
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum CurveFormat {
+    /// A `temp_c,duty` header, then one line per temperature
+    Csv,
+    /// An array of `{"temp_c": ..., "duty": ...}` objects
+    Json,
+}
+
+/// Formats the fan speed (as a percentage) at every `step` from `from` to
+/// `to`, evaluated exactly as the control loop evaluates it, before the
+/// adjustments that depend on the live state
+fn export_curve(config: &Config, format: CurveFormat, from: f32, to: f32, step: f32) -> String {
+    // Counted in steps rather than accumulated, so the error doesn't build up
+    let points = ((to - from) / step + 1e-3).floor() as usize + 1;
+    let rows = (0..points).map(|i| {
+        let temp = from + i as f32 * step;
+        let duty = fan_speed(temp, config) * 100.0;
+        (temp, duty)
+    });
+    match format {
+        CurveFormat::Csv => std::iter::once("temp_c,duty".to_string())
+            .chain(rows.map(|(temp, duty)| format!("{temp},{duty}")))
+            .map(|line| line + "\n")
+            .collect(),
+        CurveFormat::Json => {
+            let rows: Vec<String> = rows
+                .map(|(temp, duty)| format!("{{\"temp_c\":{temp},\"duty\":{duty}}}"))
+                .collect();
+            format!("[{}]\n", rows.join(","))
+        }
+    }
+}
+
+/// Describes, step by step, how the control loop would pick the fan speed at
+/// `temp` [°C] on its first cycle. The steps that depend on live readings or
+/// on the speeds before are named, but left out of the final duty cycle.
+fn explain(config: &Config, temp: f32) -> String {
+    let mut lines = vec![format!("At {temp:.2}°C:")];
+    let mut step = |line: String| lines.push(format!("  {line}"));
+    let current_band = band(temp, config);
+    step(match current_band {
+        Band::ForcedOff => format!(
+            "Band: ForcedOff, below force_off_below ({:.2}°C)",
+            config.force_off_below.unwrap_or_default()
+        ),
+        Band::Off => format!("Band: Off, below off_temp ({:.2}°C)", config.off_temp),
+        Band::Idle => format!(
+            "Band: Idle, between off_temp ({:.2}°C) and min_temp ({:.2}°C)",
+            config.off_temp, config.min_temp
+        ),
+        Band::Active => format!(
+            "Band: Active, between min_temp ({:.2}°C) and max_temp ({:.2}°C)",
+            config.min_temp, config.max_temp
+        ),
+        Band::Max => format!("Band: Max, at or above max_temp ({:.2}°C)", config.max_temp),
+    });
+    let raw = band_speed(temp, config);
+    step(match current_band {
+        Band::ForcedOff | Band::Off => format!("Speed: {:.2}% (off)", raw * 100.0),
+        Band::Idle => format!(
+            "Speed: {:.2}% (idle_band = {})",
+            raw * 100.0,
+            format!("{:?}", config.idle_band()).to_lowercase()
+        ),
+        Band::Active => format!(
+            "Speed: {:.2}% ({} curve)",
+            raw * 100.0,
+            match config.curve {
+                CurveMode::Sine => "sine",
+                CurveMode::Table(_) => "table",
+                CurveMode::Exponential { .. } => "exponential",
+            }
+        ),
+        Band::Max => format!("Speed: {:.2}% (full speed)", raw * 100.0),
+    });
+    if config.curve_lookup.is_some() && current_band != Band::Max {
+        step(format!(
+            "Precomputed table: {:.2}%",
+            fan_speed(temp, config) * 100.0
+        ));
+    }
+    if let Some(grace) = &config.max_temp_grace {
+        if current_band == Band::Max && temp < grace.critical_temp {
+            step(format!(
+                "Grace period: starts from the current speed ({:.2}% on the first cycle) and \
+                 reaches full speed after {}s, or at once from {:.2}°C",
+                FAN_LOW * 100.0,
+                grace.secs,
+                grace.critical_temp
+            ));
+        }
+    }
+    if let Some(comfort) = &config.comfort {
+        if current_band != Band::Max && current_band != Band::ForcedOff {
+            step(format!(
+                "Comfort mode: takes over from this speed, adjusting it to stay under {:.2}°C",
+                comfort.soft_ceiling
+            ));
+        }
+    }
+    if let Some(target) = &config.rpm_target {
+        step(format!(
+            "RPM target: not applied, the PID loop holds {:.0} RPM from the measured speed",
+            target.rpm
+        ));
+    }
+    if config.accel_feed_forward {
+        step("Feed-forward: not applied, it follows the temperature's acceleration".to_string());
+    }
+    if let Some(freq_boost) = &config.freq_boost {
+        step(format!(
+            "Frequency boost: not applied, up to +{:.2}% above {:.0}% of the CPU's maximum \
+             frequency",
+            freq_boost.boost * 100.0,
+            freq_boost.above * 100.0
+        ));
+    }
+    if let Some(ambient) = &config.ambient {
+        step(format!(
+            "Ambient: not applied, at least {:.2}% while the ambient sensor reads above {:.2}°C",
+            ambient.min_speed * 100.0,
+            ambient.hot_above
+        ));
+    }
+    if config.speed_floor() > raw {
+        step(format!(
+            "Always-on floor: raised to {:.2}%",
+            config.speed_floor() * 100.0
+        ));
+    }
+    if config.duty_deadband > 0.0 || config.min_dwell_secs > 0.0 {
+        step(
+            "Deadband and dwell time: not applied, they compare against the speed before"
+                .to_string(),
+        );
+    }
+    if config.ramp_up_rate.is_some() || config.ramp_down_rate.is_some() {
+        step("Ramp rates: not applied, they limit the change from the speed before".to_string());
+    }
+    let duty = decide_speed(temp, config, &mut ControlState::default()).unwrap_or(FAN_OFF);
+    step(format!("Duty cycle: {:.2}%", duty * 100.0));
+    lines.join("\n")
+}
+
+/// The number of tachometer edges seen since the current RPM window started
+static EDGES: AtomicU64 = AtomicU64::new(0);
+/// Whether the boost button has been pressed since the last control cycle
+static BOOST_PRESSED: AtomicBool = AtomicBool::new(false);
+static RPM: Lazy<Arc<Mutex<Vec<f32>>>> = Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+
+/// Returns the path that the config is read from: `--config` if it's given,
+/// then [`CONFIG_PATH_ENV`] if it's set and not empty, then
+/// [`DEFAULT_CONFIG_PATH`]. Along with it comes what gave the path, unless it's
+/// the default.
+fn config_path(flag: Option<PathBuf>, env: Option<OsString>) -> (PathBuf, Option<&'static str>) {
+    match (flag, env) {
+        (Some(path), _) => (path, Some("--config")),
+        (None, Some(path)) if !path.is_empty() => (path.into(), Some(CONFIG_PATH_ENV)),
+        _ => (DEFAULT_CONFIG_PATH.into(), None),
+    }
+}
+
+fn main() -> Result<(), AppError> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    if let Some(path) = &args.tail_log {
+        return record::tail(path);
+    }
+    if args.schema {
+        print!("{}", config::EXAMPLE);
+        return Ok(());
+    }
+
+    let (config_path, origin) = config_path(args.config.clone(), env::var_os(CONFIG_PATH_ENV));
+    info!(
+        "Config: {} (from {})",
+        config_path.display(),
+        origin.unwrap_or("the default")
+    );
+    let config_path = config_path.as_path();
+    let explicit_config = origin.is_some();
+    let config = Config::load(config_path, explicit_config, args.config_format);
+    if args.validate {
+        return validate::run(config);
+    }
+    let mut config = config?;
+    let forced_profile = args.profile.is_some();
+    let base_profile = match args.profile {
+        Some(name) if !config.profiles.contains_key(&name) => {
+            return Err(AppError::UnknownProfile {
+                name,
+                defined: config.profiles.keys().cloned().collect(),
+            });
+        }
+        Some(name) => Some(name),
+        None => config
+            .profiles
+            .contains_key(DEFAULT_PROFILE)
+            .then(|| DEFAULT_PROFILE.to_string()),
+    };
+    config.set_profile(base_profile.as_deref());
+    if let Some(format) = args.export_curve {
+        if config.thresholds == Thresholds::AutoTrip {
+            config.apply_trip_points();
+        }
+        let from = args.curve_from.unwrap_or(config.off_temp - 5.0);
+        let to = args.curve_to.unwrap_or(config.max_temp + 5.0);
+        if from > to {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("--curve-from ({from}) must not be above --curve-to ({to})"),
+                )
+                .exit();
+        }
+        print!(
+            "{}",
+            export_curve(&config, format, from, to, args.curve_step)
+        );
+        return Ok(());
+    }
+    if !args.explain.is_empty() {
+        if config.thresholds == Thresholds::AutoTrip {
+            config.apply_trip_points();
+        }
+        let explanations: Vec<String> = args
+            .explain
+            .iter()
+            .map(|&temp| explain(&config, temp))
+            .collect();
+        println!("{}", explanations.join("\n\n"));
+        return Ok(());
+    }
+    let labels = config.labels();
+    let sinks = sink::open_all(&config, &labels)?;
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))
+            .map_err(AppError::io("register the signal handlers"))?;
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload))
+        .map_err(AppError::io("register the signal handlers"))?;
+
+    let board = Board::detect();
+    let dry_run = !board::hardware_present();
+    if dry_run {
+        match config.on_non_pi {
+            OnNonPi::Exit => {
+                println!(
+                    "There's no temperature sensor or GPIO chip here, so this doesn't look like a \
+                     Raspberry Pi. Nothing to control. Set on_non_pi = \"dry-run\" to run the \
+                     control loop without any hardware."
+                );
+                return Ok(());
+            }
+            OnNonPi::DryRun => {
+                warn!(
+                    "There's no temperature sensor or GPIO chip here, so this doesn't look like a \
+                     Raspberry Pi. Running without touching any hardware; the temperature reads \
+                     {FALLBACK_TEMP}°C and the fan speed 0 RPM."
+                );
+                config.prepare_dry_run();
+            }
+        }
+    }
+    if config.thresholds == Thresholds::AutoTrip {
+        config.apply_trip_points();
+    }
+    // Held until main returns, which releases it
+    let _pwm_lock = config
+        .pwm_lock
+        .as_deref()
+        .map(PwmLock::acquire)
+        .transpose()?;
+
+    if args.fan_off {
+        driver::open(&config, board, dry_run)?.disable()?;
+        println!("Fan stopped and PWM output disabled");
+        return Ok(());
+    }
+
+    if args.reset_pwm {
+        match config.fan_driver {
+            _ if dry_run => println!("Dry run: there's no PWM channel to reset"),
+            FanBackend::Pwm => {
+                let channel = pwm_channel(&config, board)?;
+                driver::reset_pwm(channel, args.unexport)?;
+                println!(
+                    "{channel} set to 0% and disabled{}",
+                    if args.unexport {
+                        ", and unexported"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            FanBackend::I2c(_) | FanBackend::Hwmon(_) => {
+                driver::open(&config, board, dry_run)?.disable()?;
+                println!(
+                    "Fan stopped and output disabled. Only the Pi's own PWM channels are \
+                     exported, so there's nothing more to reset."
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    let fan_driver = driver::open(&config, board, dry_run)?;
+
+    let gpio = if dry_run {
+        None
+    } else {
+        Some(Gpio::new().map_err(AppError::gpio("access the GPIO peripheral"))?)
+    };
+    let power = match (config.power_pin, &gpio) {
+        (Some(pin), Some(gpio)) => {
+            let mut power = gpio
+                .get(pin)
+                .map_err(AppError::gpio("claim the fan power pin"))?
+                .into_output_low();
+            // Like the PWM channel, leave the fan powered as it was on exit
+            power.set_reset_on_drop(false);
+            Some(power)
+        }
+        _ => None,
+    };
+    let led = match (config.led_pin, &gpio) {
+        (Some(pin), Some(gpio)) => Some(StatusLed::start(
+            gpio.get(pin)
+                .map_err(AppError::gpio("claim the status LED pin"))?
+                .into_output_low(),
+        )),
+        _ => None,
+    };
+    let mut fan = Fan {
+        driver: fan_driver,
+        frequency: config.pwm_frequency(None),
+        power,
+        write_limit: WriteLimit::new(Duration::from_millis(config.min_write_interval_ms)),
+    };
+    let polled_rpm = match PolledRpm::open(&config) {
+        // A HAT that doesn't answer leaves the fan speed unknown, rather
+        // than leaving the fan uncontrolled
+        Err(e) if config.rpm_source == RpmSource::I2c => {
+            warn!("{e}, so the fan speed is unavailable. Everything that relies on it is off.");
+            config.without_rpm();
+            None
+        }
+        polled_rpm => polled_rpm?,
+    };
+    // The pin has to outlive the loop, or its interrupt is cleared
+    let tach_input = match (config.rpm_source, &gpio) {
+        (RpmSource::Tach, Some(gpio)) => {
+            let fan_speed_pin = gpio
+                .get(TACH_PIN)
+                .map_err(AppError::gpio("claim the tachometer pin"))?;
+            let mut fan_speed_pin = config.tach_pull.input(fan_speed_pin);
+            let edges_per_rev = config.pulses_per_rev * config.tach_edge.edges_per_pulse();
+            let mut filter =
+                EdgeFilter::new(Duration::from_micros(config.tach_min_edge_interval_us));
+            fan_speed_pin
+                .set_async_interrupt(config.tach_edge.trigger(), None, move |event| {
+                    if filter.accept(event.timestamp) {
+                        EDGES.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .map_err(AppError::gpio("set up the tachometer interrupt"))?;
+            std::thread::spawn(move || count_rpm(edges_per_rev));
+            Some(fan_speed_pin)
+        }
+        _ => None,
+    };
+
+    let mut rpm_available = polled_rpm.is_some() || tach_input.is_some();
+
+    if let Some(rated_rpm) = args.calibrate_pulses {
+        if tach_input.is_none() {
+            println!(
+                "Calibrating pulses_per_rev needs the tachometer on BCM GPIO {TACH_PIN}, which \
+                 isn't read with rpm_source = \"hwmon\" or \"i2c\", or in a dry run"
+            );
+            return Ok(());
+        }
+        return calibrate_pulses(&mut fan, rated_rpm, config.pulses_per_rev);
+    }
+
+    // Like the tachometer pin, it has to outlive the loop
+    let _boost_button = match (config.boost_button_pin, &gpio) {
+        (Some(pin), Some(gpio)) => {
+            let mut button = gpio
+                .get(pin)
+                .map_err(AppError::gpio("claim the boost button pin"))?
+                .into_input_pullup();
+            button
+                .set_async_interrupt(Trigger::FallingEdge, Some(BOOST_DEBOUNCE), |_| {
+                    BOOST_PRESSED.store(true, Ordering::Relaxed);
+                })
+                .map_err(AppError::gpio("set up the boost button interrupt"))?;
+            Some(button)
+        }
+        _ => None,
+    };
+
+    let mut zones = match &gpio {
+        Some(gpio) => Zone::open_all(&config, board, gpio)?,
+        None if !config.zones.is_empty() => {
+            warn!("Skipping the zones in a dry run");
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    let tachometer = match (&polled_rpm, config.rpm_source) {
+        (Some(source), _) => source.describe(),
+        (None, RpmSource::Tach) => format!("BCM GPIO {TACH_PIN}"),
+        (None, RpmSource::None) => "none configured, so the fan speed isn't read".to_string(),
+        (None, _) => "unavailable".to_string(),
+    };
+    log_system_info(fan.driver.as_ref(), &tachometer);
+    for zone in &zones {
+        info!("Zone {}: {}", zone.name, zone.fan.driver.describe());
+    }
+
+    if config.startup_delay_secs > 0.0 {
+        info!(
+            "Waiting {}s for the temperature to settle before starting the fan",
+            config.startup_delay_secs
+        );
+        std::thread::sleep(Duration::from_secs_f32(config.startup_delay_secs));
+    }
+
+    if config.detect_absent_fan
+        && rpm_available
+        && !tach_loss_test(&mut fan, FAN_OFF, polled_rpm.as_ref())?
+    {
+        warn!(
+            "No tachometer edges at full speed, so {} looks absent or has no tachometer. Its \
+             speed won't be read or alarmed on, but it's still driven.",
+            config.fan_name
+        );
+        config.without_rpm();
+        rpm_available = false;
+    }
+
+    let mut sensors = cpu_sensors(&config);
+    let start_temp = read_cpu_temp(&config, sensors.as_mut(), dry_run)?;
+    let start_speed = match forced_profile {
+        true => start_duty(start_temp, &config),
+        false => {
+            // Ramp to the profile that the first cycle switches to
+            let mut scheduled = config.clone();
+            scheduled.set_profile(
+                profile::scheduled(&config.schedule, TimeOfDay::now()).or(base_profile.as_deref()),
+            );
+            start_duty(start_temp, &scheduled)
+        }
+    };
+    if let Some(led) = &led {
+        led.set(if start_speed > FAN_OFF {
+            LedState::SpinningUp
+        } else {
+            LedState::Off
+        });
+    }
+    soft_start(
+        start_speed,
+        Duration::from_secs_f32(config.soft_start_secs),
+        &mut fan,
+    )?;
+    if let Some(auto_poll) = config.auto_poll {
+        config.poll_interval_secs = pick_poll_interval(
+            &mut fan,
+            &config,
+            auto_poll,
+            &mut sensors,
+            start_speed,
+            dry_run,
+        )?;
+    }
+
+    let mut control = ControlState::default();
+    let mut rpm_band = RpmBandMonitor::new(
+        config.rpm_warn_low,
+        config.rpm_warn_high,
+        config.rpm_warn_cycles,
+    );
+    let mut off_confirmation = config.off_confirmation.map(OffConfirmation::new);
+    let mut critical_stall = config
+        .critical_stall
+        .clone()
+        .map(|stall| CriticalStall::new(stall, config.max_temp));
+    let throttle_temp = config.headroom.map(|headroom| {
+        let temp = cpu_throttle_temp(headroom.throttle_temp);
+        info!("Reporting the headroom below a throttle temperature of {temp:.1}°C");
+        temp
+    });
+    let mut gpu = config.gpu.as_ref().map(|gpu| {
+        let sensor = SysfsSource {
+            path: gpu.path.clone(),
+            divisor: gpu.scale_divisor,
+            calibration: gpu.calibration,
+        };
+        (sensor, cpu_throttle_temp(gpu.cpu_throttle_temp))
+    });
+    let mut control_source = ControlSource::Cpu;
+
+    let mut learner = config
+        .learning
+        .then(|| CurveLearner::new(config.learning_target_max));
+
+    let mut ambient = config.ambient.as_ref().map(|ambient| SysfsSource {
+        path: ambient.path.clone(),
+        divisor: ambient.scale_divisor,
+        calibration: ambient.calibration,
+    });
+
+    let mut control_fifo = match &config.control_fifo {
+        Some(path) => Some(ControlFifo::open(path).map_err(AppError::io(format!(
+            "open the control FIFO at {}",
+            path.display()
+        )))?),
+        None => None,
+    };
+
+    let mut summary = RunSummary::new();
+    match &base_profile {
+        Some(name) if forced_profile => info!("Running with the {name} profile"),
+        Some(name) => info!("Starting with the {name} profile"),
+        None => info!("Starting without a profile"),
+    }
+    let mut active_profile = base_profile.clone();
+    let mut paused = false;
+
+    let watchdog = config
+        .watchdog
+        .map(|watchdog| {
+            fan.driver
+                .reopen()
+                .map(|driver| Watchdog::start(watchdog, driver))
+        })
+        .transpose()?;
+
+    while !shutdown.load(Ordering::Relaxed) {
+        // Whether the zones have to take up the main config again
+        let mut config_changed = false;
+        for command in control_fifo.iter_mut().flat_map(ControlFifo::poll) {
+            match command.and_then(|(key, value)| {
+                config
+                    .set_tunable(&key, &value)
+                    .map(|_| format!("{key} = {value}"))
+            }) {
+                Ok(change) => {
+                    info!("Control FIFO set {change}");
+                    config_changed = true;
+                }
+                Err(e) => warn!("Ignoring control FIFO command: {e}"),
+            }
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            reload_config(
+                &mut config,
+                config_path,
+                explicit_config,
+                args.config_format,
+            );
+            config_changed = true;
+        }
+        let scheduled = match forced_profile {
+            true => base_profile.as_deref(),
+            false => {
+                profile::scheduled(&config.schedule, TimeOfDay::now()).or(base_profile.as_deref())
+            }
+        };
+        if scheduled != active_profile.as_deref() {
+            match scheduled {
+                Some(name) => info!("Switching to the {name} profile"),
+                None => info!("Leaving the {} profile", active_profile.unwrap_or_default()),
+            }
+            active_profile = scheduled.map(str::to_string);
+            config.set_profile(active_profile.as_deref());
+            config_changed = true;
+        }
+        if config_changed {
+            for zone in &mut zones {
+                zone.reconfigure(&config);
+            }
+        }
+        let frequency = config.pwm_frequency(active_profile.as_deref());
+        if frequency != fan.frequency {
+            info!(
+                "Changing the PWM frequency from {} Hz to {frequency} Hz",
+                fan.frequency
+            );
+            set_frequency(&mut fan, frequency, &control)?;
+        }
+        if BOOST_PRESSED.swap(false, Ordering::Relaxed) {
+            info!(
+                "Boost button pressed, running at full speed for {}s",
+                config.boost_secs
+            );
+            control.boost_until = Some(Instant::now() + Duration::from_secs_f32(config.boost_secs));
+        }
+        let reading = match read_cpu_temp(&config, sensors.as_mut(), dry_run)? {
+            Some(temp) => {
+                control.failed_reads = 0;
+                Ok(temp)
+            }
+            None => {
+                control.failed_reads += 1;
+                match outage_duty(&config, &control) {
+                    None => {
+                        warn!("Assuming {FALLBACK_TEMP}°C for this cycle");
+                        Ok(FALLBACK_TEMP)
+                    }
+                    Some(duty) => Err(duty),
                 }
-                ErrorKind::NotFound => {
-                    eprintln!(
-                        "You may have forgotten to enable the selected PWM channel. The \
-                         configuration options to enable either of the two PWM channels are \
-                         listed below.\n\n{}",
-                        PWM_PERMISSION_ERROR
+            }
+        };
+        // Without a temperature to go on, the speed isn't worked out this
+        // cycle, but the rest of it still runs and reports the reading as
+        // missing
+        let (cpu_temp, fan_percentage) = match reading {
+            Err(duty) => {
+                command_outage_duty(&mut fan, &mut control, duty)?;
+                (None, duty * 100.0)
+            }
+            Ok(mut cpu_temp) => {
+                if let Some(max_slew) = config.max_temp_slew_per_cycle {
+                    cpu_temp =
+                        control.reject_glitch(cpu_temp, max_slew, config.max_temp_slew_rejections);
+                }
+                control.ambient_temp = ambient.as_mut().and_then(|source| {
+                    source
+                        .read()
+                        .map_err(|e| warn!("Failed to read the ambient temperature: {e}"))
+                        .ok()
+                });
+                if config.freq_boost.is_some() {
+                    control.cpu_freq = cpufreq::fraction_of_max()
+                        .map_err(|e| warn!("Failed to read the CPU frequency: {e}"))
+                        .ok();
+                }
+                let fused_temp = match (&config.gpu, &mut gpu) {
+                    (Some(gpu_config), Some((sensor, cpu_throttle))) => match sensor.read() {
+                        Ok(gpu_temp) => {
+                            let (temp, source) = temperature::fuse(
+                                gpu_config.fusion,
+                                (cpu_temp, *cpu_throttle),
+                                (gpu_temp, gpu_config.throttle_temp),
+                            );
+                            if source != control_source {
+                                info!(
+                                    "Following the {source} temperature, with the CPU at \
+                                     {cpu_temp:.2}°C and the GPU at {gpu_temp:.2}°C"
+                                );
+                                control_source = source;
+                            }
+                            temp
+                        }
+                        Err(e) => {
+                            warn!("Failed to read the GPU temperature, following the CPU's: {e}");
+                            cpu_temp
+                        }
+                    },
+                    _ => cpu_temp,
+                };
+                let control_temp = match config.smoothing_halflife_secs {
+                    // The average is kept up to date either way, for when the
+                    // emergency is over
+                    Some(halflife) => match control.smooth_temp(fused_temp, halflife) {
+                        _ if config.emergency(fused_temp) => fused_temp,
+                        smoothed => smoothed,
+                    },
+                    None => fused_temp,
+                };
+                if let Some(path) = &config.pause_file {
+                    if path.exists() != paused {
+                        paused = !paused;
+                        let path = path.display();
+                        match (paused, config.pause_speed) {
+                            (true, Some(speed)) => {
+                                info!(
+                                    "Pausing automatic control at {:.1}% while {path} exists",
+                                    speed * 100.0
+                                );
+                                set_duty(&mut fan, speed)?;
+                                control.commanded_duty = Some(speed);
+                                control.last_change = Some(Instant::now());
+                            }
+                            (true, None) => info!("Pausing automatic control while {path} exists"),
+                            (false, _) => info!("Resuming automatic control, since {path} is gone"),
+                        }
+                    }
+                }
+                // Reaching max_temp still takes over, so a forgotten pause file can't
+                // cook the CPU
+                let fan_percentage = if paused && control_temp < config.max_temp {
+                    control.commanded_duty.unwrap_or(FAN_OFF) * 100.0
+                } else {
+                    handle_fan_speed(control_temp, &config, &mut control, &mut fan)?
+                };
+                (Some(cpu_temp), fan_percentage)
+            }
+        };
+        let avg_rpm = match &polled_rpm {
+            Some(source) => source.read().unwrap_or_else(|e| {
+                warn!("Failed to read the fan speed: {e}");
+                control.rpm.unwrap_or(0.0)
+            }),
+            None => {
+                let mut rpm_samples = std::mem::take(&mut *RPM.lock().unwrap());
+                // A cycle shorter than the RPM window may not have finished
+                // one, in which case the last reading still stands
+                match control.rpm {
+                    Some(rpm) if rpm_samples.is_empty() => rpm,
+                    _ => rpm::reduce(&mut rpm_samples, config.rpm_averaging),
+                }
+            }
+        };
+        control.rpm = Some(avg_rpm);
+        rpm_band.check(avg_rpm, fan_percentage);
+        if let Some(off_confirmation) = &mut off_confirmation {
+            off_confirmation.check(avg_rpm, fan_percentage);
+        }
+        if let (Some(critical_stall), Some(cpu_temp)) = (&mut critical_stall, cpu_temp) {
+            critical_stall.check(cpu_temp, avg_rpm, fan_percentage);
+        }
+        if !rpm_available || avg_rpm > 0.0 || fan_percentage <= 0.0 {
+            control.zero_rpm_cycles = 0;
+        } else {
+            control.zero_rpm_cycles += 1;
+            if config.tach_loss_test && control.zero_rpm_cycles == TACH_LOSS_CYCLES {
+                if tach_loss_test(&mut fan, fan_percentage / 100.0, polled_rpm.as_ref())? {
+                    warn!(
+                        "Fan stalled at {fan_percentage:.2}%, but spins up at full speed. It may \
+                         need a higher minimum speed."
+                    );
+                } else {
+                    warn!(
+                        "No tachometer edges even at full speed. The tachometer or its wiring is \
+                         likely faulty, or the fan is dead."
                     );
-                    std::process::exit(1);
                 }
-                _ => panic!("Error: {e}"),
-            },
+            }
+        }
+        if let Some(led) = &led {
+            led.set(if fan_percentage <= 0.0 {
+                LedState::Off
+            } else if control.zero_rpm_cycles >= TACH_LOSS_CYCLES || rpm_band.alarmed() {
+                LedState::Alarm
+            } else if control.zero_rpm_cycles > 0 {
+                LedState::SpinningUp
+            } else {
+                LedState::Running
+            });
+        }
+        let mut headroom = throttle_temp
+            .zip(cpu_temp)
+            .map(|(throttle_temp, cpu_temp)| throttle_temp - cpu_temp);
+        // From here on the readings are only printed and sent, so they're
+        // made safe for parsers first
+        let sentinel = config.output_sentinel();
+        let mut record = Record::now(
+            &labels.fan,
+            cpu_temp.unwrap_or(f32::NAN),
+            fan_percentage,
+            config.reported_rpm(avg_rpm),
+        );
+        if let Some(sentinel) = sentinel {
+            record = record.finite(sentinel);
+            headroom = headroom.map(|headroom| record::finite(headroom, sentinel));
+        }
+        let pwm_floor = config.pwm_floor(fan_percentage);
+        let mut sensor_fault = control.glitching() || cpu_temp.is_none();
+        let mut zone_readings = Vec::with_capacity(zones.len());
+        for zone in &mut zones {
+            let mut reading = zone.step()?;
+            sensor_fault |= reading.temp.is_none();
+            let mut temp = reading.temp.unwrap_or(f32::NAN);
+            reading.rpm = reading.rpm.map(|rpm| config.reported_rpm(rpm));
+            if let Some(sentinel) = sentinel {
+                temp = record::finite(temp, sentinel);
+                reading.duty = record::finite(reading.duty, sentinel);
+                reading.rpm = reading.rpm.map(|rpm| record::finite(rpm, sentinel));
+            }
+            zone_readings.push((config::label(&zone.name), temp, reading));
+        }
+        let health = Health::assess(&Conditions {
+            cpu_temp: cpu_temp.unwrap_or(f32::NAN),
+            warn_temp: config.warn_temp(),
+            max_temp: config.max_temp,
+            // A dry run never reads the fan speed, so it can't tell a stall
+            stalled: rpm_available && control.zero_rpm_cycles >= TACH_LOSS_CYCLES,
+            sensor_fault,
+            rpm_alarm: rpm_band.alarmed(),
+        });
+        if let (Some(learner), Some(cpu_temp)) = (&mut learner, cpu_temp) {
+            learner.observe(cpu_temp, fan_percentage / 100.0);
+        }
+        summary.record(cpu_temp.map(|_| record.cpu_temp), record.duty, record.rpm);
+        let mut snapshots = vec![StatusSnapshot {
+            fan: labels.fan.clone(),
+            timestamp: record.timestamp,
+            cpu_temp: record.cpu_temp,
+            duty: record.duty,
+            rpm: record.rpm,
+            no_tach: !rpm_available,
+            rpm_alerts: rpm_band.alerts,
+            iterations: summary.iterations,
+            boost: control.boost_until.is_some(),
+            headroom,
+            pwm_floor,
+            no_reading: cpu_temp.is_none(),
+            health,
+        }];
+        for (fan, temp, reading) in zone_readings {
+            snapshots.push(StatusSnapshot {
+                fan,
+                timestamp: record.timestamp,
+                cpu_temp: temp,
+                duty: reading.duty,
+                rpm: reading.rpm.unwrap_or_else(|| sentinel.unwrap_or(f32::NAN)),
+                no_tach: reading.rpm.is_none(),
+                rpm_alerts: 0,
+                iterations: summary.iterations,
+                boost: false,
+                headroom: None,
+                pwm_floor: config.pwm_floor(reading.duty),
+                no_reading: reading.temp.is_none(),
+                health,
+            });
+        }
+        for sink in &sinks {
+            sink.emit(&snapshots);
+        }
+        if watchdog.as_ref().is_some_and(Watchdog::beat) {
+            // Take the fan back from the fail-safe speed
+            set_duty(&mut fan, control.commanded_duty.unwrap_or(FAN_OFF))?;
+        }
+        if summary.iterations == args.max_iterations {
+            break;
+        }
+        std::thread::sleep(Duration::from_secs_f32(config.poll_interval_secs));
+    }
+
+    // Whether the loop ended on a signal or after --max-iterations, nothing
+    // else may touch the fans from here on
+    if let Some(watchdog) = watchdog {
+        watchdog.stop();
+    }
+    let read_rpm = || {
+        RPM.lock().unwrap().clear();
+        std::thread::sleep(SHUTDOWN_SETTLE);
+        match &polled_rpm {
+            Some(source) => source.read().unwrap_or(0.0),
+            None => rpm::reduce(&mut RPM.lock().unwrap(), config.rpm_averaging),
+        }
+    };
+    let confirm = rpm_available.then_some(&read_rpm as &dyn Fn() -> f32);
+    crate::shutdown(&mut fan, config.shutdown_fan_state, confirm)?;
+    for zone in &mut zones {
+        crate::shutdown(&mut zone.fan, config.shutdown_fan_state, None)?;
+    }
+    match config.shutdown_fan_state {
+        ShutdownFanState::Keep => {}
+        ShutdownFanState::Full => info!("Left the fans at full speed for shutdown"),
+        ShutdownFanState::Duty(duty) => {
+            info!("Left the fans at {:.0}% for shutdown", duty * 100.0)
+        }
+        ShutdownFanState::Off => info!("Stopped the fans and disabled their outputs for shutdown"),
+    }
+
+    println!("{summary}");
+
+    if let Some(path) = &config.status_file {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove the status file: {e}");
+        }
+    }
+
+    if let Some(learner) = &learner {
+        match learner.suggestion(config.max_temp) {
+            Some(table) => println!(
+                "Suggested curve from {} steady readings:\n{}",
+                learner.observations(),
+                learning::format_curve_table(&table)
+            ),
+            None => println!("Not enough steady readings to suggest a curve"),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for the PWM channel, keeping the last duty cycle written
+    #[derive(Default)]
+    struct MockFan {
+        duty: f32,
+    }
+
+    /// A driver that logs each call made to it, in order
+    #[derive(Default, Clone)]
+    struct RecordingDriver {
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FanDriver for RecordingDriver {
+        fn set_duty(&mut self, duty: f32) -> Result<(), AppError> {
+            self.calls.lock().unwrap().push(format!("duty {duty}"));
+            Ok(())
+        }
+
+        fn set_frequency(&mut self, frequency: f64) -> Result<(), AppError> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("frequency {frequency}"));
+            Ok(())
+        }
+
+        fn disable(&mut self) -> Result<(), AppError> {
+            self.calls.lock().unwrap().push("disable".to_string());
+            Ok(())
+        }
+
+        fn describe(&self) -> String {
+            "recording".to_string()
+        }
+
+        fn reopen(&self) -> Result<Box<dyn FanDriver + Send>, AppError> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    /// A fan driven by a [`RecordingDriver`], along with the calls made to it
+    fn recording_fan(write_limit: WriteLimit) -> (Fan, Arc<Mutex<Vec<String>>>) {
+        let driver = RecordingDriver::default();
+        let calls = Arc::clone(&driver.calls);
+        let fan = Fan {
+            driver: Box::new(driver),
+            frequency: PWM_FREQUENCY,
+            power: None,
+            write_limit,
         };
+        (fan, calls)
+    }
+
+    /// A first-order (RC) thermal model of the CPU: a constant heat input
+    /// warms it, and it loses heat to the ambient air in proportion to the
+    /// temperature difference, more so the harder the fan blows.
+    struct ThermalModel {
+        /// [°C]
+        temp: f32,
+        /// [°C]
+        ambient: f32,
+        /// [W]
+        heat: f32,
+        /// [J/°C]
+        capacity: f32,
+        /// [W/°C] the heat lost with the fan off
+        passive_conductance: f32,
+        /// [W/°C] the extra heat lost with the fan at full speed
+        fan_conductance: f32,
+    }
+
+    impl ThermalModel {
+        fn step(&mut self, duty: f32, dt: f32) {
+            let conductance = self.passive_conductance + self.fan_conductance * duty;
+            let loss = conductance * (self.temp - self.ambient);
+            self.temp += (self.heat - loss) / self.capacity * dt;
+        }
+    }
 
-    let gpio = Gpio::new().unwrap();
-    let mut fan_speed_pin = match gpio.get(24) {
-        Ok(p) => p,
-        Err(e) => match e {
-            rppal::gpio::Error::Io(e) => {
-                eprintln!("Error: {e}");
-                std::process::exit(1);
+    /// Runs the controller against the thermal model, returning the
+    /// temperatures over the last `tail` of `iterations` cycles
+    fn simulate(config: &Config, iterations: usize, tail: usize) -> Vec<f32> {
+        // Settles at 90°C with the fan off, and 40°C at full speed
+        let mut model = ThermalModel {
+            temp: 30.0,
+            ambient: 25.0,
+            heat: 6.5,
+            capacity: 20.0,
+            passive_conductance: 0.1,
+            fan_conductance: 0.1 * 65.0 / 15.0 - 0.1,
+        };
+        let mut state = ControlState::default();
+        let mut fan = MockFan::default();
+        let mut temps = Vec::new();
+        for i in 0..iterations {
+            if let Some(duty) = decide_speed(model.temp, config, &mut state) {
+                fan.duty = duty;
+                state.commanded_duty = Some(duty);
             }
-            rppal::gpio::Error::PermissionDenied(e) => {
-                eprintln!("Error: {e}\n\n{GPIO_PERMISSION_ERROR}");
-                std::process::exit(1);
+            model.step(fan.duty, config.poll_interval_secs);
+            if i >= iterations - tail {
+                temps.push(model.temp);
             }
-            _ => panic!("Error: {e}"),
-        },
+        }
+        temps
     }
-    .into_input_pullup();
 
-    fan_speed_pin
-        .set_async_interrupt(Trigger::FallingEdge, |_| {
-            let mut time_diff = TIME_DIFF.lock().unwrap();
-            let dt = Instant::now() - time_diff.clone();
+    fn assert_settles(config: &Config) {
+        let temps = simulate(config, 2_000, 200);
+        let min = temps.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = temps.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        assert!(
+            min >= config.min_temp && max <= config.max_temp,
+            "temperature left the {}..{}°C band, ranging over {min}..{max}°C",
+            config.min_temp,
+            config.max_temp
+        );
+        assert!(
+            max - min < 2.0,
+            "temperature still swings over {min}..{max}°C after settling"
+        );
+    }
 
-            if dt < Duration::from_millis(5) {
-                return;
-            }
+    #[test]
+    fn precomputed_curve_matches_evaluated() {
+        let mut config = Config {
+            precompute_curve: true,
+            ..Config::default()
+        };
+        config.bake_curve();
+        let mut temp = config.off_temp - 5.0;
+        while temp < config.max_temp + 5.0 {
+            let (looked_up, evaluated) =
+                (fan_speed(temp, &config), evaluate_fan_speed(temp, &config));
+            assert!(
+                (looked_up - evaluated).abs() < 0.01,
+                "{looked_up} was looked up at {temp}°C, but the curve gives {evaluated}"
+            );
+            temp += 0.037;
+        }
+    }
 
-            let freq: f32 = 1.0 / dt.as_secs_f32();
-            let rpm = (freq / FAN_PULSE) * 60.0;
-            let mut rpm_guard = RPM.lock().unwrap();
-            (*rpm_guard).push(rpm);
-            *time_diff = Instant::now();
-        })
-        .unwrap();
+    #[test]
+    fn profiles_shape_the_idle_band() {
+        let profile = |idle_band| profile::Profile {
+            idle_band: Some(idle_band),
+            ..Default::default()
+        };
+        let mut config = Config {
+            idle_band: IdleBand::Ramp,
+            profiles: [
+                ("quiet".to_string(), profile(IdleBand::Off)),
+                ("aggressive".to_string(), profile(IdleBand::Step)),
+            ]
+            .into(),
+            ..Config::default()
+        };
+        let halfway = (config.off_temp + config.min_temp) / 2.0;
+        assert!((fan_speed(halfway, &config) - FAN_LOW / 2.0).abs() < 1e-6);
+        config.set_profile(Some("quiet"));
+        assert_eq!(fan_speed(halfway, &config), FAN_OFF);
+        config.set_profile(Some("aggressive"));
+        assert_eq!(fan_speed(halfway, &config), FAN_LOW);
+        // The curve above min_temp is the same whatever the idle band
+        assert_eq!(
+            fan_speed(config.min_temp + 1.0, &config),
+            evaluate_fan_speed(config.min_temp + 1.0, &Config::default())
+        );
+    }
 
-    loop {
-        let cpu_temp = get_cpu_temp();
-        let fan_percentage =
-            handle_fan_speed(cpu_temp, &mut pwm_pin).expect("Error setting fan speed");
-        let mut rpm_guard = RPM.lock().unwrap();
-        let avg_rpm =
-            rpm_guard.drain(..).reduce(|acc, x| acc + x).unwrap_or(0.0) / rpm_guard.len() as f32;
-        println!(
-            "CPU Temp: {cpu_temp:.2}°C, Fan Percentage: {fan_percentage:.2}%, Fan Speed: \
-             {avg_rpm:.2} RPM",
-        );
-        *rpm_guard = Vec::new();
-        std::thread::sleep(Duration::from_secs(5));
+    #[test]
+    fn held_back_speeds_are_not_reported() {
+        let (mut fan, calls) = recording_fan(WriteLimit::new(Duration::from_secs(3600)));
+        let config = Config::default();
+        let mut state = ControlState::default();
+        let first = handle_fan_speed(50.0, &config, &mut state, &mut fan).unwrap();
+        let second = handle_fan_speed(65.0, &config, &mut state, &mut fan).unwrap();
+        assert!(state.commanded_duty.unwrap() * 100.0 > first);
+        assert_eq!(second, first);
+        assert_eq!(*calls.lock().unwrap(), [format!("duty {}", first / 100.0)]);
+    }
+
+    #[test]
+    fn soft_start_leaves_a_stopped_fan_alone() {
+        let (mut fan, calls) = recording_fan(WriteLimit::default());
+        soft_start(FAN_OFF, Duration::from_secs(2), &mut fan).unwrap();
+        soft_start(0.5, Duration::ZERO, &mut fan).unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+
+        // The speed without a reading follows sensor_failure, as it does in the
+        // loop, and the always-on floor applies below off_temp
+        let mut config = Config {
+            sensor_failure: SensorFailure::Full,
+            always_on: true,
+            ..Config::default()
+        };
+        assert_eq!(start_duty(None, &config), FAN_MAX);
+        assert_eq!(
+            start_duty(Some(config.off_temp - 5.0), &config),
+            config.idle_speed
+        );
+        config.sensor_failure = SensorFailure::AssumeTemp;
+        assert_eq!(
+            start_duty(None, &config),
+            decide_speed(FALLBACK_TEMP, &config, &mut ControlState::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn shutdown_confirms_before_disabling() {
+        let (mut fan, calls) = recording_fan(WriteLimit::default());
+        let confirm = || {
+            calls.lock().unwrap().push("confirm".to_string());
+            0.0
+        };
+        shutdown(&mut fan, ShutdownFanState::Off, Some(&confirm)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["duty 0", "confirm", "disable"]);
+
+        // Full speed unless it's configured otherwise
+        calls.lock().unwrap().clear();
+        shutdown(&mut fan, ShutdownFanState::default(), Some(&confirm)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["duty 1", "confirm"]);
+
+        calls.lock().unwrap().clear();
+        shutdown(&mut fan, ShutdownFanState::Duty(0.4), Some(&confirm)).unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["duty 0.4", "confirm"]);
+
+        calls.lock().unwrap().clear();
+        shutdown(&mut fan, ShutdownFanState::Keep, Some(&confirm)).unwrap();
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hardware_floor_is_written_but_not_reported() {
+        let driver = RecordingDriver::default();
+        let calls = Arc::clone(&driver.calls);
+        let mut driver = driver::with_min_duty(Box::new(driver), 0.05);
+        driver.set_duty(FAN_OFF).unwrap();
+        driver.set_duty(0.5).unwrap();
+        driver.disable().unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["duty 0.05", "duty 0.5", "disable"]);
+
+        let config = Config {
+            hardware_min_duty: 0.05,
+            ..Config::default()
+        };
+        assert_eq!(config.pwm_floor(0.0), Some(5.0));
+        assert_eq!(config.pwm_floor(50.0), None);
+    }
+
+    #[test]
+    fn exported_curve_follows_the_loop() {
+        let config = Config::default();
+        let csv = export_curve(&config, CurveFormat::Csv, 40.0, 80.0, 10.0);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "temp_c,duty");
+        for (line, temp) in lines[1..].iter().zip([40.0, 50.0, 60.0, 70.0, 80.0]) {
+            let (at, duty) = line.split_once(',').unwrap();
+            assert_eq!(at.parse::<f32>().unwrap(), temp);
+            assert_eq!(
+                duty.parse::<f32>().unwrap(),
+                fan_speed(temp, &config) * 100.0
+            );
+        }
+
+        let json = export_curve(&config, CurveFormat::Json, 75.0, 75.0, 1.0);
+        assert_eq!(json, "[{\"temp_c\":75,\"duty\":100}]\n");
+    }
+
+    #[test]
+    fn sensor_outage_holds_then_runs_at_full_speed() {
+        let config = Config {
+            sensor_failure: SensorFailure::Hold,
+            sensor_failure_grace: 2,
+            ..Config::default()
+        };
+        let mut state = ControlState::default();
+        state.commanded_duty = Some(0.4);
+        for failures in 1..=2 {
+            state.failed_reads = failures;
+            assert_eq!(outage_duty(&config, &state), Some(0.4));
+        }
+        state.failed_reads = 3;
+        assert_eq!(outage_duty(&config, &state), Some(FAN_MAX));
+
+        // Without a speed to hold, or with the default, there's no grace
+        state.failed_reads = 1;
+        state.commanded_duty = None;
+        assert_eq!(outage_duty(&config, &state), Some(FAN_MAX));
+        assert_eq!(outage_duty(&Config::default(), &state), None);
+    }
+
+    #[test]
+    fn config_flag_beats_the_environment() {
+        let flag = Some(PathBuf::from("flag.toml"));
+        let env = Some(OsString::from("env.toml"));
+        assert_eq!(
+            config_path(flag, env.clone()),
+            ("flag.toml".into(), Some("--config"))
+        );
+        assert_eq!(
+            config_path(None, env),
+            ("env.toml".into(), Some(CONFIG_PATH_ENV))
+        );
+        // An empty variable, as systemd leaves an unset `Environment=`, is
+        // ignored
+        assert_eq!(
+            config_path(None, Some(OsString::new())),
+            (DEFAULT_CONFIG_PATH.into(), None)
+        );
+    }
+
+    #[test]
+    fn dwell_holds_full_speed_without_the_emergency_bypass() {
+        let mut config = Config {
+            emergency_bypass_smoothing: false,
+            min_dwell_secs: 30.0,
+            ..Config::default()
+        };
+        let hot = config.max_temp + 1.0;
+        let dwelling = || {
+            let mut state = ControlState::default();
+            state.commanded_duty = Some(0.4);
+            state.last_change = Some(Instant::now());
+            state
+        };
+        assert_eq!(decide_speed(hot, &config, &mut dwelling()), None);
+        config.emergency_bypass_smoothing = true;
+        assert_eq!(decide_speed(hot, &config, &mut dwelling()), Some(FAN_MAX));
+    }
+
+    #[test]
+    fn ramps_down_slower_than_up() {
+        let config = Config {
+            ramp_up_rate: Some(0.1),
+            ramp_down_rate: Some(0.01),
+            ..Config::default()
+        };
+        let ramp =
+            |commanded, target, emergency| ramp_limited(commanded, target, 5.0, &config, emergency);
+        assert!((ramp(0.3, 1.0, false) - 0.8).abs() < 1e-6);
+        assert!((ramp(0.8, 0.3, false) - 0.75).abs() < 1e-6);
+        // Small changes go straight through, and so does rising in an emergency
+        assert_eq!(ramp(0.3, 0.33, false), 0.33);
+        assert_eq!(ramp(0.3, 1.0, true), 1.0);
+        assert!((ramp(0.8, 0.3, true) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn explanation_ends_at_the_loop_speed() {
+        let config = Config::default();
+        let explanation = explain(&config, 62.0);
+        assert!(explanation.contains("Band: Active"));
+        assert!(explanation.ends_with(&format!(
+            "Duty cycle: {:.2}%",
+            fan_speed(62.0, &config) * 100.0
+        )));
+    }
+
+    #[test]
+    fn sine_curve_settles() {
+        assert_settles(&Config::default());
+    }
+
+    #[test]
+    fn exponential_curve_settles() {
+        assert_settles(&Config {
+            curve: CurveMode::Exponential { exponent: 2.0 },
+            ..Config::default()
+        });
     }
 }
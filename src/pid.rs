@@ -0,0 +1,54 @@
+use std::time::Instant;
+
+/// A PID controller whose output is clamped to a range, with the integral term
+/// held back whenever the output is saturated to avoid windup
+pub struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    min: f32,
+    max: f32,
+    integral: f32,
+    prev: Option<(Instant, f32)>,
+}
+
+impl Pid {
+    pub fn new(kp: f32, ki: f32, kd: f32, min: f32, max: f32) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            min,
+            max,
+            integral: 0.0,
+            prev: None,
+        }
+    }
+
+    /// Feeds in the current error (target - measured) and returns the new
+    /// output
+    pub fn update(&mut self, error: f32) -> f32 {
+        let now = Instant::now();
+        let (dt, derivative) = match self.prev {
+            Some((at, prev_error)) => {
+                let dt = (now - at).as_secs_f32();
+                let derivative = if dt > 0.0 {
+                    (error - prev_error) / dt
+                } else {
+                    0.0
+                };
+                (dt, derivative)
+            }
+            None => (0.0, 0.0),
+        };
+        self.prev = Some((now, error));
+
+        let integral = self.integral + error * dt;
+        let output = self.kp * error + self.ki * integral + self.kd * derivative;
+        let clamped = output.clamp(self.min, self.max);
+        if clamped == output {
+            self.integral = integral;
+        }
+        clamped
+    }
+}
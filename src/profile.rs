@@ -0,0 +1,130 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::config::IdleBand;
+
+/// The profile that's active when `--profile` isn't given, outside of the
+/// schedule's windows, if the config defines it
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Settings that override the top-level config while a profile is active
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// [Hz] the PWM frequency to drive the fan at. Some fans whine less at a
+    /// different frequency at low speeds.
+    pub pwm_frequency: Option<f64>,
+    /// What the fan does between `off_temp` and `min_temp`, in place of the
+    /// top-level `idle_band`
+    pub idle_band: Option<IdleBand>,
+}
+
+/// A time of day that a profile is active from or until
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    /// Minutes since midnight
+    minutes: u16,
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{value:?} isn't a time of day, expected HH:MM");
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl TimeOfDay {
+    /// Returns the current local time of day
+    pub fn now() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (hours, minutes, _) = local_time(now as i64).unwrap_or_default();
+        Self {
+            minutes: u16::from(hours) * 60 + u16::from(minutes),
+        }
+    }
+}
+
+/// Returns the local hours, minutes and seconds of a Unix timestamp
+pub fn local_time(timestamp: i64) -> Option<(u8, u8, u8)> {
+    // SAFETY: `localtime_r` only writes to the `tm` it's given
+    unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&(timestamp as libc::time_t), &mut tm).is_null() {
+            return None;
+        }
+        Some((tm.tm_hour as u8, tm.tm_min as u8, tm.tm_sec as u8))
+    }
+}
+
+/// A daily window during which a named profile is active
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    pub profile: String,
+    /// When the window starts, inclusive
+    pub start: TimeOfDay,
+    /// When the window ends, exclusive. A window that ends before it starts
+    /// runs past midnight.
+    pub end: TimeOfDay,
+}
+
+impl ScheduleEntry {
+    fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Returns the name of the profile scheduled at `time`, which is the first
+/// matching entry if several overlap
+pub fn scheduled(schedule: &[ScheduleEntry], time: TimeOfDay) -> Option<&str> {
+    schedule
+        .iter()
+        .find(|entry| entry.contains(time))
+        .map(|entry| entry.profile.as_str())
+}
+
+/// Checks that every scheduled profile exists and that every profile's
+/// settings make sense
+pub fn validate(
+    profiles: &BTreeMap<String, Profile>,
+    schedule: &[ScheduleEntry],
+) -> Result<(), String> {
+    if let Some(entry) = schedule.iter().find(|e| !profiles.contains_key(&e.profile)) {
+        return Err(format!(
+            "schedule refers to profile {:?}, which isn't defined",
+            entry.profile
+        ));
+    }
+    for (name, profile) in profiles {
+        if let Some(frequency) = profile.pwm_frequency {
+            if frequency.is_nan() || frequency <= 0.0 {
+                return Err(format!(
+                    "profiles.{name}.pwm_frequency must be positive, got {frequency}"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
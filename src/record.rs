@@ -0,0 +1,328 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::{
+    config::LogFileConfig,
+    error::AppError,
+    sink::{StatusSnapshot, TelemetrySink},
+};
+
+/// The header line at the top of a CSV log
+const CSV_HEADER: &str = "timestamp,cpu_temp,duty,rpm,fan";
+/// How often `--tail-log` checks the log for new lines
+const TAIL_POLL: Duration = Duration::from_millis(500);
+
+/// The format that each cycle is logged in
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+/// The readings of one fan from one control cycle, as written to the log
+/// file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// [s] since the Unix epoch
+    pub timestamp: u64,
+    /// [°C]
+    pub cpu_temp: f32,
+    /// The fan speed as a percentage
+    pub duty: f32,
+    /// [RPM]
+    pub rpm: f32,
+    /// The fan's name, which is empty in logs from before zones were logged
+    pub fan: String,
+}
+
+impl Record {
+    /// Returns a record of the given readings, timestamped now
+    pub fn now(fan: &str, cpu_temp: f32, duty: f32, rpm: f32) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            timestamp,
+            cpu_temp,
+            duty,
+            rpm,
+            fan: fan.to_string(),
+        }
+    }
+
+    /// Returns the record with any reading that isn't a finite number
+    /// replaced by `sentinel`, so that whatever went wrong upstream, what's
+    /// printed and sent always parses as a number
+    pub fn finite(self, sentinel: f32) -> Self {
+        Self {
+            cpu_temp: finite(self.cpu_temp, sentinel),
+            duty: finite(self.duty, sentinel),
+            rpm: finite(self.rpm, sentinel),
+            ..self
+        }
+    }
+
+    /// Formats the record, whose fan name (a label) never needs escaping
+    pub fn to_line(&self, format: LogFormat) -> String {
+        let Self {
+            timestamp,
+            cpu_temp,
+            duty,
+            rpm,
+            fan,
+        } = self;
+        match format {
+            LogFormat::Csv => format!("{timestamp},{cpu_temp},{duty},{rpm},{fan}"),
+            LogFormat::Json => format!(
+                "{{\"timestamp\":{timestamp},\"cpu_temp\":{cpu_temp},\"duty\":{duty},\"rpm\":\
+                 {rpm},\"fan\":\"{fan}\"}}"
+            ),
+        }
+    }
+
+    /// Parses a line of either format, returning `None` for the CSV header
+    /// and anything else that isn't a record
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let fields: Vec<&str> = match line.strip_prefix('{') {
+            Some(object) => {
+                let mut fields = [""; 5];
+                for pair in object.strip_suffix('}')?.split(',') {
+                    let (key, value) = pair.split_once(':')?;
+                    let index = ["timestamp", "cpu_temp", "duty", "rpm", "fan"]
+                        .iter()
+                        .position(|&name| key.trim().trim_matches('"') == name)?;
+                    fields[index] = value.trim().trim_matches('"');
+                }
+                fields.to_vec()
+            }
+            None => line.split(',').map(str::trim).collect(),
+        };
+        // Logs from before the fan was logged have no name
+        let (timestamp, cpu_temp, duty, rpm, fan) = match fields[..] {
+            [timestamp, cpu_temp, duty, rpm] => (timestamp, cpu_temp, duty, rpm, ""),
+            [timestamp, cpu_temp, duty, rpm, fan] => (timestamp, cpu_temp, duty, rpm, fan),
+            _ => return None,
+        };
+        Some(Self {
+            timestamp: timestamp.parse().ok()?,
+            cpu_temp: cpu_temp.parse().ok()?,
+            duty: duty.parse().ok()?,
+            rpm: rpm.parse().ok()?,
+            fan: fan.to_string(),
+        })
+    }
+}
+
+/// Returns `value`, or `sentinel` if it's NaN or infinite
+pub fn finite(value: f32, sentinel: f32) -> f32 {
+    if value.is_finite() {
+        value
+    } else {
+        sentinel
+    }
+}
+
+/// Replaces the contents of `path` with the records as `key=value` lines:
+/// the main fan's first, and then each zone's with its keys prefixed by its
+/// name, like `fan1.duty`. The new contents are written to a temporary file
+/// that's then renamed over `path`, so readers never see a partly written
+/// file.
+pub fn write_status(path: &Path, records: &[Record]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut contents = String::new();
+    for (i, record) in records.iter().enumerate() {
+        let prefix = match i {
+            0 => String::new(),
+            _ => format!("{}.", record.fan),
+        };
+        let fields = [
+            ("timestamp", record.timestamp.to_string()),
+            ("cpu_temp", record.cpu_temp.to_string()),
+            ("duty", record.duty.to_string()),
+            ("rpm", record.rpm.to_string()),
+            ("fan", record.fan.clone()),
+        ];
+        for (key, value) in fields {
+            contents += &format!("{prefix}{key}={value}\n");
+        }
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
+
+/// Appends a record of each cycle to a file
+pub struct LogFile {
+    file: File,
+    format: LogFormat,
+}
+
+impl LogFile {
+    pub fn open(config: &LogFileConfig) -> Result<Self, AppError> {
+        let context = || format!("open the log file at {}", config.path.display());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .map_err(AppError::io(context()))?;
+        let empty = file.metadata().map_err(AppError::io(context()))?.len() == 0;
+        if empty && matches!(config.format, LogFormat::Csv) {
+            writeln!(file, "{CSV_HEADER}").map_err(AppError::io(context()))?;
+        }
+        Ok(Self {
+            file,
+            format: config.format,
+        })
+    }
+
+    pub fn write(&self, record: &Record) -> io::Result<()> {
+        writeln!(&self.file, "{}", record.to_line(self.format))
+    }
+}
+
+impl TelemetrySink for LogFile {
+    /// Appends a line for each fan
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        for snapshot in snapshots {
+            if let Err(e) = self.write(&snapshot.record()) {
+                warn!("Failed to write to the log file: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// A file that the latest readings are written to each cycle, for scripts to
+/// read
+pub struct StatusFile {
+    path: PathBuf,
+}
+
+impl StatusFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl TelemetrySink for StatusFile {
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        let records: Vec<Record> = snapshots.iter().map(StatusSnapshot::record).collect();
+        if let Err(e) = write_status(&self.path, &records) {
+            warn!("Failed to write the status file: {e}");
+        }
+    }
+}
+
+/// Follows a log file written by [`LogFile`], printing each new record in the
+/// same format as the per-cycle output. Runs until interrupted.
+pub fn tail(path: &Path) -> Result<(), AppError> {
+    let context = || format!("follow the log file at {}", path.display());
+    let mut reader = BufReader::new(File::open(path).map_err(AppError::io(context()))?);
+    let mut position = reader
+        .seek(SeekFrom::End(0))
+        .map_err(AppError::io(context()))?;
+    let mut line = String::new();
+    loop {
+        // Start over from the top if the log has been truncated or rotated in
+        // place
+        let len = std::fs::metadata(path)
+            .map_err(AppError::io(context()))?
+            .len();
+        if len < position {
+            position = reader
+                .seek(SeekFrom::Start(0))
+                .map_err(AppError::io(context()))?;
+        }
+        line.clear();
+        let read = reader
+            .read_line(&mut line)
+            .map_err(AppError::io(context()))?;
+        // A line without its newline is still being written
+        if read == 0 || !line.ends_with('\n') {
+            reader
+                .seek(SeekFrom::Start(position))
+                .map_err(AppError::io(context()))?;
+            std::thread::sleep(TAIL_POLL);
+            continue;
+        }
+        position += read as u64;
+        if let Some(record) = Record::parse(&line) {
+            let (hours, minutes, seconds) =
+                crate::profile::local_time(record.timestamp as i64).unwrap_or_default();
+            let fan = match record.fan.as_str() {
+                "" => String::new(),
+                fan => format!("[{fan}] "),
+            };
+            println!(
+                "{hours:02}:{minutes:02}:{seconds:02} {fan}CPU Temp: {:.2}°C, Fan Percentage: \
+                 {:.2}%, Fan Speed: {:.2} RPM",
+                record.cpu_temp, record.duty, record.rpm
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_readings_print_as_the_sentinel() {
+        let record = Record {
+            timestamp: 1_700_000_000,
+            cpu_temp: f32::INFINITY,
+            duty: 30.0,
+            rpm: f32::NAN,
+            fan: "fan1".to_string(),
+        }
+        .finite(0.0);
+        assert_eq!(record.to_line(LogFormat::Csv), "1700000000,0,30,0,fan1");
+        assert_eq!(
+            record.to_line(LogFormat::Json),
+            r#"{"timestamp":1700000000,"cpu_temp":0,"duty":30,"rpm":0,"fan":"fan1"}"#
+        );
+        assert_eq!(format!("{:.2} RPM", record.rpm), "0.00 RPM");
+        assert_eq!(finite(f32::NEG_INFINITY, -1.0), -1.0);
+        assert_eq!(
+            Record::parse(&record.to_line(LogFormat::Json)),
+            Some(record.clone())
+        );
+        assert_eq!(Record::parse(&record.to_line(LogFormat::Csv)), Some(record));
+        // Logs from before the fan name was logged still parse
+        let old = Record::parse("1700000000,52.5,30,1800").unwrap();
+        assert_eq!((old.cpu_temp, old.fan.as_str()), (52.5, ""));
+    }
+
+    #[test]
+    fn zones_are_prefixed_in_the_status_file() {
+        let path = std::env::temp_dir().join(format!("rpi-fan-status-{}", std::process::id()));
+        let record = |fan: &str, duty| Record {
+            timestamp: 1_700_000_000,
+            cpu_temp: 50.0,
+            duty,
+            rpm: 1200.0,
+            fan: fan.to_string(),
+        };
+        write_status(&path, &[record("fan0", 30.0), record("fan1", 45.5)]).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(contents.starts_with("timestamp=1700000000\ncpu_temp=50\nduty=30\n"));
+        assert!(contents.contains("\nfan=fan0\nfan1.timestamp=1700000000\n"));
+        assert!(contents.ends_with("fan1.duty=45.5\nfan1.rpm=1200\nfan1.fan=fan1\n"));
+    }
+}
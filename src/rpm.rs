@@ -0,0 +1,398 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use rppal::i2c::I2c;
+
+use crate::{
+    config::{
+        Config, CriticalStallAction, CriticalStallConfig, I2cRpmConfig, OffConfirmationConfig,
+        RpmAveraging, RpmSource,
+    },
+    error::AppError,
+};
+
+/// Drops tachometer edges that come less than a minimum interval after the
+/// last one counted, which can only be noise (such as ringing on the line),
+/// and would otherwise inflate the fan speed. An edge timestamped at or
+/// before the last one is dropped too, so the interval is never zero or
+/// negative.
+pub struct EdgeFilter {
+    min_interval: Duration,
+    /// When the last counted edge happened
+    last: Option<Duration>,
+}
+
+impl EdgeFilter {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: None,
+        }
+    }
+
+    /// Returns whether the edge at `timestamp` should be counted
+    pub fn accept(&mut self, timestamp: Duration) -> bool {
+        if let Some(last) = self.last {
+            if timestamp <= last {
+                // Start over from this edge, rather than ignoring every edge
+                // until the clock passes the last one again
+                if timestamp < last {
+                    self.last = Some(timestamp);
+                }
+                return false;
+            }
+            if timestamp - last < self.min_interval {
+                return false;
+            }
+        }
+        self.last = Some(timestamp);
+        true
+    }
+}
+
+/// Reduces a cycle's RPM samples (one per counting window) to a single
+/// reading, or 0.0 if there were none.
+pub fn reduce(samples: &mut [f32], method: RpmAveraging) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    match method {
+        RpmAveraging::Mean => samples.iter().sum::<f32>() / samples.len() as f32,
+        RpmAveraging::Median => {
+            samples.sort_unstable_by(f32::total_cmp);
+            let mid = samples.len() / 2;
+            if samples.len().is_multiple_of(2) {
+                (samples[mid - 1] + samples[mid]) / 2.0
+            } else {
+                samples[mid]
+            }
+        }
+        RpmAveraging::Last => samples[samples.len() - 1],
+    }
+}
+
+/// Returns the first hwmon `fan1_input` node, if any hwmon device reports a
+/// fan speed
+pub fn find_hwmon_fan() -> Option<PathBuf> {
+    let mut nodes: Vec<PathBuf> = std::fs::read_dir("/sys/class/hwmon")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().join("fan1_input"))
+        .filter(|path| path.exists())
+        .collect();
+    nodes.sort();
+    nodes.into_iter().next()
+}
+
+/// Reads the fan speed from a hwmon `fanN_input` node, which holds the RPM as
+/// an integer
+pub fn read_hwmon(path: &Path) -> io::Result<f32> {
+    let raw = std::fs::read_to_string(path)?;
+    raw.trim().parse::<f32>().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{raw:?} in {} isn't a number: {e}", path.display()),
+        )
+    })
+}
+
+/// A register of a fan controller on an I2C bus that holds the fan speed
+pub struct I2cTach {
+    i2c: I2c,
+    config: I2cRpmConfig,
+}
+
+impl I2cTach {
+    /// Opens the bus and reads the register once, reporting
+    /// [`AppError::I2cNoAck`] if nothing answers at its address
+    pub fn open(config: &I2cRpmConfig) -> Result<Self, AppError> {
+        let bus = config.bus;
+        let mut i2c = I2c::with_bus(bus).map_err(AppError::i2c(format!("open /dev/i2c-{bus}")))?;
+        i2c.set_slave_address(config.address)
+            .map_err(AppError::i2c(format!(
+                "address {:#04x} on /dev/i2c-{bus}",
+                config.address
+            )))?;
+        let tach = Self {
+            i2c,
+            config: config.clone(),
+        };
+        tach.read_register().map_err(AppError::i2c_read(
+            bus,
+            config.address,
+            format!(
+                "read the fan speed from {:#04x} on /dev/i2c-{bus}",
+                config.address
+            ),
+        ))?;
+        Ok(tach)
+    }
+
+    fn read_register(&self) -> Result<f32, rppal::i2c::Error> {
+        let raw = match self.config.bits {
+            8 => u16::from(self.i2c.smbus_read_byte(self.config.register)?),
+            _ if self.config.big_endian => {
+                self.i2c.smbus_read_word(self.config.register)?.swap_bytes()
+            }
+            _ => self.i2c.smbus_read_word(self.config.register)?,
+        };
+        Ok(f32::from(raw) * self.config.scale)
+    }
+}
+
+/// Somewhere that the fan speed is read from once per cycle, rather than
+/// counted from tachometer edges
+pub enum PolledRpm {
+    Hwmon(PathBuf),
+    I2c(I2cTach),
+}
+
+impl PolledRpm {
+    /// Opens the configured source, or returns `None` if the tachometer is
+    /// read directly
+    pub fn open(config: &Config) -> Result<Option<Self>, AppError> {
+        match (config.rpm_source, &config.rpm_i2c) {
+            (RpmSource::Hwmon, _) => Ok(config.hwmon_fan()?.map(Self::Hwmon)),
+            (RpmSource::I2c, Some(rpm_i2c)) => Ok(Some(Self::I2c(I2cTach::open(rpm_i2c)?))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the fan speed [RPM]
+    pub fn read(&self) -> io::Result<f32> {
+        match self {
+            Self::Hwmon(path) => read_hwmon(path),
+            Self::I2c(tach) => tach.read_register().map_err(|e| match e {
+                rppal::i2c::Error::Io(e) => e,
+                e => io::Error::other(e),
+            }),
+        }
+    }
+
+    /// Describes the source for the startup log
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Hwmon(path) => path.display().to_string(),
+            Self::I2c(tach) => format!(
+                "register {:#04x} of {:#04x} on /dev/i2c-{}",
+                tach.config.register, tach.config.address, tach.config.bus
+            ),
+        }
+    }
+}
+
+/// Watches the averaged fan RPM for readings outside an expected band while
+/// the fan is running. Too low a reading suggests a failing fan, and too high
+/// a reading suggests the wrong pulse count or a runaway fan. The band is
+/// given at full speed, and scaled down in proportion to the commanded duty,
+/// so that a fan running slowly on purpose isn't taken to be failing.
+pub struct RpmBandMonitor {
+    /// [RPM] the lower limit at full speed
+    low: Option<f32>,
+    /// [RPM] the upper limit at full speed
+    high: Option<f32>,
+    /// How many consecutive cycles out of band it takes to raise an alert
+    cycles: u32,
+    out_of_band: u32,
+    /// The number of alerts raised since startup
+    pub alerts: u64,
+}
+
+impl RpmBandMonitor {
+    pub fn new(low: Option<f32>, high: Option<f32>, cycles: u32) -> Self {
+        Self {
+            low,
+            high,
+            cycles: cycles.max(1),
+            out_of_band: 0,
+            alerts: 0,
+        }
+    }
+
+    /// Returns whether the fan speed has been out of band for long enough to
+    /// have raised an alert, and hasn't come back yet
+    pub fn alarmed(&self) -> bool {
+        self.out_of_band >= self.cycles
+    }
+
+    /// Checks one cycle's averaged RPM against the band for the commanded
+    /// speed. Readings taken while the fan is commanded off are expected to be
+    /// zero and are ignored.
+    pub fn check(&mut self, rpm: f32, fan_percentage: f32) {
+        let scale = fan_percentage.clamp(0.0, 100.0) / 100.0;
+        let low = self.low.map(|low| low * scale);
+        let high = self.high.map(|high| high * scale);
+        let in_band = fan_percentage <= 0.0
+            || (low.is_none_or(|low| rpm >= low) && high.is_none_or(|high| rpm <= high));
+
+        if in_band {
+            if self.out_of_band >= self.cycles {
+                info!("Fan speed of {rpm:.2} RPM is back within the expected range");
+            }
+            self.out_of_band = 0;
+            return;
+        }
+
+        self.out_of_band += 1;
+        if self.out_of_band == self.cycles {
+            self.alerts += 1;
+            warn!(
+                "Fan speed of {rpm:.2} RPM at {fan_percentage:.2}% has been outside the expected \
+                 range of {} to {} RPM for {} cycles",
+                low.map_or("-".to_string(), |low| format!("{low:.0}")),
+                high.map_or("-".to_string(), |high| format!("{high:.0}")),
+                self.cycles
+            );
+        }
+    }
+}
+
+/// Watches the fan speed after the fan has been commanded off, and warns if
+/// it's still spinning once it's had time to stop, which usually means that
+/// its power is stuck on (e.g. by a welded relay, or an external supply)
+pub struct OffConfirmation {
+    config: OffConfirmationConfig,
+    /// The number of cycles in a row that the fan has been commanded off
+    off_cycles: u32,
+}
+
+impl OffConfirmation {
+    pub fn new(config: OffConfirmationConfig) -> Self {
+        Self {
+            config,
+            off_cycles: 0,
+        }
+    }
+
+    /// Checks one cycle's averaged RPM against the fan speed it was commanded
+    /// to
+    pub fn check(&mut self, rpm: f32, fan_percentage: f32) {
+        if fan_percentage > 0.0 {
+            self.off_cycles = 0;
+            return;
+        }
+        self.off_cycles += 1;
+        if self.off_cycles != self.config.cycles.max(1) {
+            return;
+        }
+        if rpm > self.config.max_rpm {
+            warn!(
+                "Fan is still spinning at {rpm:.2} RPM {} cycles after being turned off. Its \
+                 power may be stuck on.",
+                self.off_cycles
+            );
+        } else {
+            debug!("Fan confirmed stopped at {rpm:.2} RPM");
+        }
+    }
+}
+
+/// Watches for the worst case: the CPU at or above the critical temperature
+/// while the fan reads 0 RPM at full speed, so more duty can't help. This is
+/// responded to once per episode, once it has held for `cycles` cycles in a
+/// row.
+pub struct CriticalStall {
+    /// [°C]
+    critical_temp: f32,
+    cycles: u32,
+    action: CriticalStallAction,
+    /// The number of cycles in a row that the condition has held
+    held: u32,
+}
+
+impl CriticalStall {
+    pub fn new(config: CriticalStallConfig, max_temp: f32) -> Self {
+        Self {
+            critical_temp: config.critical_temp.unwrap_or(max_temp),
+            cycles: config.cycles.max(1),
+            action: config.action,
+            held: 0,
+        }
+    }
+
+    /// Checks one cycle's readings, responding if this is the cycle that the
+    /// condition has held long enough
+    pub fn check(&mut self, cpu_temp: f32, rpm: f32, fan_percentage: f32) {
+        if cpu_temp < self.critical_temp || fan_percentage < 100.0 || rpm > 0.0 {
+            self.held = 0;
+            return;
+        }
+        self.held += 1;
+        if self.held != self.cycles {
+            return;
+        }
+        error!(
+            "CRITICAL: the CPU is at {cpu_temp:.2}°C and the fan reads 0 RPM at full speed for {} \
+             cycles in a row. The fan can't cool it.",
+            self.held
+        );
+        let command = match &self.action {
+            CriticalStallAction::Log => return,
+            CriticalStallAction::Shutdown => "shutdown -h now",
+            CriticalStallAction::Command(command) => command,
+        };
+        error!("Running {command:?} in response");
+        match Command::new("sh").arg("-c").arg(command).status() {
+            Ok(status) if status.success() => info!("The critical stall command succeeded"),
+            Ok(status) => warn!("The critical stall command failed with {status}"),
+            Err(e) => warn!("Failed to run the critical stall command: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edges_too_close_together_are_dropped() {
+        let mut filter = EdgeFilter::new(Duration::from_micros(200));
+        let at = Duration::from_micros;
+        assert!(filter.accept(at(1_000)));
+        // The same instant, and a hair later
+        assert!(!filter.accept(at(1_000)));
+        assert!(!filter.accept(at(1_001)));
+        assert!(filter.accept(at(1_200)));
+        // Going backwards restarts from that edge without counting it
+        assert!(!filter.accept(at(500)));
+        assert!(filter.accept(at(800)));
+    }
+
+    #[test]
+    fn rpm_band_follows_the_duty() {
+        let mut band = RpmBandMonitor::new(Some(1000.0), Some(5000.0), 1);
+        // 800 RPM is fine at 50% (500 to 2500 RPM), but too slow at full speed
+        band.check(800.0, 50.0);
+        assert!(!band.alarmed());
+        band.check(800.0, 100.0);
+        assert!(band.alarmed());
+        assert_eq!(band.alerts, 1);
+        band.check(4000.0, 100.0);
+        assert!(!band.alarmed());
+        // 4000 RPM is fine at full speed, but too fast at 50%
+        band.check(4000.0, 50.0);
+        assert_eq!(band.alerts, 2);
+    }
+
+    #[test]
+    fn critical_stall_needs_every_condition() {
+        let mut stall = CriticalStall::new(CriticalStallConfig::default(), 75.0);
+        stall.check(80.0, 0.0, 100.0);
+        stall.check(80.0, 0.0, 100.0);
+        assert_eq!(stall.held, 2);
+        // Spinning, below full speed, or below the critical temperature
+        // each clear it
+        for (cpu_temp, rpm, fan_percentage) in
+            [(80.0, 900.0, 100.0), (80.0, 0.0, 90.0), (70.0, 0.0, 100.0)]
+        {
+            stall.check(80.0, 0.0, 100.0);
+            stall.check(cpu_temp, rpm, fan_percentage);
+            assert_eq!(stall.held, 0);
+        }
+    }
+}
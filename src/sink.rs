@@ -0,0 +1,92 @@
+use crate::{
+    config::{Config, Labels},
+    error::AppError,
+    health::{Health, HealthOutput},
+    http::HttpServer,
+    influx::InfluxOutput,
+    record::{LogFile, Record, StatusFile},
+    stdout::StdoutOutput,
+};
+
+/// The readings of one fan from one control cycle, as handed to every
+/// telemetry sink
+#[derive(Debug, Default, Clone)]
+pub struct StatusSnapshot {
+    /// The fan's name, as a label
+    pub fan: String,
+    /// [s] since the Unix epoch
+    pub timestamp: u64,
+    /// [°C] the CPU temperature, or a zone's own temperature
+    pub cpu_temp: f32,
+    /// The fan speed as a percentage
+    pub duty: f32,
+    /// [RPM] the measured fan speed, which is NaN (or `nan_sentinel`) for a
+    /// zone without a tachometer
+    pub rpm: f32,
+    /// Whether the fan's speed can't be read, leaving `rpm` meaningless
+    pub no_tach: bool,
+    /// The number of RPM alerts raised so far
+    pub rpm_alerts: u64,
+    /// The number of control cycles run so far
+    pub iterations: u64,
+    /// Whether the boost button is holding the fan at full speed
+    pub boost: bool,
+    /// [°C] how far the CPU temperature is below the throttle point, if the
+    /// headroom is reported
+    pub headroom: Option<f32>,
+    /// The duty cycle as a percentage that's actually written, while the fan
+    /// is held at `hardware_min_duty` rather than the commanded speed
+    pub pwm_floor: Option<f32>,
+    /// Whether the CPU temperature couldn't be read this cycle, leaving
+    /// `cpu_temp` NaN (or `nan_sentinel`) and the fan at the `sensor_failure`
+    /// speed
+    pub no_reading: bool,
+    /// The health verdict on the cycle, which covers every fan
+    pub health: Health,
+}
+
+impl StatusSnapshot {
+    /// Returns the readings as they're logged
+    pub fn record(&self) -> Record {
+        Record {
+            timestamp: self.timestamp,
+            cpu_temp: self.cpu_temp,
+            duty: self.duty,
+            rpm: self.rpm,
+            fan: self.fan.clone(),
+        }
+    }
+}
+
+/// Somewhere that each control cycle is reported to. A sink deals with its
+/// own failures (usually by logging them), so one that fails never keeps the
+/// others from being written.
+pub trait TelemetrySink {
+    /// Reports a cycle, with the main fan's snapshot first and then one for
+    /// each zone
+    fn emit(&self, snapshots: &[StatusSnapshot]);
+}
+
+/// Opens every sink that the config enables, in any combination
+pub fn open_all(config: &Config, labels: &Labels) -> Result<Vec<Box<dyn TelemetrySink>>, AppError> {
+    let mut sinks: Vec<Box<dyn TelemetrySink>> = Vec::new();
+    if config.stdout {
+        sinks.push(Box::new(StdoutOutput::new(config)));
+    }
+    if let Some(output) = &config.health.output {
+        sinks.push(Box::new(HealthOutput::parse(output)));
+    }
+    if let Some(influx) = &config.influx {
+        sinks.push(Box::new(InfluxOutput::new(influx, labels)?));
+    }
+    if let Some(log_file) = &config.log_file {
+        sinks.push(Box::new(LogFile::open(log_file)?));
+    }
+    if let Some(path) = &config.status_file {
+        sinks.push(Box::new(StatusFile::new(path.clone())));
+    }
+    if let Some(http) = &config.http {
+        sinks.push(Box::new(HttpServer::start(http, labels.clone())?));
+    }
+    Ok(sinks)
+}
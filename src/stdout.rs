@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+
+use crate::{
+    change::ChangeFilter,
+    config::{Config, Precision},
+    sink::{StatusSnapshot, TelemetrySink},
+    summary::RunSummary,
+};
+
+/// Prints each cycle's status lines on stdout, or a rollup of them over each
+/// window
+pub struct StdoutOutput {
+    precision: Precision,
+    /// Whether the per-cycle lines are printed, which they aren't with a
+    /// rollup unless it asks for them
+    raw_lines: bool,
+    change_filter: RefCell<Option<ChangeFilter>>,
+    /// [s] how long each rollup window is, and the window so far
+    rollup: Option<(f32, RefCell<RunSummary>)>,
+}
+
+impl StdoutOutput {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            precision: config.precision,
+            raw_lines: config.rollup.as_ref().is_none_or(|rollup| rollup.raw_lines),
+            change_filter: RefCell::new(config.log_on_change.map(ChangeFilter::new)),
+            rollup: config
+                .rollup
+                .as_ref()
+                .map(|rollup| (rollup.secs, RefCell::new(RunSummary::window()))),
+        }
+    }
+
+    /// Formats the main fan's line, which says which fan it's about if
+    /// there are zones too
+    fn main_line(&self, snapshot: &StatusSnapshot, prefixed: bool) -> String {
+        let precision = self.precision;
+        format!(
+            "{}CPU Temp: {:.temp$}°C, Fan Percentage: {:.duty$}%{}, Fan Speed: {}{}",
+            match prefixed {
+                true => format!("[{}] ", snapshot.fan),
+                false => String::new(),
+            },
+            snapshot.cpu_temp,
+            snapshot.duty,
+            snapshot.pwm_floor.map_or_else(String::new, |floor| format!(
+                " (PWM held at {floor:.duty$}%)",
+                duty = precision.duty
+            )),
+            self.speed(snapshot),
+            snapshot
+                .headroom
+                .map_or_else(String::new, |headroom| format!(
+                    ", Headroom: {headroom:.temp$}°C",
+                    temp = precision.temp
+                )),
+            temp = precision.temp,
+            duty = precision.duty,
+        )
+    }
+
+    /// Formats a zone's line
+    fn zone_line(&self, snapshot: &StatusSnapshot) -> String {
+        format!(
+            "[{}] Temp: {:.temp$}°C, Fan Percentage: {:.duty$}%, Fan Speed: {}",
+            snapshot.fan,
+            snapshot.cpu_temp,
+            snapshot.duty,
+            self.speed(snapshot),
+            temp = self.precision.temp,
+            duty = self.precision.duty,
+        )
+    }
+
+    fn speed(&self, snapshot: &StatusSnapshot) -> String {
+        match snapshot.no_tach {
+            true => "unknown".to_string(),
+            false => format!("{:.rpm$} RPM", snapshot.rpm, rpm = self.precision.rpm),
+        }
+    }
+}
+
+impl TelemetrySink for StdoutOutput {
+    fn emit(&self, snapshots: &[StatusSnapshot]) {
+        let Some((main, zones)) = snapshots.split_first() else {
+            return;
+        };
+        if self.raw_lines {
+            let changed = self
+                .change_filter
+                .borrow_mut()
+                .as_mut()
+                .is_none_or(|filter| filter.should_log(main.cpu_temp, main.duty, main.rpm));
+            if changed {
+                println!("{}", self.main_line(main, !zones.is_empty()));
+            }
+            for zone in zones {
+                println!("{}", self.zone_line(zone));
+            }
+        }
+        if let Some((secs, window)) = &self.rollup {
+            let mut window = window.borrow_mut();
+            let cpu_temp = (!main.no_reading).then_some(main.cpu_temp);
+            window.record(cpu_temp, main.duty, main.rpm);
+            if window.elapsed().as_secs_f32() >= *secs {
+                println!("{}", window.one_line());
+                *window = RunSummary::window();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_name_the_fan_with_zones() {
+        let output = StdoutOutput::new(&Config::default());
+        let main = StatusSnapshot {
+            fan: "fan0".to_string(),
+            cpu_temp: 50.0,
+            duty: 30.0,
+            rpm: 1200.0,
+            headroom: Some(30.0),
+            ..StatusSnapshot::default()
+        };
+        assert_eq!(
+            output.main_line(&main, false),
+            "CPU Temp: 50.00°C, Fan Percentage: 30.00%, Fan Speed: 1200.00 RPM, Headroom: 30.00°C"
+        );
+        assert!(output
+            .main_line(&main, true)
+            .starts_with("[fan0] CPU Temp: 50.00°C"));
+        let zone = StatusSnapshot {
+            fan: "fan1".to_string(),
+            cpu_temp: 40.0,
+            duty: 20.0,
+            no_tach: true,
+            ..StatusSnapshot::default()
+        };
+        assert_eq!(
+            output.zone_line(&zone),
+            "[fan1] Temp: 40.00°C, Fan Percentage: 20.00%, Fan Speed: unknown"
+        );
+    }
+}
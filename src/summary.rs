@@ -0,0 +1,184 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// The minimum, maximum, and mean of a series of readings
+#[derive(Default)]
+pub struct Stats {
+    min: f32,
+    max: f32,
+    sum: f32,
+    count: u32,
+}
+
+impl Stats {
+    pub fn add(&mut self, value: f32) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f32
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return f.write_str("no readings");
+        }
+        write!(
+            f,
+            "min {:.2}, avg {:.2}, max {:.2}",
+            self.min,
+            self.mean(),
+            self.max
+        )
+    }
+}
+
+/// The median, 95th percentile, and standard deviation of a series of
+/// readings, which tell a reading that holds steady from one that surges.
+/// Unlike [`Stats`], every reading is kept, so it's only used over bounded
+/// windows.
+#[derive(Default)]
+pub struct Spread {
+    samples: Vec<f32>,
+}
+
+impl Spread {
+    pub fn add(&mut self, value: f32) {
+        self.samples.push(value);
+    }
+
+    /// Returns the nearest-rank percentile, with `p` between 0.0 and 1.0
+    fn percentile(sorted: &[f32], p: f32) -> f32 {
+        if sorted.is_empty() {
+            return 0.0;
+        }
+        let rank = (p * sorted.len() as f32).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    fn std_dev(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let count = self.samples.len() as f32;
+        let mean = self.samples.iter().sum::<f32>() / count;
+        let variance = self
+            .samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f32>()
+            / count;
+        variance.sqrt()
+    }
+}
+
+impl fmt::Display for Spread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable_by(f32::total_cmp);
+        write!(
+            f,
+            "p50 {:.2}, p95 {:.2}, std dev {:.2}",
+            Self::percentile(&sorted, 0.5),
+            Self::percentile(&sorted, 0.95),
+            self.std_dev()
+        )
+    }
+}
+
+/// Statistics over every cycle of a run (printed on exit), or of a rollup
+/// window
+pub struct RunSummary {
+    started: Instant,
+    pub iterations: u64,
+    pub cpu_temp: Stats,
+    pub fan_percentage: Stats,
+    pub rpm: Stats,
+    /// The spread of the fan speed, which only rollup windows keep
+    pub rpm_spread: Option<Spread>,
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            iterations: 0,
+            cpu_temp: Stats::default(),
+            fan_percentage: Stats::default(),
+            rpm: Stats::default(),
+            rpm_spread: None,
+        }
+    }
+
+    /// Starts a rollup window, which also tracks the spread of the fan speed
+    pub fn window() -> Self {
+        Self {
+            rpm_spread: Some(Spread::default()),
+            ..Self::new()
+        }
+    }
+
+    /// Counts a cycle, whose CPU temperature is `None` if it couldn't be read
+    pub fn record(&mut self, cpu_temp: Option<f32>, fan_percentage: f32, rpm: f32) {
+        self.iterations += 1;
+        if let Some(cpu_temp) = cpu_temp {
+            self.cpu_temp.add(cpu_temp);
+        }
+        self.fan_percentage.add(fan_percentage);
+        self.rpm.add(rpm);
+        if let Some(spread) = &mut self.rpm_spread {
+            spread.add(rpm);
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Formats the statistics as a single line, for rollups
+    pub fn one_line(&self) -> String {
+        let mut line = format!(
+            "Rollup of {} iterations over {:.0?}: CPU Temp (°C): {}; Fan Percentage (%): {}; Fan \
+             Speed (RPM): {}",
+            self.iterations,
+            self.elapsed(),
+            self.cpu_temp,
+            self.fan_percentage,
+            self.rpm
+        );
+        if let Some(spread) = &self.rpm_spread {
+            line += &format!(", {spread}");
+        }
+        line
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Ran {} iterations over {:.0?}",
+            self.iterations,
+            self.started.elapsed()
+        )?;
+        writeln!(f, "CPU Temp (°C): {}", self.cpu_temp)?;
+        writeln!(f, "Fan Percentage (%): {}", self.fan_percentage)?;
+        write!(f, "Fan Speed (RPM): {}", self.rpm)
+    }
+}
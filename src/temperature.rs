@@ -0,0 +1,264 @@
+use std::{
+    fmt, io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use crate::config::TempFusion;
+
+/// The CPU's thermal zone, whose `temp` is the CPU temperature
+pub const CPU_THERMAL_ZONE: &str = "/sys/class/thermal/thermal_zone0";
+
+/// Something that a temperature can be read from
+pub trait TemperatureSource {
+    /// Returns the current temperature in °C
+    fn read(&mut self) -> io::Result<f32>;
+}
+
+/// A correction applied to a sensor's readings, as `reading * scale + offset`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    /// [°C] added after scaling
+    pub offset: f32,
+    pub scale: f32,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(self, temp: f32) -> f32 {
+        temp * self.scale + self.offset
+    }
+}
+
+/// A temperature read from a sysfs-style file holding a single number, such
+/// as a thermal zone or a 1-Wire sensor
+pub struct SysfsSource {
+    pub path: PathBuf,
+    /// What the raw value is divided by to get °C, e.g. 1000 for
+    /// millidegrees
+    pub divisor: f32,
+    pub calibration: Calibration,
+}
+
+impl TemperatureSource for SysfsSource {
+    fn read(&mut self) -> io::Result<f32> {
+        let bytes = std::fs::read(&self.path)?;
+        debug!(
+            "Read {} bytes from {}: \"{}\", to be divided by {}",
+            bytes.len(),
+            self.path.display(),
+            bytes.escape_ascii(),
+            self.divisor
+        );
+        let raw = String::from_utf8_lossy(&bytes);
+        let value = raw.trim().parse::<f32>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{raw:?} in {} isn't a number: {e}", self.path.display()),
+            )
+        })?;
+        let temp = self.calibration.apply(value / self.divisor);
+        debug!(
+            "Read {}°C from {}, calibrated to {temp}°C",
+            value / self.divisor,
+            self.path.display()
+        );
+        Ok(temp)
+    }
+}
+
+/// Reads from the first of several sources, in order of priority, that
+/// reads successfully. A reading outside of the plausible range counts as a
+/// failure, like a sensor that reads -127°C once it's dropped off the bus.
+pub struct FailoverSource {
+    /// The sources, each with the name it's logged under
+    sources: Vec<(String, Box<dyn TemperatureSource>)>,
+    /// [°C] the readings that are taken at face value
+    plausible: RangeInclusive<f32>,
+    /// The source that the last successful reading came from
+    active: Option<usize>,
+}
+
+impl FailoverSource {
+    pub fn new(
+        sources: Vec<(String, Box<dyn TemperatureSource>)>,
+        plausible: RangeInclusive<f32>,
+    ) -> Self {
+        Self {
+            sources,
+            plausible,
+            active: None,
+        }
+    }
+}
+
+impl TemperatureSource for FailoverSource {
+    fn read(&mut self) -> io::Result<f32> {
+        let mut failures = Vec::new();
+        let mut reading = None;
+        for (i, (name, source)) in self.sources.iter_mut().enumerate() {
+            match source.read() {
+                Ok(temp) if self.plausible.contains(&temp) => {
+                    reading = Some((i, temp));
+                    break;
+                }
+                Ok(temp) => failures.push(format!("{name} read an implausible {temp}°C")),
+                Err(e) => failures.push(format!("{name} couldn't be read: {e}")),
+            }
+        }
+        let Some((i, temp)) = reading else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("none of the sensors could be read, {}", failures.join(", ")),
+            ));
+        };
+        let name = &self.sources[i].0;
+        match self.active {
+            Some(active) if i > active => warn!(
+                "Failing over from {} to {name}, since {}",
+                self.sources[active].0,
+                failures.join(", ")
+            ),
+            Some(active) if i < active => info!(
+                "{name} has recovered, switching back to it from {}",
+                self.sources[active].0
+            ),
+            None if i > 0 => warn!("Starting on {name}, since {}", failures.join(", ")),
+            _ => {}
+        }
+        self.active = Some(i);
+        Ok(temp)
+    }
+}
+
+/// The sensor that the control temperature follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlSource {
+    Cpu,
+    Gpu,
+}
+
+impl fmt::Display for ControlSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cpu => "CPU",
+            Self::Gpu => "GPU",
+        })
+    }
+}
+
+/// Combines the CPU and GPU temperatures [°C] into the one that the fan is
+/// controlled from, each given with the temperature that it throttles at, and
+/// returns which of the two it follows
+pub fn fuse(
+    fusion: TempFusion,
+    (cpu, cpu_throttle): (f32, f32),
+    (gpu, gpu_throttle): (f32, f32),
+) -> (f32, ControlSource) {
+    match fusion {
+        TempFusion::Max if gpu > cpu => (gpu, ControlSource::Gpu),
+        TempFusion::HeadroomMax if gpu_throttle - gpu < cpu_throttle - cpu => {
+            (cpu_throttle - (gpu_throttle - gpu), ControlSource::Gpu)
+        }
+        _ => (cpu, ControlSource::Cpu),
+    }
+}
+
+/// Returns the temperatures [°C] of a thermal zone's `active` and `passive`
+/// trip points, where the kernel starts cooling, in increasing order. The
+/// `hot` and `critical` ones are left out, since they're where it shuts down.
+pub fn cooling_trip_points(zone: &Path) -> io::Result<Vec<f32>> {
+    let mut temps = Vec::new();
+    for i in 0.. {
+        let temp_path = zone.join(format!("trip_point_{i}_temp"));
+        let raw = match std::fs::read_to_string(&temp_path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        };
+        let kind = std::fs::read_to_string(zone.join(format!("trip_point_{i}_type")))?;
+        if !matches!(kind.trim(), "active" | "passive") {
+            continue;
+        }
+        let millidegrees = raw.trim().parse::<f32>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{raw:?} in {} isn't a number: {e}", temp_path.display()),
+            )
+        })?;
+        temps.push(millidegrees / 1000.0);
+    }
+    temps.sort_by(f32::total_cmp);
+    temps.dedup();
+    Ok(temps)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Hands out a fixed series of readings, where `None` is a failed read
+    struct Scripted(VecDeque<Option<f32>>);
+
+    impl TemperatureSource for Scripted {
+        fn read(&mut self) -> io::Result<f32> {
+            self.0
+                .pop_front()
+                .flatten()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "gone"))
+        }
+    }
+
+    #[test]
+    fn fails_over_in_order_and_back() {
+        let primary = Scripted([Some(50.0), None, Some(-127.0), Some(52.0)].into());
+        let secondary = Scripted([Some(61.0), Some(62.0)].into());
+        let mut failover = FailoverSource::new(
+            vec![
+                ("primary".to_string(), Box::new(primary)),
+                ("secondary".to_string(), Box::new(secondary)),
+            ],
+            -20.0..=150.0,
+        );
+        // The secondary isn't read while the primary is fine
+        assert_eq!(failover.read().unwrap(), 50.0);
+        assert_eq!(failover.read().unwrap(), 61.0);
+        // An implausible reading fails over just the same
+        assert_eq!(failover.read().unwrap(), 62.0);
+        assert_eq!(failover.read().unwrap(), 52.0);
+        // With nothing left to read, the error names every sensor
+        let error = failover.read().unwrap_err().to_string();
+        assert!(
+            error.contains("primary") && error.contains("secondary"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn headroom_max_follows_the_closest_to_throttling() {
+        // The CPU is hotter and closer to its throttle point
+        let fused = fuse(TempFusion::HeadroomMax, (72.0, 85.0), (70.0, 85.0));
+        assert_eq!(fused, (72.0, ControlSource::Cpu));
+        // The GPU is cooler, but has only 5°C left, which puts it at 80°C on
+        // the CPU's scale
+        let fused = fuse(TempFusion::HeadroomMax, (72.0, 85.0), (70.0, 75.0));
+        assert_eq!(fused, (80.0, ControlSource::Gpu));
+        // A plain max ignores the throttle points
+        let fused = fuse(TempFusion::Max, (72.0, 85.0), (70.0, 75.0));
+        assert_eq!(fused, (72.0, ControlSource::Cpu));
+    }
+}
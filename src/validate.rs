@@ -0,0 +1,152 @@
+use std::{
+    ffi::CString,
+    io::{self, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use rppal::{gpio::Gpio, pwm::Pwm};
+
+use crate::{
+    board::Board,
+    config::{Config, FanBackend, HwmonPwmConfig, I2cConfig, RpmSource},
+    driver::Pca9685,
+    error::AppError,
+    rpm::PolledRpm,
+};
+
+/// The outcome of one check, as a description of what was found or the error
+/// that made it fail
+type Outcome = Result<String, AppError>;
+
+/// Checks the config, the temperature sensor, the PWM output, and the GPIO
+/// pins in turn, printing a pass/fail line for each. Nothing is written to
+/// the PWM channel or the pins, so the fan keeps whatever speed it had.
+pub fn run(config: Result<Config, AppError>) -> Result<(), AppError> {
+    let mut outcomes: Vec<(&str, Outcome)> = Vec::new();
+
+    let config = match config {
+        Ok(config) => {
+            outcomes.push(("Config", Ok("parsed and valid".to_string())));
+            config
+        }
+        Err(e) => {
+            outcomes.push(("Config", Err(e)));
+            Config::default()
+        }
+    };
+
+    outcomes.push((
+        "Temperature sensor",
+        crate::get_cpu_temp(config.cpu_calibration, config.cpu_scale_divisor).and_then(|temp| {
+            let temp = temp.ok_or_else(|| {
+                AppError::io("read a plausible temperature")(ErrorKind::InvalidData.into())
+            })?;
+            Ok(format!("reads {temp:.2}°C"))
+        }),
+    ));
+
+    if let Some(polled_rpm) = PolledRpm::open(&config).transpose() {
+        outcomes.push(("Tachometer", polled_rpm.and_then(check_polled_rpm)));
+    }
+
+    let board = Board::detect();
+    match &config.fan_driver {
+        FanBackend::Pwm => outcomes.push(("PWM channel", check_pwm(&config, board))),
+        FanBackend::I2c(i2c) => outcomes.push(("I2C fan controller", check_i2c(i2c))),
+        FanBackend::Hwmon(hwmon) => outcomes.push(("Hwmon PWM node", check_hwmon_pwm(hwmon))),
+    }
+
+    match Gpio::new().map_err(AppError::gpio("access the GPIO peripheral")) {
+        Ok(gpio) => {
+            if config.rpm_source == RpmSource::Tach {
+                outcomes.push((
+                    "Tachometer pin",
+                    claim(&gpio, crate::TACH_PIN, "claim the tachometer pin"),
+                ));
+            }
+            if let Some(pin) = config.power_pin {
+                outcomes.push(("Power pin", claim(&gpio, pin, "claim the fan power pin")));
+            }
+            if let Some(pin) = config.led_pin {
+                outcomes.push(("LED pin", claim(&gpio, pin, "claim the status LED pin")));
+            }
+        }
+        Err(e) => outcomes.push(("GPIO", Err(e))),
+    }
+
+    let total = outcomes.len();
+    let mut failed = 0;
+    for (name, outcome) in &outcomes {
+        match outcome {
+            Ok(found) => println!("[PASS] {name}: {found}"),
+            Err(e) => {
+                failed += 1;
+                println!("[FAIL] {name}: {e:?}");
+            }
+        }
+    }
+    println!("{} of {total} checks passed", total - failed);
+
+    if failed > 0 {
+        return Err(AppError::Validation { failed, total });
+    }
+    Ok(())
+}
+
+/// Exports the PWM channel without touching its period, duty cycle or
+/// enabled state
+fn check_pwm(config: &Config, board: Board) -> Outcome {
+    let channel = crate::pwm_channel(config, board)?;
+    let mut pwm = Pwm::new(channel).map_err(AppError::pwm("export the PWM channel"))?;
+    // Dropping it would otherwise disable and unexport the channel
+    pwm.set_reset_on_drop(false);
+    Ok(format!("{channel} can be exported on the {}", board.name()))
+}
+
+/// Checks that the PCA9685 acknowledges its address, without writing to it
+fn check_i2c(config: &I2cConfig) -> Outcome {
+    Pca9685::probe(config)?;
+    Ok(format!(
+        "a device answers at {:#04x} on /dev/i2c-{}",
+        config.address, config.bus
+    ))
+}
+
+/// Checks that the PWM node can be read, and that it could be written, without
+/// writing to it
+fn check_hwmon_pwm(config: &HwmonPwmConfig) -> Outcome {
+    let path = &config.path;
+    let value =
+        std::fs::read_to_string(path).map_err(AppError::io(format!("read {}", path.display())))?;
+    writable(path).map_err(AppError::io(format!("write to {}", path.display())))?;
+    Ok(format!(
+        "{} holds {} of {}",
+        path.display(),
+        value.trim(),
+        config.pwm_max_value
+    ))
+}
+
+/// Checks that the file at `path` could be opened for writing
+fn writable(path: &Path) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid NUL-terminated string
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn check_polled_rpm(source: PolledRpm) -> Outcome {
+    let rpm = source
+        .read()
+        .map_err(AppError::io(format!("read {}", source.describe())))?;
+    Ok(format!("{} reads {rpm} RPM", source.describe()))
+}
+
+fn claim(gpio: &Gpio, pin: u8, context: &'static str) -> Outcome {
+    gpio.get(pin).map_err(AppError::gpio(context))?;
+    Ok(format!("BCM GPIO {pin} can be claimed"))
+}
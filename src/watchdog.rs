@@ -0,0 +1,102 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+
+use crate::{config::WatchdogConfig, driver::FanDriver};
+
+/// How many times per timeout the watchdog checks on the control loop
+const CHECKS_PER_TIMEOUT: u32 = 4;
+
+/// Forces the fan to a fail-safe speed from a thread of its own if the control
+/// loop stops completing cycles, e.g. because an output has blocked. The
+/// thread drives the fan through a handle of its own, so it doesn't depend on
+/// anything that the stuck loop might be holding.
+pub struct Watchdog {
+    shared: Arc<Shared>,
+}
+
+/// What the control loop and the watchdog thread share
+struct Shared {
+    started: Instant,
+    /// [ms] since `started`, when the loop last completed a cycle
+    last_beat: AtomicU64,
+    /// Whether the fan has been forced to the fail-safe speed since the last
+    /// beat
+    tripped: AtomicBool,
+    /// Whether the watchdog has been stopped for shutdown. It's held while the
+    /// fan speed is forced, so once it's set the speed is never forced again.
+    stopped: Mutex<bool>,
+}
+
+impl Shared {
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+impl Watchdog {
+    pub fn start(config: WatchdogConfig, mut driver: Box<dyn FanDriver + Send>) -> Self {
+        let shared = Arc::new(Shared {
+            started: Instant::now(),
+            last_beat: AtomicU64::new(0),
+            tripped: AtomicBool::new(false),
+            stopped: Mutex::new(false),
+        });
+        let watched = Arc::clone(&shared);
+        let timeout = Duration::from_secs_f32(config.timeout_secs);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(timeout / CHECKS_PER_TIMEOUT);
+            let stopped = watched.stopped.lock().unwrap();
+            if *stopped {
+                break;
+            }
+            // A beat can land between reading the clock and loading it
+            let since_beat = watched
+                .now()
+                .saturating_sub(watched.last_beat.load(Ordering::Relaxed));
+            if Duration::from_millis(since_beat) < timeout {
+                continue;
+            }
+            if !watched.tripped.swap(true, Ordering::Relaxed) {
+                error!(
+                    "The control loop hasn't completed a cycle in {:.1}s, forcing the fan to \
+                     {:.0}%",
+                    since_beat as f32 / 1000.0,
+                    config.failsafe_speed * 100.0
+                );
+            }
+            // Repeated for as long as the loop is stuck, in case something
+            // else changes the speed
+            if let Err(e) = driver.set_duty(config.failsafe_speed) {
+                error!("The watchdog failed to set the fan speed: {e:?}");
+            }
+        });
+        Self { shared }
+    }
+
+    /// Records that the control loop has completed a cycle. Returns whether
+    /// the watchdog forced the fan speed since the last one, in which case the
+    /// loop has to write its own speed again.
+    pub fn beat(&self) -> bool {
+        self.shared
+            .last_beat
+            .store(self.shared.now(), Ordering::Relaxed);
+        let tripped = self.shared.tripped.swap(false, Ordering::Relaxed);
+        if tripped {
+            info!("The control loop is running again, handing the fan back to it");
+        }
+        tripped
+    }
+
+    /// Stops the watchdog, so it can't override the speed that the fan is
+    /// left at on shutdown
+    pub fn stop(self) {
+        *self.shared.stopped.lock().unwrap() = true;
+    }
+}
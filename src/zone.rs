@@ -0,0 +1,218 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use rppal::{
+    gpio::{Gpio, InputPin},
+    pwm::Channel,
+};
+
+use crate::{
+    board::Board,
+    config::{Config, FanBackend, ZoneConfig},
+    control::ControlState,
+    driver::{self, PwmDriver, WriteLimit},
+    error::AppError,
+    rpm::EdgeFilter,
+    temperature::{SysfsSource, TemperatureSource},
+    Fan,
+};
+
+/// A fan that follows its own temperature sensor, controlled independently
+/// of the main fan but with the same pipeline
+pub struct Zone {
+    pub name: String,
+    source: SysfsSource,
+    /// The zone's own settings, as they were at startup
+    zone: ZoneConfig,
+    /// The main config with the zone's thresholds and curve swapped in
+    config: Config,
+    state: ControlState,
+    pub fan: Fan,
+    tach: Option<Tach>,
+}
+
+/// A zone fan's tachometer, whose edges are counted into a counter of its own
+struct Tach {
+    edges: Arc<AtomicU64>,
+    /// When the edges were last turned into an RPM reading
+    since: Instant,
+    edges_per_rev: f32,
+    // The pin has to outlive the zone, or its interrupt is cleared
+    _pin: InputPin,
+}
+
+impl Tach {
+    /// Returns the fan speed since the last call, in RPM
+    fn rpm(&mut self) -> f32 {
+        let edges = self.edges.swap(0, Ordering::Relaxed);
+        let now = Instant::now();
+        let window = (now - self.since).as_secs_f32();
+        self.since = now;
+        if window <= 0.0 {
+            return 0.0;
+        }
+        edges as f32 / self.edges_per_rev / window * 60.0
+    }
+}
+
+/// The readings from one cycle of a zone
+pub struct Reading {
+    /// [°C] the zone's temperature, or `None` if it couldn't be read
+    pub temp: Option<f32>,
+    /// The fan speed as a percentage
+    pub duty: f32,
+    /// [RPM] the measured fan speed, if the zone has a tachometer
+    pub rpm: Option<f32>,
+}
+
+impl Zone {
+    /// Sets up every configured zone, with its fan stopped
+    pub fn open_all(config: &Config, board: Board, gpio: &Gpio) -> Result<Vec<Self>, AppError> {
+        let main_channel = match config.fan_driver {
+            FanBackend::Pwm => Some(crate::pwm_channel(config, board)? as u8),
+            FanBackend::I2c(_) | FanBackend::Hwmon(_) => None,
+        };
+        config
+            .zones
+            .iter()
+            .map(|zone| {
+                if main_channel == Some(zone.pwm_channel) {
+                    return Err(AppError::ConfigInvalid {
+                        source_name: "zones".to_string(),
+                        reason: format!(
+                            "zone {:?} uses PWM channel {}, which drives the main fan",
+                            zone.name, zone.pwm_channel
+                        ),
+                    });
+                }
+                Self::open(zone, config, board, gpio)
+            })
+            .collect()
+    }
+
+    fn open(
+        zone: &ZoneConfig,
+        config: &Config,
+        board: Board,
+        gpio: &Gpio,
+    ) -> Result<Self, AppError> {
+        let channel = zone.pwm_channel;
+        board
+            .pwm_pin(channel)
+            .ok_or(AppError::PwmChannel { board, channel })?;
+        let channel =
+            Channel::try_from(channel).map_err(|_| AppError::PwmChannel { board, channel })?;
+        let fan = Fan {
+            driver: driver::with_min_duty(
+                Box::new(PwmDriver::open(
+                    channel,
+                    board,
+                    config.pwm_frequency,
+                    config.pwm_recovery.clone(),
+                )?),
+                config.hardware_min_duty,
+            ),
+            frequency: config.pwm_frequency,
+            power: None,
+            write_limit: WriteLimit::new(Duration::from_millis(config.min_write_interval_ms)),
+        };
+
+        let tach = match zone.tach_pin {
+            Some(pin) => {
+                let mut input = config.tach_pull.input(
+                    gpio.get(pin)
+                        .map_err(AppError::gpio("claim a zone's tachometer pin"))?,
+                );
+                let edges = Arc::new(AtomicU64::new(0));
+                let counter = Arc::clone(&edges);
+                let mut filter =
+                    EdgeFilter::new(Duration::from_micros(config.tach_min_edge_interval_us));
+                input
+                    .set_async_interrupt(config.tach_edge.trigger(), None, move |event| {
+                        if filter.accept(event.timestamp) {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        }
+                    })
+                    .map_err(AppError::gpio("set up a zone's tachometer interrupt"))?;
+                Some(Tach {
+                    edges,
+                    since: Instant::now(),
+                    edges_per_rev: config.pulses_per_rev * config.tach_edge.edges_per_pulse(),
+                    _pin: input,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            name: zone.name.clone(),
+            source: SysfsSource {
+                path: zone.sensor.clone(),
+                divisor: zone.scale_divisor,
+                calibration: zone.calibration,
+            },
+            zone: zone.clone(),
+            config: config.for_zone(zone),
+            state: ControlState::default(),
+            fan,
+            tach,
+        })
+    }
+
+    /// Takes up the main config's control settings again, after it's
+    /// changed. The zone's own settings only change on a restart.
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.config = config.for_zone(&self.zone);
+    }
+
+    /// Reads the zone's sensor and sets its fan speed. A reading that fails,
+    /// or is implausible, is handled according to `sensor_failure` like the
+    /// main sensor's.
+    pub fn step(&mut self) -> Result<Reading, AppError> {
+        let temp = match self.source.read() {
+            Ok(temp) if crate::PLAUSIBLE_TEMPS.contains(&temp) => Some(temp),
+            Ok(temp) => {
+                warn!(
+                    "Ignoring an implausible temperature of {temp}°C in the {} zone",
+                    self.name
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to read the temperature of the {} zone: {e}",
+                    self.name
+                );
+                None
+            }
+        };
+        let rpm = self.tach.as_mut().map(Tach::rpm);
+        self.state.rpm = rpm;
+        let control_temp = match temp {
+            Some(temp) => {
+                self.state.failed_reads = 0;
+                temp
+            }
+            None => {
+                self.state.failed_reads += 1;
+                match crate::outage_duty(&self.config, &self.state) {
+                    None => crate::FALLBACK_TEMP,
+                    Some(duty) => {
+                        crate::command_outage_duty(&mut self.fan, &mut self.state, duty)?;
+                        let duty = duty * 100.0;
+                        return Ok(Reading { temp, duty, rpm });
+                    }
+                }
+            }
+        };
+        let duty =
+            crate::handle_fan_speed(control_temp, &self.config, &mut self.state, &mut self.fan)?;
+        Ok(Reading { temp, duty, rpm })
+    }
+}
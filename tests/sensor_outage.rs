@@ -0,0 +1,49 @@
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the run gets before it's taken to be stuck
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn max_iterations_ends_a_run_without_readings() {
+    let config = std::env::temp_dir().join(format!("rpi-fan-outage-{}.toml", std::process::id()));
+    std::fs::write(
+        &config,
+        "on_non_pi = \"dry-run\"\npoll_interval_secs = 0.1\nsoft_start_secs = 0.0\nsensor_failure \
+         = \"full\"\nsensor_priority = [{ path = \"/nonexistent/temp\" }]\n",
+    )
+    .unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rpi_fan_control"))
+        .arg("--config")
+        .arg(&config)
+        .args(["--max-iterations", "3"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if started.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            panic!("still running after {TIMEOUT:?}");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&config).unwrap();
+    assert!(output.status.success());
+
+    // Every cycle is still reported, at full speed, but none of them count
+    // towards the temperature statistics
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let cycles = stdout
+        .lines()
+        .filter(|line| line.contains("Fan Percentage: 100.00%"))
+        .count();
+    assert_eq!(cycles, 3, "{stdout}");
+    assert!(stdout.contains("Ran 3 iterations"), "{stdout}");
+    assert!(stdout.contains("CPU Temp (°C): no readings"), "{stdout}");
+}